@@ -0,0 +1,128 @@
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Returns the directory for cached artifacts of kind `kind`
+///
+/// The directory is created if it does not exist yet. Respects
+/// `$XDG_CACHE_HOME` and falls back to `~/.cache`.
+pub fn cache_dir(kind: &str) -> Result<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = env::var_os("HOME").ok_or_else(|| anyhow!("$HOME is not set"))?;
+            PathBuf::from(home).join(".cache")
+        }
+    };
+
+    let dir = base.join("vmtest").join(kind);
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create cache directory {}", dir.display()))?;
+
+    Ok(dir)
+}
+
+/// Builds the key of a cache entry
+///
+/// Keys are SHA-256 digests of explicitly encoded parts, so unlike std's
+/// `DefaultHasher` they stay the same across vmtest and Rust releases.
+#[derive(Default)]
+pub(crate) struct CacheKey {
+    hasher: Sha256,
+}
+
+impl CacheKey {
+    /// Add `bytes` to the key
+    ///
+    /// Parts are length prefixed, so adjacent parts cannot run into each
+    /// other.
+    pub fn add(&mut self, bytes: impl AsRef<[u8]>) -> &mut Self {
+        let bytes = bytes.as_ref();
+        self.hasher.update((bytes.len() as u64).to_le_bytes());
+        self.hasher.update(bytes);
+        self
+    }
+
+    /// Add the size and modification time of the file at `path` to the key
+    pub fn add_metadata(&mut self, path: &Path) -> Result<&mut Self> {
+        let metadata =
+            fs::metadata(path).with_context(|| format!("Failed to stat {}", path.display()))?;
+        let mtime = metadata
+            .modified()
+            .with_context(|| format!("Failed to get mtime of {}", path.display()))?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.add(metadata.len().to_le_bytes());
+        self.add(mtime.as_secs().to_le_bytes());
+        self.add(mtime.subsec_nanos().to_le_bytes());
+
+        Ok(self)
+    }
+
+    /// Add the contents of the file at `path` to the key
+    pub fn add_contents(&mut self, path: &Path) -> Result<&mut Self> {
+        let mut file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let len = file
+            .metadata()
+            .with_context(|| format!("Failed to stat {}", path.display()))?
+            .len();
+        self.hasher.update(len.to_le_bytes());
+        io::copy(&mut file, &mut self.hasher)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        Ok(self)
+    }
+
+    /// Returns the key as a hex string
+    pub fn finish(&self) -> String {
+        // 128 bits are plenty to tell cache entries apart
+        self.hasher.clone().finalize()[..16]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        // Keys must not change between releases
+        assert_eq!(
+            CacheKey::default().add("abc").finish(),
+            "ce91dc5eec0139adf091900d225971d6"
+        );
+        let key = |parts: &[&str]| {
+            let mut key = CacheKey::default();
+            parts.iter().for_each(|p| {
+                key.add(p);
+            });
+            key.finish()
+        };
+        assert_ne!(key(&["ab", "c"]), key(&["a", "bc"]));
+        assert_eq!(key(&["ab", "c"]), key(&["ab", "c"]));
+    }
+
+    #[test]
+    fn test_cache_key_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, "abc").unwrap();
+        // Contents are encoded like any other part
+        assert_eq!(
+            CacheKey::default().add_contents(&path).unwrap().finish(),
+            CacheKey::default().add("abc").finish()
+        );
+        assert!(CacheKey::default()
+            .add_contents(&dir.path().join("missing"))
+            .is_err());
+    }
+}
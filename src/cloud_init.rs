@@ -0,0 +1,336 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use tempfile::{Builder, TempDir};
+
+use crate::CloudInit;
+
+/// Volume label cloud-init looks for to find a NoCloud seed
+const SEED_LABEL: &str = "cidata";
+/// Size of a vfat seed. Plenty for user-data and meta-data.
+const VFAT_SEED_SIZE: u64 = 2 << 20;
+/// Tools that can build an ISO seed, along with the arguments that select
+/// mkisofs compatible argument parsing
+const ISO_TOOLS: &[Tool] = &[
+    ("genisoimage", &[]),
+    ("mkisofs", &[]),
+    ("xorriso", &["-as", "mkisofs"]),
+];
+/// Tools that format a vfat seed and copy files into it, respectively
+const VFAT_TOOLS: (Tool, Tool) = (("mkfs.vfat", &[]), ("mcopy", &[]));
+
+/// A program along with the arguments to always pass first
+type Tool<'a> = (&'a str, &'a [&'a str]);
+
+/// A NoCloud seed disk
+///
+/// The seed is deleted when this is dropped.
+pub struct Seed {
+    /// Holds the seed and the files it was built from
+    _dir: TempDir,
+    path: PathBuf,
+}
+
+impl Seed {
+    /// Path to the raw seed disk image
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Render the user-data for `config`
+fn user_data(config: &CloudInit) -> String {
+    if let Some(user_data) = &config.user_data {
+        return user_data.clone();
+    }
+
+    // YAML is a superset of JSON, so lean on serde_json for quoting.
+    // Unwraps should never fail b/c we only serialize strings.
+    let mut data = String::from("#cloud-config\n");
+    if !config.packages.is_empty() {
+        let packages = serde_json::to_string(&config.packages).unwrap();
+        data.push_str(&format!("packages: {packages}\n"));
+    }
+
+    let services = config
+        .services
+        .clone()
+        .unwrap_or_else(CloudInit::default_services);
+    if !services.is_empty() {
+        let mut cmd = vec!["systemctl".to_string(), "enable".into(), "--now".into()];
+        cmd.extend(services);
+        let cmd = serde_json::to_string(&cmd).unwrap();
+        data.push_str(&format!("runcmd:\n  - {cmd}\n"));
+    }
+
+    data
+}
+
+/// Render the meta-data for `config`
+fn meta_data(config: &CloudInit, user_data: &str) -> String {
+    if let Some(meta_data) = &config.meta_data {
+        return meta_data.clone();
+    }
+
+    // cloud-init only applies user-data once per instance. Deriving the
+    // instance-id from the user-data makes config changes apply to images
+    // that were already initialized.
+    let mut h = DefaultHasher::new();
+    user_data.hash(&mut h);
+    format!(
+        "instance-id: vmtest-{:016x}\nlocal-hostname: vmtest\n",
+        h.finish()
+    )
+}
+
+/// Run `cmd`
+///
+/// Returns false if the program is not installed.
+fn run(cmd: &mut Command) -> Result<bool> {
+    let out = match cmd.output() {
+        Ok(out) => out,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to run {}", cmd.get_program().to_string_lossy()))
+        }
+    };
+    if !out.status.success() {
+        bail!(
+            "{} failed: {}",
+            cmd.get_program().to_string_lossy(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    Ok(true)
+}
+
+/// Build an ISO seed from the files in `dir` with the first available of
+/// `tools`
+///
+/// Returns false if no tool is installed.
+fn gen_iso(dir: &Path, seed: &Path, tools: &[Tool]) -> Result<bool> {
+    for (program, args) in tools {
+        let mut cmd = Command::new(program);
+        cmd.current_dir(dir)
+            .args(*args)
+            .arg("-output")
+            .arg(seed)
+            .args(["-volid", SEED_LABEL, "-joliet", "-rock"])
+            .args(["user-data", "meta-data"]);
+        if run(&mut cmd)? {
+            debug!("Generated cloud-init seed with {program}");
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Build a vfat seed from the files in `dir` with `tools`
+///
+/// Returns false if the required tools are not installed.
+fn gen_vfat(dir: &Path, seed: &Path, tools: (Tool, Tool)) -> Result<bool> {
+    let ((mkfs, mkfs_args), (mcopy, mcopy_args)) = tools;
+    fs::File::create(seed)
+        .and_then(|f| f.set_len(VFAT_SEED_SIZE))
+        .context("Failed to create vfat seed")?;
+
+    if !run(Command::new(mkfs)
+        .args(mkfs_args)
+        .arg("-n")
+        .arg(SEED_LABEL)
+        .arg(seed))?
+    {
+        return Ok(false);
+    }
+    let mut mcopy = Command::new(mcopy);
+    mcopy
+        .current_dir(dir)
+        .args(mcopy_args)
+        .arg("-i")
+        .arg(seed)
+        .args(["user-data", "meta-data", "::"]);
+    if !run(&mut mcopy)? {
+        return Ok(false);
+    }
+
+    debug!("Generated cloud-init seed with mkfs.vfat");
+    Ok(true)
+}
+
+/// Write the files that make up the seed for `config` into `dir`
+fn write_seed_files(dir: &Path, config: &CloudInit) -> Result<()> {
+    let user_data = user_data(config);
+    fs::write(dir.join("user-data"), &user_data).context("Failed to write user-data")?;
+    fs::write(dir.join("meta-data"), meta_data(config, &user_data))
+        .context("Failed to write meta-data")?;
+
+    Ok(())
+}
+
+/// Generate a NoCloud seed disk for `config` in a new directory inside `dir`
+///
+/// An ISO is preferred. A vfat image is built as a fallback.
+pub fn gen_seed(dir: &Path, config: &CloudInit) -> Result<Seed> {
+    let dir = Builder::new()
+        .prefix("vmtest-cloud-init")
+        .tempdir_in(dir)
+        .context("Failed to create cloud-init directory")?;
+    write_seed_files(dir.path(), config)?;
+
+    let path = dir.path().join("seed.img");
+    if !gen_iso(dir.path(), &path, ISO_TOOLS)? && !gen_vfat(dir.path(), &path, VFAT_TOOLS)? {
+        bail!(
+            "No tool to build a cloud-init seed found. Install one of genisoimage, mkisofs, xorriso, or mkfs.vfat and mcopy"
+        );
+    }
+
+    Ok(Seed { _dir: dir, path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Write a fake tool to `dir` that records where and how it was run to
+    /// `<name>.args` and exits with `code`
+    ///
+    /// The tool is a script run by `sh`, as executing a file that was just
+    /// written races with other threads forking.
+    fn fake_tool(dir: &Path, name: &str, code: i32) -> String {
+        let path = dir.join(name);
+        fs::write(
+            &path,
+            format!("echo \"$PWD\" \"$@\" > \"$0.args\"\necho {name} failed >&2\nexit {code}\n"),
+        )
+        .unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn recorded_args(tool: &str) -> String {
+        fs::read_to_string(format!("{tool}.args")).unwrap()
+    }
+
+    #[test]
+    fn test_default_user_data() {
+        let data = user_data(&CloudInit::default());
+        assert_eq!(
+            data,
+            "#cloud-config\nruncmd:\n  - [\"systemctl\",\"enable\",\"--now\",\"qemu-guest-agent\"]\n"
+        );
+    }
+
+    #[test]
+    fn test_user_data_packages_and_services() {
+        let config = CloudInit {
+            packages: vec!["qemu-guest-agent".into(), "fio".into()],
+            services: Some(vec![]),
+            ..Default::default()
+        };
+        assert_eq!(
+            user_data(&config),
+            "#cloud-config\npackages: [\"qemu-guest-agent\",\"fio\"]\n"
+        );
+    }
+
+    #[test]
+    fn test_verbatim_data() {
+        let config = CloudInit {
+            user_data: Some("#cloud-config\n".into()),
+            meta_data: Some("instance-id: mine\n".into()),
+            ..Default::default()
+        };
+        let data = user_data(&config);
+        assert_eq!(data, "#cloud-config\n");
+        assert_eq!(meta_data(&config, &data), "instance-id: mine\n");
+    }
+
+    #[test]
+    fn test_instance_id_follows_user_data() {
+        let config = CloudInit::default();
+        let a = meta_data(&config, "#cloud-config\n");
+        let b = meta_data(&config, "#cloud-config\npackages: [\"fio\"]\n");
+        assert!(a.starts_with("instance-id: vmtest-"));
+        assert_ne!(a, b);
+        assert_eq!(a, meta_data(&config, "#cloud-config\n"));
+    }
+
+    #[test]
+    fn test_seed_files() {
+        let dir = tempdir().unwrap();
+        let config = CloudInit {
+            packages: vec!["fio".into()],
+            ..Default::default()
+        };
+        write_seed_files(dir.path(), &config).unwrap();
+
+        let user_data = fs::read_to_string(dir.path().join("user-data")).unwrap();
+        assert_eq!(user_data, self::user_data(&config));
+        assert!(user_data.contains("packages: [\"fio\"]\n"));
+        let meta_data = fs::read_to_string(dir.path().join("meta-data")).unwrap();
+        assert_eq!(meta_data, self::meta_data(&config, &user_data));
+        assert!(meta_data.ends_with("local-hostname: vmtest\n"));
+    }
+
+    #[test]
+    fn test_gen_iso() {
+        let dir = tempdir().unwrap();
+        let seed = dir.path().join("seed.img");
+        let missing = dir.path().join("missing");
+        let missing = missing.to_str().unwrap();
+        let tool = fake_tool(dir.path(), "mkisofs", 0);
+
+        // Missing tools are skipped
+        let tools: &[Tool] = &[(missing, &[]), ("sh", &[&tool, "-as", "mkisofs"])];
+        assert!(gen_iso(dir.path(), &seed, tools).unwrap());
+        assert_eq!(
+            recorded_args(&tool),
+            format!(
+                "{} -as mkisofs -output {} -volid cidata -joliet -rock user-data meta-data\n",
+                dir.path().display(),
+                seed.display()
+            )
+        );
+
+        assert!(!gen_iso(dir.path(), &seed, &[(missing, &[])]).unwrap());
+
+        let failing = fake_tool(dir.path(), "genisoimage", 1);
+        let err = gen_iso(dir.path(), &seed, &[("sh", &[&failing])]).unwrap_err();
+        assert_eq!(err.to_string(), "sh failed: genisoimage failed");
+    }
+
+    #[test]
+    fn test_gen_vfat() {
+        let dir = tempdir().unwrap();
+        let seed = dir.path().join("seed.img");
+        let mkfs = fake_tool(dir.path(), "mkfs.vfat", 0);
+        let mcopy = fake_tool(dir.path(), "mcopy", 0);
+
+        assert!(gen_vfat(dir.path(), &seed, (("sh", &[&mkfs]), ("sh", &[&mcopy]))).unwrap());
+        assert_eq!(fs::metadata(&seed).unwrap().len(), VFAT_SEED_SIZE);
+        // mkfs.vfat runs wherever vmtest does
+        assert!(recorded_args(&mkfs).ends_with(&format!(" -n cidata {}\n", seed.display())));
+        assert_eq!(
+            recorded_args(&mcopy),
+            format!(
+                "{} -i {} user-data meta-data ::\n",
+                dir.path().display(),
+                seed.display()
+            )
+        );
+
+        let missing = dir.path().join("missing");
+        let missing = missing.to_str().unwrap();
+        let tools = (("sh", &[mkfs.as_str()][..]), (missing, &[][..]));
+        assert!(!gen_vfat(dir.path(), &seed, tools).unwrap());
+    }
+}
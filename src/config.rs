@@ -3,8 +3,11 @@ use std::env::consts::ARCH;
 use std::path::PathBuf;
 use std::vec::Vec;
 
+use anyhow::Result;
 use serde_derive::Deserialize;
 
+use crate::vmtest::validate_config;
+
 /// Config for a mount
 #[derive(Deserialize, Clone)]
 pub struct Mount {
@@ -17,45 +20,412 @@ pub struct Mount {
     pub writable: bool,
 }
 
+/// Backing storage for the writable layer of a rootfs overlay
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlayUpper {
+    /// Keep guest writes in memory.
+    #[default]
+    Tmpfs,
+    /// Keep guest writes on a scratch disk that is thrown away after the run.
+    Disk,
+}
+
+/// Where a kernel target's rootfs comes from
+///
+/// [`Target::rootfs`] used to be a plain [`PathBuf`]. Library users who set it
+/// directly can convert with `path.into()`, which gives [`Rootfs::Path`].
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Rootfs {
+    /// A directory, tarball, OCI image layout or squashfs/erofs image.
+    Path(PathBuf),
+    /// A minimal rootfs built from host executables.
+    FromHost {
+        /// Executables to copy into the rootfs along with their shared
+        /// libraries.
+        ///
+        /// Names are looked up in `$PATH`. Paths are relative to
+        /// `vmtest.toml`. Whatever init needs is always included.
+        from_host: Vec<String>,
+    },
+}
+
+impl Default for Rootfs {
+    fn default() -> Self {
+        Self::Path(Target::default_rootfs())
+    }
+}
+
+impl From<PathBuf> for Rootfs {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+/// Config for a writable overlay on top of a kernel target's rootfs
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct RootfsOverlay {
+    /// Where guest writes to the rootfs are stored.
+    ///
+    /// Default: tmpfs
+    #[serde(default)]
+    pub upper: OverlayUpper,
+    /// Size limit of the writable layer, like 512M or 8G.
+    ///
+    /// Default: half of guest RAM for tmpfs, 8G for disk
+    pub size: Option<String>,
+}
+
+/// Kind of media a disk presents to the guest
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskMedia {
+    /// A regular hard disk.
+    #[default]
+    Disk,
+    /// A read-only CD-ROM.
+    Cdrom,
+}
+
+/// Bus a disk is attached to the guest through
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskBus {
+    /// Paravirtualized block device. Shows up as `/dev/vdX`.
+    VirtioBlk,
+    /// Paravirtualized SCSI controller. Shows up as `/dev/sdX` or `/dev/srX`.
+    VirtioScsi,
+    /// Emulated NVMe controller. Shows up as `/dev/nvmeXnY`.
+    Nvme,
+}
+
+/// Kind of storage controller
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ControllerKind {
+    /// Paravirtualized SCSI controller.
+    VirtioScsi,
+    /// Emulated NVMe controller. Each disk on it is a separate namespace.
+    Nvme,
+}
+
+/// Config for a storage controller that disks can share
+#[derive(Deserialize, Clone)]
+pub struct Controller {
+    /// Name of the controller.
+    ///
+    /// Disks attach to the controller by name. NVMe controllers also use it
+    /// as their serial number.
+    pub name: String,
+    /// Kind of controller.
+    pub kind: ControllerKind,
+    /// Number of I/O queues.
+    ///
+    /// Default: QEMU's default for the controller
+    pub queues: Option<u16>,
+}
+
+/// Config for an additional disk
+#[derive(Deserialize, Clone, Default)]
+pub struct Disk {
+    /// Name of the disk.
+    ///
+    /// The guest device path is exposed to the command through the
+    /// `VMTEST_DISK_<NAME>` environment variable, where `<NAME>` is the
+    /// upper cased name.
+    pub name: String,
+    /// Path on the host to an existing disk image.
+    ///
+    /// Mutually exclusive with `size`.
+    pub path: Option<PathBuf>,
+    /// Size of a scratch disk to create for each run, like 512M or 8G.
+    ///
+    /// Scratch disks are sparse and deleted after the run.
+    /// Mutually exclusive with `path`.
+    pub size: Option<String>,
+    /// Image format, like raw or qcow2.
+    ///
+    /// Default: raw for scratch disks. QEMU probes existing images.
+    pub format: Option<String>,
+    /// Prevent the guest from writing to the disk.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub readonly: bool,
+    /// Kind of media the disk presents.
+    ///
+    /// Default: disk
+    #[serde(default)]
+    pub media: DiskMedia,
+    /// Bus the disk is attached through.
+    ///
+    /// Default: virtio-blk for disks, virtio-scsi for cdroms
+    pub bus: Option<DiskBus>,
+    /// Name of the controller to attach the disk to.
+    ///
+    /// The controller must provide the disk's bus. Without one, SCSI disks
+    /// share a default controller and NVMe disks each get their own.
+    pub controller: Option<String>,
+    /// Number of I/O queues.
+    ///
+    /// Only for virtio-blk disks and NVMe disks without a controller.
+    /// Default: QEMU's default for the device
+    pub queues: Option<u16>,
+    /// Logical block size in bytes the disk reports.
+    ///
+    /// Default: 512
+    pub logical_block_size: Option<u32>,
+    /// Physical block size in bytes the disk reports.
+    ///
+    /// Default: `logical_block_size`
+    pub physical_block_size: Option<u32>,
+}
+
+impl Disk {
+    /// The bus the disk is attached through, after applying defaults
+    pub fn bus(&self) -> DiskBus {
+        match (self.bus, self.media) {
+            (Some(bus), _) => bus,
+            (None, DiskMedia::Disk) => DiskBus::VirtioBlk,
+            (None, DiskMedia::Cdrom) => DiskBus::VirtioScsi,
+        }
+    }
+
+    /// Name of the environment variable holding the guest device path
+    pub fn env_var(&self) -> String {
+        format!("VMTEST_DISK_{}", self.name.to_uppercase())
+    }
+}
+
+/// Config for a cloud-init NoCloud seed
+///
+/// When `user_data` is not provided, user-data is generated from
+/// `packages` and `services`.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct CloudInit {
+    /// Verbatim user-data.
+    ///
+    /// Mutually exclusive with `packages` and `services`.
+    pub user_data: Option<String>,
+    /// Verbatim meta-data.
+    ///
+    /// Default: an `instance-id` derived from the user-data
+    pub meta_data: Option<String>,
+    /// Packages to install on first boot.
+    ///
+    /// Note the guest needs network access for this.
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// Services to enable and start on first boot.
+    ///
+    /// Default: qemu-guest-agent
+    pub services: Option<Vec<String>>,
+}
+
+impl CloudInit {
+    /// Services to enable if none are specified.
+    pub fn default_services() -> Vec<String> {
+        vec!["qemu-guest-agent".into()]
+    }
+}
+
+/// TPM interface exposed to the guest
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TpmModel {
+    /// TPM Interface Specification. Works on all supported architectures.
+    Tis,
+    /// Command Response Buffer. Only on x86_64.
+    Crb,
+}
+
+/// CPU topology of the VM
+///
+/// The product of all fields must equal `max_cpus`, or `num_cpus` if
+/// CPU hotplug is disabled.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Topology {
+    /// Number of CPU sockets.
+    ///
+    /// Default: 1
+    #[serde(default = "Topology::default_count")]
+    pub sockets: u16,
+    /// Number of cores per socket.
+    ///
+    /// Default: 1
+    #[serde(default = "Topology::default_count")]
+    pub cores: u16,
+    /// Number of threads per core.
+    ///
+    /// Default: 1
+    #[serde(default = "Topology::default_count")]
+    pub threads: u16,
+}
+
+impl Topology {
+    fn default_count() -> u16 {
+        1
+    }
+
+    /// Total number of CPUs in the topology
+    pub fn cpus(&self) -> u32 {
+        u32::from(self.sockets) * u32::from(self.cores) * u32::from(self.threads)
+    }
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Self {
+            sockets: Self::default_count(),
+            cores: Self::default_count(),
+            threads: Self::default_count(),
+        }
+    }
+}
+
+/// Config for a NUMA node
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct NumaNode {
+    /// CPUs in the node, like `0-3` or `0,2,4-7`.
+    ///
+    /// Default: no CPUs
+    pub cpus: Option<String>,
+    /// Amount of RAM in the node.
+    ///
+    /// Uses the same syntax as `memory`.
+    pub memory: String,
+}
+
+/// Kind of host memory backing guest RAM
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryBackendKind {
+    /// Private anonymous memory. What QEMU uses by default.
+    #[default]
+    Anonymous,
+    /// An anonymous memfd. Can be shared with other processes, like
+    /// vhost-user device backends.
+    Memfd,
+    /// Files on a hugetlbfs mount, so guest RAM is backed by hugepages.
+    Hugetlbfs,
+}
+
+/// Config for the host memory backing guest RAM
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct MemoryBackend {
+    /// Kind of memory.
+    ///
+    /// Default: anonymous
+    #[serde(default)]
+    pub kind: MemoryBackendKind,
+    /// Path to the hugetlbfs mount. Only for `hugetlbfs`.
+    ///
+    /// Default: /dev/hugepages
+    pub path: Option<PathBuf>,
+    /// Whether to allocate all of guest RAM before boot.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub prealloc: bool,
+    /// Whether guest RAM is mapped shared instead of private.
+    ///
+    /// Default: QEMU's default for the kind, which is shared for `memfd`
+    pub share: Option<bool>,
+}
+
+impl MemoryBackend {
+    /// Default hugetlbfs mount
+    pub fn default_path() -> PathBuf {
+        "/dev/hugepages".into()
+    }
+}
+
 /// VM Config for a target
+///
+/// More fields may be added in any release. Outside of vmtest, construct it
+/// with [`VMConfig::builder`].
 #[derive(Deserialize, Clone)]
+#[non_exhaustive]
 pub struct VMConfig {
     /// Number of CPUs in the VM.
     ///
     /// Default: 2
     #[serde(default = "VMConfig::default_cpus")]
-    pub num_cpus: u8,
+    pub num_cpus: u16,
+    /// Maximum number of CPUs, including CPUs hotplugged with `vmtest-ctl`.
+    ///
+    /// Default: no CPU hotplug
+    pub max_cpus: Option<u16>,
+    /// CPU topology.
+    ///
+    /// Default: one socket with `max_cpus` single threaded cores
+    pub topology: Option<Topology>,
     /// Amount of RAM for the VM.
     ///
     /// Accepts a QEMU parsable string for the -m flag like 256M or 4G.
     /// Default: 4G
     #[serde(default = "VMConfig::default_memory")]
     pub memory: String,
+    /// NUMA nodes.
+    ///
+    /// Together, the nodes must contain every CPU exactly once and all of
+    /// `memory`.
+    /// Default: no NUMA
+    #[serde(default = "Vec::new")]
+    pub numa: Vec<NumaNode>,
+    /// Host memory backing guest RAM.
+    ///
+    /// Also used for the memory of each NUMA node.
+    /// Default: QEMU's default anonymous memory
+    pub memory_backend: Option<MemoryBackend>,
+    /// Attach a memory balloon that `vmtest-ctl` can resize.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub balloon: bool,
     /// Map of additional Host mounts.
     ///
     /// Key is the path in the VM and the value is the path on the host.
     /// * Only respected when using an image.
     #[serde(default = "HashMap::new")]
     pub mounts: HashMap<String, Mount>,
+    /// Additional disks to attach to the VM.
+    #[serde(default = "Vec::new")]
+    pub disks: Vec<Disk>,
+    /// Storage controllers that disks can attach to.
+    #[serde(default = "Vec::new")]
+    pub controllers: Vec<Controller>,
 
     /// Path to the BIOS file.
     ///
-    /// If this is empty, the default OS locations will be tried:
-    /// * /usr/share/edk2/ovmf/OVMF_CODE.fd
-    /// * /usr/share/OVMF/OVMF_CODE.fd
-    /// * /usr/share/edk2-ovmf/x64/OVMF_CODE.fd
+    /// If this is empty, the default OS locations of the edk2 firmware for
+    /// the target's arch will be tried, e.g. OVMF for x86_64 or AAVMF for
+    /// aarch64. Firmware with a separate variable store gets a fresh copy of
+    /// it every run.
     pub bios: Option<PathBuf>,
 
+    /// Attach a software TPM 2.0 backed by swtpm.
+    ///
+    /// The TPM starts out with fresh state every run.
+    /// Default: false
+    #[serde(default)]
+    pub tpm: bool,
+    /// TPM interface exposed to the guest.
+    ///
+    /// Default: tis
+    pub tpm_model: Option<TpmModel>,
+
     /// Extra arguments to pass to QEMU.
     #[serde(default = "Vec::new")]
     pub extra_args: Vec<String>,
     // TODO: Consider adding higher level interfaces for adding
-    // additional hardware to the VM (USB, HDDs, CDROM, TPM, etc).
+    // additional hardware to the VM (USB, etc).
     // For now, people can use extra_args to add them.
 }
 
 impl VMConfig {
-    fn default_cpus() -> u8 {
+    fn default_cpus() -> u16 {
         2
     }
 
@@ -68,16 +438,140 @@ impl Default for VMConfig {
     fn default() -> Self {
         Self {
             num_cpus: Self::default_cpus(),
+            max_cpus: None,
+            topology: None,
             memory: Self::default_memory(),
+            numa: Vec::new(),
+            memory_backend: None,
+            balloon: false,
             mounts: HashMap::new(),
+            disks: Vec::new(),
+            controllers: Vec::new(),
             bios: None,
+            tpm: false,
+            tpm_model: None,
             extra_args: Vec::new(),
         }
     }
 }
 
+/// Builder for [`VMConfig`]
+///
+/// Starts out with the same defaults as a config file. Created by
+/// [`VMConfig::builder`].
+pub struct VMConfigBuilder {
+    vm: VMConfig,
+}
+
+impl VMConfig {
+    /// Returns a builder for a VM config
+    pub fn builder() -> VMConfigBuilder {
+        VMConfigBuilder {
+            vm: VMConfig::default(),
+        }
+    }
+}
+
+impl VMConfigBuilder {
+    /// Set the number of CPUs the VM boots with
+    pub fn num_cpus(mut self, num_cpus: u16) -> Self {
+        self.vm.num_cpus = num_cpus;
+        self
+    }
+
+    /// Set the number of CPUs the VM can be hotplugged up to
+    pub fn max_cpus(mut self, max_cpus: u16) -> Self {
+        self.vm.max_cpus = Some(max_cpus);
+        self
+    }
+
+    /// Set the CPU topology
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.vm.topology = Some(topology);
+        self
+    }
+
+    /// Set the amount of RAM, for example `"4G"`
+    pub fn memory(mut self, memory: impl Into<String>) -> Self {
+        self.vm.memory = memory.into();
+        self
+    }
+
+    /// Add a NUMA node
+    pub fn numa_node(mut self, node: NumaNode) -> Self {
+        self.vm.numa.push(node);
+        self
+    }
+
+    /// Set what guest RAM is backed by
+    pub fn memory_backend(mut self, backend: MemoryBackend) -> Self {
+        self.vm.memory_backend = Some(backend);
+        self
+    }
+
+    /// Attach a memory balloon device
+    pub fn balloon(mut self, balloon: bool) -> Self {
+        self.vm.balloon = balloon;
+        self
+    }
+
+    /// Mount `mount` at `guest_path` inside the VM
+    pub fn mount(mut self, guest_path: impl Into<String>, mount: Mount) -> Self {
+        self.vm.mounts.insert(guest_path.into(), mount);
+        self
+    }
+
+    /// Attach an additional disk
+    pub fn disk(mut self, disk: Disk) -> Self {
+        self.vm.disks.push(disk);
+        self
+    }
+
+    /// Add a storage controller
+    pub fn controller(mut self, controller: Controller) -> Self {
+        self.vm.controllers.push(controller);
+        self
+    }
+
+    /// Set the UEFI firmware to boot with
+    pub fn bios(mut self, bios: impl Into<PathBuf>) -> Self {
+        self.vm.bios = Some(bios.into());
+        self
+    }
+
+    /// Attach a software TPM
+    pub fn tpm(mut self, tpm: bool) -> Self {
+        self.vm.tpm = tpm;
+        self
+    }
+
+    /// Set the TPM device model
+    pub fn tpm_model(mut self, model: TpmModel) -> Self {
+        self.vm.tpm_model = Some(model);
+        self
+    }
+
+    /// Pass an additional argument to QEMU
+    pub fn extra_arg(mut self, arg: impl Into<String>) -> Self {
+        self.vm.extra_args.push(arg.into());
+        self
+    }
+
+    /// Returns the VM config
+    ///
+    /// It is validated as part of the target it ends up in, as most
+    /// constraints depend on the target.
+    pub fn build(self) -> VMConfig {
+        self.vm
+    }
+}
+
 /// Config for a single target
+///
+/// More fields may be added in any release. Outside of vmtest, construct it
+/// with [`Target::builder`].
 #[derive(Deserialize, Clone)]
+#[non_exhaustive]
 pub struct Target {
     /// Name of the testing target.
     pub name: String,
@@ -91,6 +585,25 @@ pub struct Target {
     /// Default: false
     #[serde(default)]
     pub uefi: bool,
+    /// Whether or not to enforce UEFI Secure Boot
+    ///
+    /// Boots firmware with the Microsoft keys enrolled. Only on x86_64.
+    /// Default: false
+    #[serde(default)]
+    pub secure_boot: bool,
+    /// Whether or not to boot from a saved VM snapshot
+    ///
+    /// The first run boots the image normally and saves the VM state to the
+    /// cache. Subsequent runs resume from the saved state, skipping boot.
+    /// Only valid for image targets.
+    ///
+    /// The state is saved right after boot, before the VM is set up, as QEMU
+    /// cannot save guests with mounted shared directories. Setup, including
+    /// mounting shared directories, runs again on every resume.
+    ///
+    /// Default: false
+    #[serde(default)]
+    pub snapshot: bool,
     /// Path to kernel image to test against.
     ///
     /// * The path is relative to `vmtest.toml`.
@@ -109,17 +622,42 @@ pub struct Target {
     ///
     /// * The path is relative to `vmtest.toml`.
     /// * If not specified, the host's rootfs will be used.
+    /// * Tarballs and OCI image layouts are unpacked into the vmtest cache
+    ///   before use.
+    /// * squashfs and erofs images are attached read-only with a writable
+    ///   overlay on top.
+    /// * `/bin/bash` and `qemu-ga` in the rootfs must be built for `arch`.
+    /// * `{ from_host = [...] }` builds a minimal rootfs from host
+    ///   executables instead.
     ///
     /// Default: /
-    #[serde(default = "Target::default_rootfs")]
-    pub rootfs: PathBuf,
+    #[serde(default)]
+    pub rootfs: Rootfs,
+    /// Stack a writable overlay on top of the rootfs.
+    ///
+    /// The rootfs is shared read-only and all guest writes land in a
+    /// disposable upper layer.
+    /// * Only respected when using a kernel.
+    pub rootfs_overlay: Option<RootfsOverlay>,
     /// Arch to run
     #[serde(default = "Target::default_arch")]
     pub arch: String,
+    /// Attach a cloud-init NoCloud seed generated from this config.
+    ///
+    /// * Only respected when using an image.
+    pub cloud_init: Option<CloudInit>,
     /// Command used to launch QEMU
     pub qemu_command: Option<String>,
     /// Command to run inside virtual machine.
     pub command: String,
+    /// User to run the command as.
+    ///
+    /// Accepts either a user name known to the guest or a `uid:gid` pair.
+    /// Supplementary groups and `$HOME` are set up from the guest's user
+    /// database.
+    ///
+    /// Default: root
+    pub user: Option<String>,
 
     /// VM Configuration.
     #[serde(default)]
@@ -139,6 +677,135 @@ impl Target {
     pub fn default_kvm_cpu_args() -> Option<String> {
         Some("host".into())
     }
+
+    /// Returns a builder for a target named `name`
+    pub fn builder(name: impl Into<String>) -> TargetBuilder {
+        TargetBuilder {
+            target: Target {
+                name: name.into(),
+                // Match what a config file gets
+                kvm_cpu_args: Self::default_kvm_cpu_args(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Builder for [`Target`]
+///
+/// Starts out with the same defaults as a config file. Created by
+/// [`Target::builder`].
+pub struct TargetBuilder {
+    target: Target,
+}
+
+impl TargetBuilder {
+    /// Rename the target
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.target.name = name.into();
+        self
+    }
+
+    /// Boot the image at `image`
+    pub fn image(mut self, image: impl Into<PathBuf>) -> Self {
+        self.target.image = Some(image.into());
+        self
+    }
+
+    /// Boot the image with UEFI
+    pub fn uefi(mut self, uefi: bool) -> Self {
+        self.target.uefi = uefi;
+        self
+    }
+
+    /// Boot the image with Secure Boot enforced
+    pub fn secure_boot(mut self, secure_boot: bool) -> Self {
+        self.target.secure_boot = secure_boot;
+        self
+    }
+
+    /// Resume the image from a snapshot taken right after boot, before setup
+    pub fn snapshot(mut self, snapshot: bool) -> Self {
+        self.target.snapshot = snapshot;
+        self
+    }
+
+    /// Boot the kernel at `kernel`
+    pub fn kernel(mut self, kernel: impl Into<PathBuf>) -> Self {
+        self.target.kernel = Some(kernel.into());
+        self
+    }
+
+    /// Set additional kernel command line arguments
+    pub fn kernel_args(mut self, args: impl Into<String>) -> Self {
+        self.target.kernel_args = Some(args.into());
+        self
+    }
+
+    /// Set the `-cpu` arguments used with KVM, or `None` for QEMU's default
+    pub fn kvm_cpu_args(mut self, args: Option<String>) -> Self {
+        self.target.kvm_cpu_args = args;
+        self
+    }
+
+    /// Set the rootfs of a kernel target
+    pub fn rootfs(mut self, rootfs: impl Into<Rootfs>) -> Self {
+        self.target.rootfs = rootfs.into();
+        self
+    }
+
+    /// Overlay the rootfs with a disposable, writable layer
+    pub fn rootfs_overlay(mut self, overlay: RootfsOverlay) -> Self {
+        self.target.rootfs_overlay = Some(overlay);
+        self
+    }
+
+    /// Set the arch of the VM
+    pub fn arch(mut self, arch: impl Into<String>) -> Self {
+        self.target.arch = arch.into();
+        self
+    }
+
+    /// Provision the image with cloud-init
+    pub fn cloud_init(mut self, cloud_init: CloudInit) -> Self {
+        self.target.cloud_init = Some(cloud_init);
+        self
+    }
+
+    /// Set the command used to launch QEMU
+    pub fn qemu_command(mut self, command: impl Into<String>) -> Self {
+        self.target.qemu_command = Some(command.into());
+        self
+    }
+
+    /// Set the command to run inside the VM
+    pub fn command(mut self, command: impl Into<String>) -> Self {
+        self.target.command = command.into();
+        self
+    }
+
+    /// Run the command as `user`
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.target.user = Some(user.into());
+        self
+    }
+
+    /// Set the VM config
+    pub fn vm(mut self, vm: VMConfig) -> Self {
+        self.target.vm = vm;
+        self
+    }
+
+    /// Validate and return the target
+    pub fn build(self) -> Result<Target> {
+        let config = Config {
+            target: vec![self.target],
+        };
+        validate_config(&config)?;
+
+        // Unwrap is safe b/c we just put it in there
+        Ok(config.target.into_iter().next().unwrap())
+    }
 }
 
 impl Default for Target {
@@ -147,25 +814,63 @@ impl Default for Target {
             name: "".into(),
             image: None,
             uefi: false,
+            secure_boot: false,
+            snapshot: false,
             kernel: None,
             kernel_args: None,
             kvm_cpu_args: None,
-            rootfs: Self::default_rootfs(),
+            rootfs: Rootfs::default(),
+            rootfs_overlay: None,
             arch: Self::default_arch(),
+            cloud_init: None,
             qemu_command: None,
             command: "".into(),
+            user: None,
             vm: VMConfig::default(),
         }
     }
 }
 
 /// Config containing full test matrix
-#[derive(Deserialize)]
+///
+/// Outside of vmtest, construct it with [`Config::builder`].
+#[derive(Clone, Deserialize)]
+#[non_exhaustive]
 pub struct Config {
     /// List of targets in the testing matrix.
     pub target: Vec<Target>,
 }
 
+/// Builder for [`Config`]
+///
+/// Created by [`Config::builder`].
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl Config {
+    /// Returns a builder for a config without any targets
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config { target: Vec::new() },
+        }
+    }
+}
+
+impl ConfigBuilder {
+    /// Add a target to the testing matrix
+    pub fn target(mut self, target: Target) -> Self {
+        self.config.target.push(target);
+        self
+    }
+
+    /// Validate and return the config
+    pub fn build(self) -> Result<Config> {
+        validate_config(&self.config)?;
+        Ok(self.config)
+    }
+}
+
 // Test that triple quoted toml strings are treated literally.
 // This is used by vmtest-action to avoid escaping issues.
 #[test]
@@ -219,3 +924,356 @@ fn test_default_vmconfig() {
     assert_eq!(config.target[0].vm.extra_args.len(), 0);
     assert_eq!(config.target[0].vm.mounts.len(), 0);
 }
+
+#[test]
+fn test_rootfs_overlay() {
+    let config: Config = toml::from_str(
+        r#"
+        [[target]]
+        name = "default"
+        command = "true"
+        rootfs_overlay = {}
+
+        [[target]]
+        name = "disk"
+        command = "true"
+        rootfs_overlay = { upper = "disk", size = "2G" }
+        "#,
+    )
+    .unwrap();
+
+    let overlay = config.target[0].rootfs_overlay.as_ref().unwrap();
+    assert_eq!(overlay.upper, OverlayUpper::Tmpfs);
+    assert_eq!(overlay.size, None);
+
+    let overlay = config.target[1].rootfs_overlay.as_ref().unwrap();
+    assert_eq!(overlay.upper, OverlayUpper::Disk);
+    assert_eq!(overlay.size.as_deref(), Some("2G"));
+}
+
+#[test]
+fn test_rootfs() {
+    let config: Config = toml::from_str(
+        r#"
+        [[target]]
+        name = "default"
+        command = "true"
+
+        [[target]]
+        name = "path"
+        command = "true"
+        rootfs = "./rootfs.tar.zst"
+
+        [[target]]
+        name = "from host"
+        command = "true"
+        rootfs = { from_host = ["bash", "./my-test"] }
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.target[0].rootfs, Rootfs::Path("/".into()));
+    assert_eq!(
+        config.target[1].rootfs,
+        Rootfs::Path("./rootfs.tar.zst".into())
+    );
+    assert_eq!(
+        config.target[2].rootfs,
+        Rootfs::FromHost {
+            from_host: vec!["bash".into(), "./my-test".into()]
+        }
+    );
+
+    let res = toml::from_str::<Config>(
+        r#"
+        [[target]]
+        name = "invalid"
+        command = "true"
+        rootfs = { from_guest = ["bash"] }
+        "#,
+    );
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_disks() {
+    let config: Config = toml::from_str(
+        r#"
+        [[target]]
+        name = "test"
+        command = "true"
+
+        [[target.vm.disks]]
+        name = "scratch"
+        size = "1G"
+
+        [[target.vm.disks]]
+        name = "install_media"
+        path = "install.iso"
+        media = "cdrom"
+
+        [[target.vm.disks]]
+        name = "data"
+        path = "data.qcow2"
+        format = "qcow2"
+        readonly = true
+        bus = "nvme"
+        "#,
+    )
+    .unwrap();
+
+    let disks = &config.target[0].vm.disks;
+    assert_eq!(disks.len(), 3);
+    assert_eq!(disks[0].bus(), DiskBus::VirtioBlk);
+    assert_eq!(disks[0].env_var(), "VMTEST_DISK_SCRATCH");
+    assert_eq!(disks[1].media, DiskMedia::Cdrom);
+    assert_eq!(disks[1].bus(), DiskBus::VirtioScsi);
+    assert_eq!(disks[1].env_var(), "VMTEST_DISK_INSTALL_MEDIA");
+    assert!(disks[2].readonly);
+    assert_eq!(disks[2].bus(), DiskBus::Nvme);
+    assert_eq!(disks[2].format.as_deref(), Some("qcow2"));
+}
+
+#[test]
+fn test_controllers() {
+    let config: Config = toml::from_str(
+        r#"
+        [[target]]
+        name = "test"
+        command = "true"
+
+        [[target.vm.controllers]]
+        name = "nvme0"
+        kind = "nvme"
+        queues = 4
+
+        [[target.vm.disks]]
+        name = "ns1"
+        size = "1G"
+        bus = "nvme"
+        controller = "nvme0"
+        logical_block_size = 4096
+
+        [[target.vm.disks]]
+        name = "ns2"
+        size = "1G"
+        bus = "nvme"
+        controller = "nvme0"
+        "#,
+    )
+    .unwrap();
+
+    let vm = &config.target[0].vm;
+    assert_eq!(vm.controllers[0].kind, ControllerKind::Nvme);
+    assert_eq!(vm.controllers[0].queues, Some(4));
+    assert_eq!(vm.disks[0].controller.as_deref(), Some("nvme0"));
+    assert_eq!(vm.disks[0].logical_block_size, Some(4096));
+    assert_eq!(vm.disks[1].logical_block_size, None);
+}
+
+#[test]
+fn test_cloud_init() {
+    let config: Config = toml::from_str(
+        r#"
+        [[target]]
+        name = "default"
+        command = "true"
+        cloud_init = {}
+
+        [[target]]
+        name = "custom"
+        command = "true"
+        [target.cloud_init]
+        packages = ["qemu-guest-agent", "fio"]
+        services = ["qemu-guest-agent", "sshd"]
+        "#,
+    )
+    .unwrap();
+
+    let c = config.target[0].cloud_init.as_ref().unwrap();
+    assert!(c.user_data.is_none());
+    assert!(c.packages.is_empty());
+    assert!(c.services.is_none());
+
+    let c = config.target[1].cloud_init.as_ref().unwrap();
+    assert_eq!(c.packages, vec!["qemu-guest-agent", "fio"]);
+    assert_eq!(
+        c.services.as_deref(),
+        Some(&["qemu-guest-agent".to_string(), "sshd".to_string()][..])
+    );
+}
+
+#[test]
+fn test_topology_and_numa() {
+    let config: Config = toml::from_str(
+        r#"
+        [[target]]
+        name = "test"
+        command = "true"
+        [target.vm]
+        num_cpus = 8
+        memory = "4G"
+        topology = { sockets = 2, cores = 2, threads = 2 }
+
+        [[target.vm.numa]]
+        cpus = "0-3"
+        memory = "3G"
+
+        [[target.vm.numa]]
+        cpus = "4-7"
+        memory = "1G"
+        "#,
+    )
+    .unwrap();
+
+    let vm = &config.target[0].vm;
+    assert_eq!(vm.topology.as_ref().unwrap().cpus(), 8);
+    assert_eq!(vm.numa.len(), 2);
+    assert_eq!(vm.numa[1].cpus.as_deref(), Some("4-7"));
+    assert_eq!(vm.numa[1].memory, "1G");
+
+    let topology: Topology = toml::from_str("cores = 4").unwrap();
+    assert_eq!(
+        (topology.sockets, topology.cores, topology.threads),
+        (1, 4, 1)
+    );
+}
+
+#[test]
+fn test_memory_backend() {
+    let config: Config = toml::from_str(
+        r#"
+        [[target]]
+        name = "test"
+        command = "true"
+        vm.memory_backend = { kind = "hugetlbfs", prealloc = true }
+
+        [[target]]
+        name = "test2"
+        command = "true"
+        vm.memory_backend = { kind = "memfd", share = false }
+        "#,
+    )
+    .unwrap();
+
+    let backend = config.target[0].vm.memory_backend.as_ref().unwrap();
+    assert_eq!(backend.kind, MemoryBackendKind::Hugetlbfs);
+    assert!(backend.prealloc);
+    assert!(backend.path.is_none());
+    assert!(backend.share.is_none());
+
+    let backend = config.target[1].vm.memory_backend.as_ref().unwrap();
+    assert_eq!(backend.kind, MemoryBackendKind::Memfd);
+    assert!(!backend.prealloc);
+    assert_eq!(backend.share, Some(false));
+
+    let backend: MemoryBackend = toml::from_str("").unwrap();
+    assert_eq!(backend.kind, MemoryBackendKind::Anonymous);
+    assert!(toml::from_str::<MemoryBackend>(r#"kind = "shm""#).is_err());
+}
+
+#[test]
+fn test_target_builder() {
+    let vm = VMConfig::builder()
+        .num_cpus(4)
+        .memory("8G")
+        .mount(
+            "/mnt/host",
+            Mount {
+                host_path: "/tmp".into(),
+                writable: true,
+            },
+        )
+        .extra_arg("-no-reboot")
+        .build();
+    let target = Target::builder("test")
+        .kernel("bzImage")
+        .rootfs(PathBuf::from("rootfs.tar"))
+        .command("true")
+        .vm(vm)
+        .build()
+        .unwrap();
+
+    assert_eq!(target.name, "test");
+    assert_eq!(target.kernel, Some("bzImage".into()));
+    assert_eq!(target.rootfs, Rootfs::Path("rootfs.tar".into()));
+    assert_eq!(target.arch, Target::default_arch());
+    assert_eq!(target.kvm_cpu_args, Target::default_kvm_cpu_args());
+    assert_eq!(target.vm.num_cpus, 4);
+    assert_eq!(target.vm.memory, "8G");
+    assert!(target.vm.mounts["/mnt/host"].writable);
+    assert_eq!(target.vm.extra_args, vec!["-no-reboot"]);
+
+    let renamed = Target::builder("test")
+        .name("renamed")
+        .kernel("bzImage")
+        .command("true")
+        .build()
+        .unwrap();
+    assert_eq!(renamed.name, "renamed");
+
+    // Builders validate like config files do
+    let err = |builder: TargetBuilder| match builder.build() {
+        Ok(_) => panic!("Target was accepted"),
+        Err(e) => e.to_string(),
+    };
+    assert_eq!(
+        err(Target::builder("test").command("true")),
+        "Target 'test' must specify 'image' or 'kernel'"
+    );
+    assert_eq!(
+        err(Target::builder("test").kernel("bzImage")),
+        "Target 'test' has empty command"
+    );
+    assert_eq!(
+        err(Target::builder("test")
+            .name("")
+            .kernel("bzImage")
+            .command("true")),
+        "Target index=0 name empty"
+    );
+    assert_eq!(
+        err(Target::builder("test")
+            .kernel("bzImage")
+            .image("image.qcow2")
+            .command("true")),
+        "Target 'test' specified both 'image' and 'kernel'"
+    );
+    // Memory is only parsed when vmtest has to size a memory backend
+    let vm = VMConfig::builder()
+        .memory("lots")
+        .memory_backend(MemoryBackend::default())
+        .build();
+    assert_eq!(
+        err(Target::builder("test")
+            .kernel("bzImage")
+            .command("true")
+            .vm(vm)),
+        "Target 'test' has invalid memory"
+    );
+}
+
+#[test]
+fn test_config_builder() {
+    let target = Target::builder("test")
+        .kernel("bzImage")
+        .command("true")
+        .build()
+        .unwrap();
+    let config = Config::builder()
+        .target(target.clone())
+        .target(
+            Target::builder("other")
+                .image("image.qcow2")
+                .command("true")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    assert_eq!(config.target.len(), 2);
+
+    let mut unnamed = target;
+    unnamed.name = String::new();
+    assert!(Config::builder().target(unnamed).build().is_err());
+}
@@ -0,0 +1,186 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, warn};
+use qapi::{qmp, Qmp};
+
+use crate::qemu::parse_memory;
+
+/// Prefix of the QOM path of CPUs hotplugged through [`Request::CpuAdd`]
+const HOTPLUG_CPU_PATH: &str = "/machine/peripheral/";
+/// Prefix of the device id of CPUs hotplugged through [`Request::CpuAdd`]
+const HOTPLUG_CPU_ID: &str = "vmtest-cpu";
+
+/// A request from the guest to change the VM
+#[derive(Debug, PartialEq)]
+enum Request {
+    /// Resize the balloon so the guest is left with this many bytes of RAM
+    Balloon(u64),
+    /// Plug in the next free vCPU
+    CpuAdd,
+    /// Unplug the most recently plugged in vCPU
+    CpuDel,
+}
+
+impl Request {
+    /// Parse a request line sent by `vmtest-ctl`
+    fn parse(line: &str) -> Result<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["balloon", size] => Ok(Self::Balloon(parse_memory(size)?)),
+            ["cpu-add"] => Ok(Self::CpuAdd),
+            ["cpu-del"] => Ok(Self::CpuDel),
+            _ => bail!("Invalid request '{line}'"),
+        }
+    }
+}
+
+/// Index of the lowest numbered free vCPU slot
+///
+/// QEMU lists slots from the highest numbered one down.
+fn free_cpu_slot(cpus: &[qmp::HotpluggableCPU]) -> Option<usize> {
+    cpus.iter()
+        .rev()
+        .position(|cpu| cpu.qom_path.is_none())
+        .map(|i| cpus.len() - 1 - i)
+}
+
+/// Device id of the highest numbered vCPU plugged in by vmtest
+fn plugged_cpu(cpus: &[qmp::HotpluggableCPU]) -> Option<String> {
+    cpus.iter()
+        .filter_map(|cpu| cpu.qom_path.as_deref()?.strip_prefix(HOTPLUG_CPU_PATH))
+        .find(|id| id.starts_with(HOTPLUG_CPU_ID))
+        .map(|id| id.to_string())
+}
+
+/// Carry out `request` through `qmp`
+fn handle<S: BufRead + Write>(qmp: &mut Qmp<S>, request: &Request) -> Result<()> {
+    match request {
+        Request::Balloon(size) => {
+            let value = i64::try_from(*size).context("Balloon size too large")?;
+            qmp.execute(&qmp::balloon { value })
+                .context("Failed to resize balloon")?;
+        }
+        Request::CpuAdd => {
+            let cpus = qmp
+                .execute(&qmp::query_hotpluggable_cpus {})
+                .context("Failed to query hotpluggable CPUs")?;
+            let slot = free_cpu_slot(&cpus).ok_or_else(|| anyhow!("No free CPU slots"))?;
+            // Slots are numbered from 0 but listed in reverse
+            let id = format!("{HOTPLUG_CPU_ID}{}", cpus.len() - 1 - slot);
+            let cpu = &cpus[slot];
+            let arguments = match serde_json::to_value(&cpu.props)? {
+                serde_json::Value::Object(props) => props,
+                _ => bail!("Unexpected CPU properties: {:?}", cpu.props),
+            };
+            qmp.execute(&qmp::device_add {
+                bus: None,
+                id: Some(id),
+                driver: cpu.type_.clone(),
+                arguments,
+            })
+            .context("Failed to plug in CPU")?;
+        }
+        Request::CpuDel => {
+            let cpus = qmp
+                .execute(&qmp::query_hotpluggable_cpus {})
+                .context("Failed to query hotpluggable CPUs")?;
+            let id = plugged_cpu(&cpus).ok_or_else(|| anyhow!("No hotplugged CPUs to unplug"))?;
+            qmp.execute(&qmp::device_del { id })
+                .context("Failed to unplug CPU")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Serve requests from the guest until `stream` is shut down
+///
+/// Each request is a single line and gets a single line reply of either
+/// `ok` or `error <message>`.
+pub fn serve<S: BufRead + Write>(stream: UnixStream, qmp: &mut Qmp<S>) {
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(e) => {
+            warn!("Failed to clone control socket: {e}");
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                debug!("Failed to read from control socket: {e}");
+                break;
+            }
+        };
+        debug!("Control request: {line}");
+
+        let reply = match Request::parse(&line).and_then(|r| handle(qmp, &r)) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => format!("error {e:#}"),
+        };
+        if let Err(e) = writeln!(writer, "{reply}") {
+            debug!("Failed to reply on control socket: {e}");
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn cpu(qom_path: Option<&str>, socket: i64) -> qmp::HotpluggableCPU {
+        qmp::HotpluggableCPU {
+            qom_path: qom_path.map(Into::into),
+            props: qmp::CpuInstanceProperties {
+                cluster_id: None,
+                core_id: Some(0),
+                die_id: None,
+                node_id: None,
+                socket_id: Some(socket),
+                thread_id: Some(0),
+            },
+            type_: "qemu64-x86_64-cpu".into(),
+            vcpus_count: 1,
+        }
+    }
+
+    #[rstest]
+    #[case("balloon 512M", Some(Request::Balloon(512 << 20)))]
+    #[case("balloon 1024", Some(Request::Balloon(1 << 30)))]
+    #[case("cpu-add", Some(Request::CpuAdd))]
+    #[case(" cpu-del ", Some(Request::CpuDel))]
+    #[case("balloon", None)]
+    #[case("balloon lots", None)]
+    #[case("cpu-add 2", None)]
+    #[case("reboot", None)]
+    fn test_parse_request(#[case] line: &str, #[case] expected: Option<Request>) {
+        assert_eq!(Request::parse(line).ok(), expected);
+    }
+
+    #[test]
+    fn test_cpu_slots() {
+        // Listed from the highest numbered slot down like QEMU does
+        let mut cpus = vec![
+            cpu(None, 3),
+            cpu(None, 2),
+            cpu(Some("/machine/unattached/device[2]"), 1),
+            cpu(Some("/machine/unattached/device[0]"), 0),
+        ];
+        assert_eq!(free_cpu_slot(&cpus), Some(1));
+        assert_eq!(plugged_cpu(&cpus), None);
+
+        cpus[1] = cpu(Some("/machine/peripheral/vmtest-cpu2"), 2);
+        assert_eq!(free_cpu_slot(&cpus), Some(0));
+        assert_eq!(plugged_cpu(&cpus).as_deref(), Some("vmtest-cpu2"));
+
+        cpus[0] = cpu(Some("/machine/peripheral/vmtest-cpu3"), 3);
+        assert_eq!(free_cpu_slot(&cpus), None);
+        assert_eq!(plugged_cpu(&cpus).as_deref(), Some("vmtest-cpu3"));
+    }
+}
@@ -0,0 +1,396 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+/// Offset of `EI_CLASS` in `e_ident`
+const EI_CLASS: usize = 4;
+/// Offset of `EI_DATA` in `e_ident`
+const EI_DATA: usize = 5;
+/// Offset of `e_machine`, which is the same for 32 and 64 bit files
+const E_MACHINE: usize = 18;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2MSB: u8 = 2;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+/// The identifying parts of an ELF file header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Whether the file is 64 bit
+    pub class64: bool,
+    /// Whether the file is big endian
+    pub big_endian: bool,
+    /// Target instruction set, one of the `EM_*` constants
+    pub machine: u16,
+}
+
+/// Parse the ELF header at the start of `data`
+///
+/// Returns `None` if `data` is not an ELF file.
+pub fn parse_header(data: &[u8]) -> Option<Header> {
+    if !data.starts_with(ELF_MAGIC) || data.len() < E_MACHINE + 2 {
+        return None;
+    }
+
+    let big_endian = data[EI_DATA] == ELFDATA2MSB;
+    let machine = [data[E_MACHINE], data[E_MACHINE + 1]];
+    Some(Header {
+        class64: data[EI_CLASS] == ELFCLASS64,
+        big_endian,
+        machine: if big_endian {
+            u16::from_be_bytes(machine)
+        } else {
+            u16::from_le_bytes(machine)
+        },
+    })
+}
+
+/// What an ELF file needs from the dynamic linker
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Dynamic {
+    /// Path of the program interpreter, usually the dynamic linker
+    pub interp: Option<String>,
+    /// Names of the shared libraries from `DT_NEEDED`
+    pub needed: Vec<String>,
+    /// Library search path from `DT_RUNPATH`, or `DT_RPATH` if absent
+    pub runpath: Vec<String>,
+}
+
+/// Reads fields of an ELF file in its class and byte order
+struct Reader<'a> {
+    data: &'a [u8],
+    header: Header,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes<const N: usize>(&self, offset: u64) -> Result<[u8; N]> {
+        usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.data.get(start..start.checked_add(N)?))
+            .map(|b| b.try_into().unwrap())
+            .ok_or_else(|| anyhow!("ELF file truncated at offset {offset}"))
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16> {
+        let b = self.bytes(offset)?;
+        Ok(if self.header.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32> {
+        let b = self.bytes(offset)?;
+        Ok(if self.header.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+
+    /// Read an address or offset sized field
+    fn word(&self, offset: u64) -> Result<u64> {
+        if !self.header.class64 {
+            return self.u32(offset).map(u64::from);
+        }
+        let b = self.bytes(offset)?;
+        Ok(if self.header.big_endian {
+            u64::from_be_bytes(b)
+        } else {
+            u64::from_le_bytes(b)
+        })
+    }
+
+    /// Read the NUL terminated string at `offset`
+    fn string(&self, offset: u64) -> Result<String> {
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.data.get(start..))
+            .ok_or_else(|| anyhow!("ELF string offset {offset} out of bounds"))?;
+        let end = data
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| anyhow!("Unterminated ELF string at offset {offset}"))?;
+        Ok(String::from_utf8_lossy(&data[..end]).into_owned())
+    }
+}
+
+/// A program header
+struct Segment {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+/// Parse the program headers of the ELF file
+fn segments(r: &Reader) -> Result<Vec<Segment>> {
+    // Offsets of e_phoff, e_phentsize and e_phnum, followed by the offsets of
+    // p_offset, p_vaddr and p_filesz within a program header
+    let (phoff, phentsize, phnum, p_offset, p_vaddr, p_filesz) = if r.header.class64 {
+        (32, 54, 56, 8, 16, 32)
+    } else {
+        (28, 42, 44, 4, 8, 16)
+    };
+    let phoff = r.word(phoff)?;
+    let phentsize = u64::from(r.u16(phentsize)?);
+
+    (0..u64::from(r.u16(phnum)?))
+        .map(|i| {
+            let ph = phoff.saturating_add(i * phentsize);
+            Ok(Segment {
+                kind: r.u32(ph)?,
+                offset: r.word(ph.saturating_add(p_offset))?,
+                vaddr: r.word(ph.saturating_add(p_vaddr))?,
+                filesz: r.word(ph.saturating_add(p_filesz))?,
+            })
+        })
+        .collect()
+}
+
+/// Parse the dynamic linking information of the ELF file in `data`
+///
+/// Returns `None` if `data` is not an ELF file. Statically linked files
+/// have no interpreter and need no libraries.
+pub fn parse_dynamic(data: &[u8]) -> Result<Option<Dynamic>> {
+    let header = match parse_header(data) {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    let r = Reader { data, header };
+    let segments = segments(&r)?;
+    let mut dynamic = Dynamic::default();
+
+    if let Some(interp) = segments.iter().find(|s| s.kind == PT_INTERP) {
+        dynamic.interp = Some(r.string(interp.offset)?);
+    }
+
+    let table = match segments.iter().find(|s| s.kind == PT_DYNAMIC) {
+        Some(s) => s,
+        None => return Ok(Some(dynamic)),
+    };
+    let entsize = if header.class64 { 16 } else { 8 };
+    let mut strtab = None;
+    let mut needed = Vec::new();
+    let mut rpath = None;
+    let mut runpath = None;
+    for i in 0..table.filesz / entsize {
+        let entry = table.offset.saturating_add(i * entsize);
+        let tag = r.word(entry)?;
+        let value = r.word(entry.saturating_add(entsize / 2))?;
+        match tag {
+            DT_NULL => break,
+            DT_NEEDED => needed.push(value),
+            DT_STRTAB => strtab = Some(value),
+            DT_RPATH => rpath = Some(value),
+            DT_RUNPATH => runpath = Some(value),
+            _ => (),
+        }
+    }
+
+    // DT_STRTAB is an address, so find where it was loaded from
+    let strtab = match strtab {
+        Some(addr) => segments
+            .iter()
+            .find(|s| {
+                s.kind == PT_LOAD && s.vaddr <= addr && addr < s.vaddr.saturating_add(s.filesz)
+            })
+            .map(|s| (addr - s.vaddr).saturating_add(s.offset))
+            .ok_or_else(|| anyhow!("DT_STRTAB address {addr:#x} is not loaded from the file"))?,
+        None if needed.is_empty() && rpath.is_none() && runpath.is_none() => {
+            return Ok(Some(dynamic))
+        }
+        None => bail!("Dynamic section has no DT_STRTAB"),
+    };
+    for name in needed {
+        dynamic.needed.push(r.string(strtab.saturating_add(name))?);
+    }
+    if let Some(path) = runpath.or(rpath) {
+        dynamic.runpath = r
+            .string(strtab.saturating_add(path))?
+            .split(':')
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+    }
+
+    Ok(Some(dynamic))
+}
+
+/// Read the dynamic linking information of the ELF file at `path`
+///
+/// Returns `None` if the file is not an ELF file.
+pub fn read_dynamic(path: &Path) -> Result<Option<Dynamic>> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    parse_dynamic(&data).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Read the ELF header of the file at `path`
+///
+/// Returns `None` if the file is not an ELF file, like a shell script.
+pub fn read_header(path: &Path) -> Result<Option<Header>> {
+    let mut data = [0; E_MACHINE + 2];
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    match file.read_exact(&mut data) {
+        Ok(_) => Ok(parse_header(&data)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x02\0\x3e\0", Some((true, false, 62)))]
+    #[case(b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0\x02\0\x28\0", Some((false, false, 40)))]
+    #[case(b"\x7fELF\x02\x02\x01\0\0\0\0\0\0\0\0\0\0\x02\0\x16", Some((true, true, 22)))]
+    #[case(b"#!/bin/sh\nexec true\n", None)]
+    #[case(b"\x7fELF", None)]
+    fn test_parse_header(#[case] data: &[u8], #[case] expected: Option<(bool, bool, u16)>) {
+        let expected = expected.map(|(class64, big_endian, machine)| Header {
+            class64,
+            big_endian,
+            machine,
+        });
+        assert_eq!(parse_header(data), expected);
+    }
+
+    /// Build an executable with an interpreter and dynamic section
+    ///
+    /// Everything lives in a single segment loaded at 0x400000.
+    fn build_elf(class64: bool, big_endian: bool, dynamic: &[(u64, &str)]) -> Vec<u8> {
+        let (ehsize, phentsize, dynentsize) = if class64 { (64, 56, 16) } else { (52, 32, 8) };
+        let base = 0x400000;
+        let phoff = ehsize;
+        let interp_off = phoff + 3 * phentsize;
+        let interp = b"/lib/ld.so\0";
+        let strtab_off = interp_off + interp.len();
+        let mut strtab = vec![0u8];
+        let mut entries = Vec::new();
+        for (tag, value) in dynamic {
+            entries.push((*tag, strtab.len() as u64));
+            strtab.extend(value.as_bytes());
+            strtab.push(0);
+        }
+        entries.push((DT_STRTAB, (base + strtab_off) as u64));
+        entries.push((DT_NULL, 0));
+        let dyn_off = strtab_off + strtab.len();
+        let size = dyn_off + entries.len() * dynentsize;
+
+        let mut data = vec![0u8; size];
+        let put = |data: &mut Vec<u8>, off: usize, value: u64, len: usize| {
+            let bytes = if big_endian {
+                value.to_be_bytes()[8 - len..].to_vec()
+            } else {
+                value.to_le_bytes()[..len].to_vec()
+            };
+            data[off..off + len].copy_from_slice(&bytes);
+        };
+        let word = if class64 { 8 } else { 4 };
+        data[..4].copy_from_slice(ELF_MAGIC);
+        data[EI_CLASS] = if class64 { 2 } else { 1 };
+        data[EI_DATA] = if big_endian { 2 } else { 1 };
+        put(&mut data, E_MACHINE, 62, 2);
+        if class64 {
+            put(&mut data, 32, phoff as u64, 8);
+            put(&mut data, 54, phentsize as u64, 2);
+            put(&mut data, 56, 3, 2);
+        } else {
+            put(&mut data, 28, phoff as u64, 4);
+            put(&mut data, 42, phentsize as u64, 2);
+            put(&mut data, 44, 3, 2);
+        }
+        let (p_offset, p_vaddr, p_filesz) = if class64 { (8, 16, 32) } else { (4, 8, 16) };
+        let phdrs = [
+            (PT_INTERP, interp_off, interp.len()),
+            (PT_LOAD, 0, size),
+            (PT_DYNAMIC, dyn_off, size - dyn_off),
+        ];
+        for (i, (kind, offset, filesz)) in phdrs.into_iter().enumerate() {
+            let ph = phoff.saturating_add(i * phentsize);
+            put(&mut data, ph, kind.into(), 4);
+            put(&mut data, ph + p_offset, offset as u64, word);
+            put(&mut data, ph + p_vaddr, (base + offset) as u64, word);
+            put(&mut data, ph + p_filesz, filesz as u64, word);
+        }
+        data[interp_off..interp_off + interp.len()].copy_from_slice(interp);
+        data[strtab_off..strtab_off + strtab.len()].copy_from_slice(&strtab);
+        for (i, (tag, value)) in entries.into_iter().enumerate() {
+            let entry = dyn_off + i * dynentsize;
+            put(&mut data, entry, tag, word);
+            put(&mut data, entry + word, value, word);
+        }
+        data
+    }
+
+    #[rstest]
+    #[case(true, false)]
+    #[case(true, true)]
+    #[case(false, false)]
+    #[case(false, true)]
+    fn test_parse_dynamic(#[case] class64: bool, #[case] big_endian: bool) {
+        let data = build_elf(
+            class64,
+            big_endian,
+            &[
+                (DT_NEEDED, "libfoo.so.1"),
+                (DT_RPATH, "/ignored"),
+                (DT_RUNPATH, "$ORIGIN/../lib:/opt/lib"),
+                (DT_NEEDED, "libc.so.6"),
+            ],
+        );
+        assert_eq!(
+            parse_dynamic(&data).unwrap(),
+            Some(Dynamic {
+                interp: Some("/lib/ld.so".into()),
+                needed: vec!["libfoo.so.1".into(), "libc.so.6".into()],
+                runpath: vec!["$ORIGIN/../lib".into(), "/opt/lib".into()],
+            })
+        );
+
+        let data = build_elf(class64, big_endian, &[(DT_RPATH, "/opt/lib")]);
+        assert_eq!(
+            parse_dynamic(&data).unwrap().unwrap().runpath,
+            vec!["/opt/lib".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_parse_dynamic_errors() {
+        assert_eq!(parse_dynamic(b"#!/bin/sh\n").unwrap(), None);
+
+        let data = build_elf(true, false, &[(DT_NEEDED, "libc.so.6")]);
+        assert!(parse_dynamic(&data[..100]).is_err());
+    }
+
+    #[test]
+    fn test_read_dynamic() {
+        let dynamic = read_dynamic(Path::new("/proc/self/exe")).unwrap().unwrap();
+        // The test binary may be statically linked
+        if dynamic.interp.is_some() {
+            assert!(dynamic.needed.iter().any(|n| n.starts_with("libc.")));
+        }
+    }
+
+    #[test]
+    fn test_read_header() {
+        let header = read_header(Path::new("/proc/self/exe")).unwrap().unwrap();
+        assert_eq!(header.class64, cfg!(target_pointer_width = "64"));
+        assert_eq!(header.big_endian, cfg!(target_endian = "big"));
+    }
+}
@@ -5,6 +5,12 @@
 pub mod config;
 /// Contains definitions for streaming output
 pub mod output;
+/// Contains the outcome of target runs.
+pub mod result;
+/// Contains per-run directories for sockets and scripts.
+pub mod run_dir;
+/// Contains the session control socket protocol.
+pub mod session;
 /// Contains user interface code.
 pub mod ui;
 /// Contains main vmtest logic.
@@ -14,5 +20,11 @@ pub use crate::config::*;
 pub use crate::ui::*;
 pub use crate::vmtest::*;
 
+mod cache;
+mod cloud_init;
+mod control;
+mod elf;
 mod qemu;
 mod qga;
+mod rootfs;
+mod tpm;
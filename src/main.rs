@@ -1,21 +1,33 @@
 use std::cell::OnceCell;
 use std::env::consts::ARCH;
 use std::fs::{self, File};
-use std::io::{stdout, IsTerminal as _};
+use std::io::{stdout, BufRead, BufReader, IsTerminal as _, Write};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::exit;
+use std::process::{exit, Command, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
 use std::{env, io};
 
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
 use env_logger::{fmt::Target as LogTarget, Builder};
 use regex::Regex;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
-use vmtest::{Config, Target, Ui, VMConfig, Vmtest};
+use vmtest::output::{JsonSink, LogSink, Output};
+use vmtest::run_dir;
+use vmtest::session::{self, Client};
+use vmtest::{Canceller, Config, RootfsOverlay, Target, Ui, Vmtest, CONFIG_FILE, EX_UNAVAILABLE};
 
 const HELP_ENV_VARS: &str = r#"Environment variables:
   VMTEST_NO_UI    Set to disable UI  [default: unset]
 "#;
+// Printed by a session server once it is ready to accept commands
+const SESSION_READY: &str = "Session ready";
 
 #[derive(Parser, Debug)]
 #[clap(version, disable_colored_help=true, after_help=HELP_ENV_VARS)]
@@ -40,20 +52,94 @@ struct Args {
     /// KVM -cpu arguments
     #[clap(long, conflicts_with = "config", default_value = "host")]
     kvm_cpu_args: Option<String>,
-    /// Location of rootfs, default to host's /
+    /// Location of rootfs directory, tarball, OCI layout or squashfs/erofs image, default to host's /
     #[clap(short, long, conflicts_with = "config", default_value = Target::default_rootfs().into_os_string())]
     rootfs: PathBuf,
+    /// Overlay the rootfs with a disposable, writable tmpfs layer
+    #[clap(long, conflicts_with = "config")]
+    rootfs_overlay: bool,
     /// Arch to run
     #[clap(short, long, default_value = ARCH, conflicts_with = "config")]
     arch: String,
     /// Command to use to launch QEMU. Can be a full path or a PATH-resolved binary. If none is
-    /// provided, we default to `qemu-system-$ARCH`, where $ARCH is the value of the `arch`
-    /// argument
+    /// provided, we default to the `qemu-system-*` binary for the `arch` argument, such as
+    /// `qemu-system-ppc64` for ppc64le
     #[clap(short, long, conflicts_with = "config")]
     qemu_command: Option<String>,
+    /// Drop into a shell inside the VM if a command fails
+    ///
+    /// The VM is kept alive until the shell exits.
+    #[clap(long)]
+    shell_on_failure: bool,
+    /// Also record the progress of all targets to a file as JSON lines
+    #[clap(long)]
+    output_json: Option<PathBuf>,
+    /// Also record the progress of all targets to a plain text log file
+    ///
+    /// Unlike on the console, no output is collapsed.
+    #[clap(long)]
+    output_log: Option<PathBuf>,
+    /// Boot each selected target N times and report how long booting took
+    ///
+    /// Targets are shut down once set up; their commands are not run.
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    bench: Option<u32>,
     /// Command to run in kernel mode. `-` to get an interactive shell.
     #[clap(conflicts_with = "config")]
     command: Vec<String>,
+    #[clap(subcommand)]
+    subcommand: Option<Subcommands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Subcommands {
+    /// Boot a target and attach the terminal to a login shell in it
+    ///
+    /// Mounts are set up as usual but the target's command is not run.
+    Shell {
+        /// Name of the target. May be omitted if only one target is selected
+        target: Option<String>,
+    },
+    /// Manage VMs that stay alive across multiple commands
+    ///
+    /// The target is selected with the same options used to run targets.
+    /// Exactly one target must be selected.
+    Session {
+        #[clap(subcommand)]
+        action: SessionAction,
+    },
+    /// Remove sockets and scripts left behind by runs that did not exit cleanly
+    ///
+    /// Files belonging to runs that are still alive are left alone.
+    Gc,
+}
+
+#[derive(Subcommand, Debug)]
+enum SessionAction {
+    /// Boot the target and keep it running in the background
+    Start {
+        /// Name of the session
+        #[clap(short, long, default_value = "default")]
+        name: String,
+        /// Do not detach from the terminal
+        #[clap(long)]
+        foreground: bool,
+    },
+    /// Run a command in a running session
+    Exec {
+        /// Name of the session
+        #[clap(short, long, default_value = "default")]
+        name: String,
+        /// Command to run
+        #[clap(required = true)]
+        command: Vec<String>,
+    },
+    /// Shut down a running session
+    Stop {
+        /// Name of the session
+        #[clap(short, long, default_value = "default")]
+        name: String,
+    },
 }
 
 /// A type representing a log that creates the associated file lazily
@@ -116,36 +202,39 @@ fn config(args: &Args) -> Result<Vmtest> {
     match &args.kernel {
         Some(kernel) => {
             let cwd = env::current_dir().context("Failed to get current directory")?;
-            let config = Config {
-                target: vec![Target {
-                    name: kernel.file_name().unwrap().to_string_lossy().to_string(),
-                    image: None,
-                    uefi: false,
-                    kernel: Some(kernel.clone()),
-                    rootfs: args.rootfs.clone(),
-                    arch: args.arch.clone(),
-                    kernel_args: args.kargs.clone(),
-                    kvm_cpu_args: args.kvm_cpu_args.clone(),
-                    qemu_command: args.qemu_command.clone(),
-                    command: args.command.join(" "),
-                    vm: VMConfig::default(),
-                }],
-            };
+            let mut target = Target::builder(kernel.file_name().unwrap().to_string_lossy())
+                .kernel(kernel)
+                .rootfs(args.rootfs.clone())
+                .arch(&args.arch)
+                .kvm_cpu_args(args.kvm_cpu_args.clone())
+                .command(match (&args.subcommand, args.bench) {
+                    // Subcommands and benchmarks do not run the target's command
+                    (Some(_), _) | (_, Some(_)) => "true".into(),
+                    (None, None) => args.command.join(" "),
+                });
+            if args.rootfs_overlay {
+                target = target.rootfs_overlay(RootfsOverlay::default());
+            }
+            if let Some(kargs) = &args.kargs {
+                target = target.kernel_args(kargs);
+            }
+            if let Some(command) = &args.qemu_command {
+                target = target.qemu_command(command);
+            }
+            let config = Config::builder().target(target.build()?).build()?;
             Vmtest::new(cwd, config)
         }
         None => {
-            let default = Path::new("vmtest.toml").to_owned();
+            let default = Path::new(CONFIG_FILE).to_owned();
             let config_path = args.config.as_ref().unwrap_or(&default);
-            let contents = fs::read_to_string(config_path).context("Failed to read config file")?;
             let filter = Regex::new(&args.filter).context("Failed to compile regex")?;
-            let mut config: Config = toml::from_str(&contents).context("Failed to parse config")?;
-            config.target = config
-                .target
-                .into_iter()
-                .filter(|t| filter.is_match(&t.name))
-                .collect::<Vec<_>>();
-            let base = config_path.parent().unwrap();
-            Vmtest::new(base, config)
+            let name = match &args.subcommand {
+                Some(Subcommands::Shell { target }) => target.as_ref(),
+                _ => None,
+            };
+            Vmtest::from_path(config_path, |t| {
+                filter.is_match(&t.name) && name.map_or(true, |n| *n == t.name)
+            })
         }
     }
 }
@@ -157,13 +246,227 @@ fn show_cmd(args: &Args) -> bool {
     args.kernel.is_some()
 }
 
+/// Configure a `Vmtest` instance for subcommands that operate on a single target
+fn single_target_config(args: &Args) -> Result<Vmtest> {
+    let vmtest = config(args)?;
+    if vmtest.targets().len() != 1 {
+        bail!(
+            "Exactly one target must be selected, but {} were selected",
+            vmtest.targets().len()
+        );
+    }
+
+    Ok(vmtest)
+}
+
+/// Cancel runs on SIGINT and SIGTERM
+///
+/// Returns where the first signal received is stored, or 0 if none was. A
+/// second signal exits right away without waiting for the VM to shut down.
+fn handle_signals(canceller: Canceller) -> Result<Arc<AtomicI32>> {
+    let mut signals = Signals::new([SIGINT, SIGTERM]).context("Failed to set up signals")?;
+    let received = Arc::new(AtomicI32::new(0));
+    let r = received.clone();
+    thread::spawn(move || {
+        for sig in signals.forever() {
+            if r.swap(sig, Ordering::SeqCst) != 0 {
+                exit(signal_rc(sig));
+            }
+            // Keep listening while QEMU shuts down
+            canceller.cancel_in_background();
+        }
+    });
+
+    Ok(received)
+}
+
+/// Conventional exit code of a process killed by signal `sig`
+fn signal_rc(sig: i32) -> i32 {
+    128 + sig
+}
+
+/// Construct the UI for `vmtest`, recording to the files requested in `args`
+fn ui(args: &Args, vmtest: Vmtest) -> Result<Ui> {
+    let mut ui = Ui::new(vmtest);
+    if let Some(path) = &args.output_json {
+        ui.add_sink(Arc::new(JsonSink::create(path)?));
+    }
+    if let Some(path) = &args.output_log {
+        ui.add_sink(Arc::new(LogSink::create(path)?));
+    }
+
+    Ok(ui)
+}
+
+/// Run `f` with SIGINT and SIGTERM cancelling the runs of `canceller`
+///
+/// Returns the exit code of `f`, or the one derived from the signal if
+/// interrupted.
+fn with_signals(canceller: Canceller, f: impl FnOnce() -> Result<i32>) -> Result<i32> {
+    let received = handle_signals(canceller)?;
+    let rc = f()?;
+
+    Ok(match received.load(Ordering::SeqCst) {
+        0 => rc,
+        sig => signal_rc(sig),
+    })
+}
+
+/// Run `f` with SIGINT and SIGTERM cancelling the runs of `vmtest`
+///
+/// Returns the exit code of `f`, or the one derived from the signal if
+/// interrupted.
+fn interruptible(args: &Args, vmtest: Vmtest, f: impl FnOnce(Ui) -> i32) -> Result<i32> {
+    with_signals(vmtest.canceller(), || Ok(f(ui(args, vmtest)?)))
+}
+
+/// Boot a session and serve it on its control socket until stopped
+///
+/// Progress is printed to stdout. Nothing is printed once the session is
+/// ready, as the detached parent stops reading at that point.
+///
+/// SIGINT and SIGTERM shut the session down.
+fn session_serve(args: &Args, name: &str) -> Result<i32> {
+    let vmtest = single_target_config(args)?;
+    with_signals(vmtest.canceller(), || serve_vmtest(vmtest, name))
+}
+
+/// Boot the single target of `vmtest` and serve it as the session `name`
+fn serve_vmtest(vmtest: Vmtest, name: &str) -> Result<i32> {
+    let (sender, receiver) = channel::<Output>();
+    let printer = thread::spawn(move || {
+        for msg in receiver {
+            match msg {
+                Output::BootStart => println!("=> Booting"),
+                Output::SetupStart => println!("=> Setting up VM"),
+                Output::BootEnd(Err(e)) | Output::SetupEnd(Err(e)) => println!("{:?}", e),
+                _ => (),
+            }
+        }
+    });
+
+    let session = match vmtest.session(0, sender) {
+        Some(s) => s,
+        None => {
+            let _ = printer.join();
+            return Ok(EX_UNAVAILABLE);
+        }
+    };
+
+    let path = session::socket_path(name)?;
+    println!("{SESSION_READY}: {}", path.display());
+    io::stdout().flush().context("Failed to flush stdout")?;
+
+    session::serve(session, &path)?;
+
+    Ok(0)
+}
+
+/// Start a session in the background
+///
+/// This re-executes ourselves in the foreground as a detached process and
+/// relays its progress until the session is ready. The detached process logs
+/// to [`session::log_path`].
+fn session_start(name: &str) -> Result<i32> {
+    let exe = env::current_exe().context("Failed to locate vmtest binary")?;
+    let log_path = session::log_path(name)?;
+    let log = File::create(&log_path)
+        .with_context(|| format!("Failed to create {}", log_path.display()))?;
+    let mut child = Command::new(exe)
+        .args(env::args_os().skip(1))
+        .arg("--foreground")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(log)
+        // Don't take the session down with our process group
+        .process_group(0)
+        .spawn()
+        .context("Failed to spawn session")?;
+
+    // unwrap() should never fail b/c we are capturing stdout
+    let stdout = child.stdout.take().unwrap();
+    for line in BufReader::new(stdout).lines() {
+        let line = line.context("Failed to read session output")?;
+        println!("{line}");
+        if line.starts_with(SESSION_READY) {
+            return Ok(0);
+        }
+    }
+
+    let status = child.wait().context("Failed to wait on session")?;
+    eprintln!("Session '{name}' failed to start: {status}");
+    match fs::read_to_string(&log_path) {
+        Ok(log) => eprint!("{log}"),
+        Err(e) => eprintln!("Failed to read {}: {e}", log_path.display()),
+    }
+
+    Ok(EX_UNAVAILABLE)
+}
+
+/// Run a command in a running session
+fn session_exec(name: &str, command: &[String]) -> Result<i32> {
+    let client = Client::connect(&session::socket_path(name)?)?;
+    match client.exec(&command.join(" "), |line| println!("{line}")) {
+        // Exit codes only have 8 bits
+        Ok(rc) => match u8::try_from(rc) {
+            Ok(rc) => Ok(rc.into()),
+            Err(_) => {
+                eprintln!("Command exited with out of range exit code: {rc}");
+                Ok(EX_UNAVAILABLE)
+            }
+        },
+        Err(e) => {
+            eprintln!("{:?}", e);
+            Ok(EX_UNAVAILABLE)
+        }
+    }
+}
+
+/// Shut down a running session
+fn session_stop(name: &str) -> Result<i32> {
+    Client::connect(&session::socket_path(name)?)?.stop()?;
+
+    Ok(0)
+}
+
+/// Remove leftovers of crashed runs
+fn gc() -> Result<i32> {
+    for path in run_dir::gc()? {
+        println!("Removed {}", path.display());
+    }
+
+    Ok(0)
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
     init_logging().context("Failed to initialize logging")?;
-    let vmtest = config(&args)?;
-    let ui = Ui::new(vmtest);
-    let rc = ui.run(show_cmd(&args));
+    let rc = match &args.subcommand {
+        Some(Subcommands::Session { action }) => match action {
+            SessionAction::Start {
+                name,
+                foreground: true,
+            } => session_serve(&args, name)?,
+            SessionAction::Start { name, .. } => session_start(name)?,
+            SessionAction::Exec { name, command } => session_exec(name, command)?,
+            SessionAction::Stop { name } => session_stop(name)?,
+        },
+        Some(Subcommands::Shell { .. }) => {
+            interruptible(&args, single_target_config(&args)?, |ui| ui.shell())?
+        }
+        Some(Subcommands::Gc) => gc()?,
+        None => {
+            let mut vmtest = config(&args)?;
+            match args.bench {
+                Some(runs) => interruptible(&args, vmtest, |ui| ui.bench(runs as usize))?,
+                None => {
+                    vmtest.set_shell_on_failure(args.shell_on_failure);
+                    interruptible(&args, vmtest, |ui| ui.run(show_cmd(&args)))?
+                }
+            }
+        }
+    };
 
     exit(rc);
 }
@@ -241,6 +544,80 @@ mod tests {
         assert_eq!(vmtest.targets()[0].name, "test2");
     }
 
+    #[test]
+    fn test_session_subcommand() {
+        let tmp_dir = test_config().expect("Failed to create config");
+        let config_path = tmp_dir.path().join("vmtest.toml");
+
+        let args = Args::parse_from([
+            "cliname",
+            "-c",
+            config_path.to_str().expect("Failed to create config path"),
+            "-f",
+            "test2",
+            "session",
+            "start",
+            "--name",
+            "mysession",
+        ]);
+        assert!(matches!(
+            args.subcommand,
+            Some(Subcommands::Session {
+                action: SessionAction::Start {
+                    foreground: false,
+                    ..
+                }
+            })
+        ));
+        let vmtest = config(&args).expect("Failed to parse config");
+        assert_eq!(vmtest.targets().len(), 1);
+    }
+
+    // Sessions in one-liner mode do not need a command up front
+    #[test]
+    fn test_session_subcommand_with_kernel() {
+        let args = Args::parse_from(["cliname", "-k", "mykernel", "session", "start"]);
+        assert!(args.command.is_empty());
+        let vmtest = config(&args).expect("Failed to parse config");
+        assert_eq!(vmtest.targets().len(), 1);
+    }
+
+    #[test]
+    fn test_shell_selects_target_by_name() {
+        let tmp_dir = test_config().expect("Failed to create config");
+        let config_path = tmp_dir.path().join("vmtest.toml");
+
+        let args = Args::parse_from([
+            "cliname",
+            "-c",
+            config_path.to_str().expect("Failed to create config path"),
+            "shell",
+            "test1",
+        ]);
+        let vmtest = single_target_config(&args).expect("Failed to parse config");
+        assert_eq!(vmtest.targets()[0].name, "test1");
+
+        // Without a name, both targets are selected which is ambiguous
+        let args = Args::parse_from([
+            "cliname",
+            "-c",
+            config_path.to_str().expect("Failed to create config path"),
+            "shell",
+        ]);
+        assert!(single_target_config(&args).is_err());
+    }
+
+    #[test]
+    fn test_bench() {
+        let args = Args::parse_from(["cliname", "-k", "mykernel", "--bench", "5"]);
+        assert_eq!(args.bench, Some(5));
+        // No command is needed as none is run
+        let vmtest = config(&args).expect("Failed to parse config");
+        assert_eq!(vmtest.targets()[0].command, "true");
+
+        assert!(Args::try_parse_from(["cliname", "-k", "mykernel", "--bench", "0"]).is_err());
+    }
+
     // Test that when using the kernel argument, the filter is not applied.
     #[test]
     fn test_config_with_kernel_ignore_filter() {
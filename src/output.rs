@@ -1,4 +1,15 @@
-use anyhow::Result;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Error, Result};
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::result::Timings;
 
 /// This enum encapsulates real time updates about the VM.
 ///
@@ -30,4 +41,497 @@ pub enum Output {
     Command(String),
     /// Command finished with provided exit code
     CommandEnd(Result<i64>),
+
+    /// How long each stage took
+    ///
+    /// Sent once the VM is torn down, so it may follow a failure.
+    Timings(Timings),
+}
+
+/// Copy `e` along with its chain of causes
+///
+/// The copy formats the same but cannot be downcast.
+fn clone_error(e: &Error) -> Error {
+    let mut chain = e.chain().rev();
+    // The chain always contains at least `e` itself
+    let root = anyhow!(chain.next().unwrap().to_string());
+    chain.fold(root, |err, cause| err.context(cause.to_string()))
+}
+
+fn clone_result<T: Copy>(r: &Result<T>) -> Result<T> {
+    r.as_ref().map(|v| *v).map_err(clone_error)
+}
+
+impl Clone for Output {
+    fn clone(&self) -> Self {
+        match self {
+            Output::BootStart => Output::BootStart,
+            Output::Boot(s) => Output::Boot(s.clone()),
+            Output::BootEnd(r) => Output::BootEnd(clone_result(r)),
+            Output::SetupStart => Output::SetupStart,
+            Output::Setup(s) => Output::Setup(s.clone()),
+            Output::SetupEnd(r) => Output::SetupEnd(clone_result(r)),
+            Output::CommandStart => Output::CommandStart,
+            Output::Command(s) => Output::Command(s.clone()),
+            Output::CommandEnd(r) => Output::CommandEnd(clone_result(r)),
+            Output::Timings(t) => Output::Timings(*t),
+        }
+    }
+}
+
+/// Destination for real time updates about runs
+///
+/// A sink is shared by all threads of a run, so updates may arrive from
+/// several threads. A run is over once every reference to its sink has been
+/// dropped.
+pub trait OutputSink: Send + Sync {
+    /// Handle an update
+    fn send(&self, output: Output);
+
+    /// Called before the target named `name` starts running
+    ///
+    /// Useful for sinks that outlive a single run. Does nothing by default.
+    fn target(&self, _name: &str) {}
+
+    /// Block until every update sent so far has been handled
+    ///
+    /// Called before the run takes over the terminal, for example to attach
+    /// a debug shell. Sinks rendering to the terminal must be done with it
+    /// once this returns. Does nothing by default.
+    fn flush(&self) {}
+}
+
+/// Shared handle to an [`OutputSink`]
+pub type Sink = Arc<dyn OutputSink>;
+
+/// Conversion into a [`Sink`]
+///
+/// Lets APIs accept a plain channel, any [`OutputSink`] or a [`Sink`].
+pub trait IntoSink {
+    /// Perform the conversion
+    fn into_sink(self) -> Sink;
+}
+
+impl IntoSink for Sink {
+    fn into_sink(self) -> Sink {
+        self
+    }
+}
+
+impl<T: OutputSink + 'static> IntoSink for T {
+    fn into_sink(self) -> Sink {
+        Arc::new(self)
+    }
+}
+
+impl IntoSink for Sender<Output> {
+    fn into_sink(self) -> Sink {
+        Arc::new(ChannelSink::new(self))
+    }
+}
+
+/// Sends updates to a channel
+///
+/// The receiver sees a hang up once the run is over.
+pub struct ChannelSink {
+    sender: Mutex<Sender<Output>>,
+}
+
+impl ChannelSink {
+    /// Construct a sink sending to `sender`
+    pub fn new(sender: Sender<Output>) -> Self {
+        Self {
+            sender: Mutex::new(sender),
+        }
+    }
+}
+
+impl OutputSink for ChannelSink {
+    fn send(&self, output: Output) {
+        // The receiver going away is not our problem
+        let _ = self.sender.lock().unwrap().send(output);
+    }
+}
+
+/// Sends updates to several sinks
+pub struct Tee {
+    sinks: Vec<Sink>,
+}
+
+impl Tee {
+    /// Construct a sink sending to each of `sinks`, in order
+    ///
+    /// All but the last sink get copies of errors that cannot be downcast.
+    pub fn new(sinks: Vec<Sink>) -> Self {
+        Self { sinks }
+    }
+}
+
+impl OutputSink for Tee {
+    fn send(&self, output: Output) {
+        if let Some((last, rest)) = self.sinks.split_last() {
+            for sink in rest {
+                sink.send(output.clone());
+            }
+            last.send(output);
+        }
+    }
+
+    fn target(&self, name: &str) {
+        for sink in &self.sinks {
+            sink.target(name);
+        }
+    }
+
+    fn flush(&self) {
+        for sink in &self.sinks {
+            sink.flush();
+        }
+    }
+}
+
+/// Writes updates to a writer, one line per update
+///
+/// Shared by the file backed sinks.
+struct LineWriter<W: Write> {
+    writer: W,
+    /// Name of the running target, if any
+    target: Option<String>,
+}
+
+impl<W: Write> LineWriter<W> {
+    fn write_line(&mut self, line: &str) {
+        let res = writeln!(self.writer, "{line}").and_then(|_| self.writer.flush());
+        if let Err(e) = res {
+            warn!("Failed to record output: {}", e);
+        }
+    }
+}
+
+/// Writes updates as JSON lines
+///
+/// Each update is written as an object on its own line. The `event` field
+/// holds the variant in snake case, alongside a `target` field naming the
+/// target if known. Output lines are in a `line` field. `*_end` events have
+/// an `error` field that is `null` on success, and `command_end` has an
+/// `exit_code` field. The `timings` event has a field in seconds for each
+/// stage, which is `null` if the stage was not timed.
+pub struct JsonSink<W: Write + Send> {
+    inner: Mutex<LineWriter<W>>,
+}
+
+impl<W: Write + Send> JsonSink<W> {
+    /// Construct a sink writing to `writer`
+    pub fn new(writer: W) -> Self {
+        Self {
+            inner: Mutex::new(LineWriter {
+                writer,
+                target: None,
+            }),
+        }
+    }
+}
+
+impl JsonSink<BufWriter<File>> {
+    /// Construct a sink writing to a new file at `path`
+    pub fn create(path: &Path) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+}
+
+/// Render `r` as the JSON `error` field of an `*_end` event
+fn json_error<T>(r: &Result<T>) -> Value {
+    match r {
+        Ok(_) => Value::Null,
+        // Alternate formatting includes the causes on a single line
+        Err(e) => Value::String(format!("{:#}", e)),
+    }
+}
+
+fn json_event(output: &Output) -> Value {
+    match output {
+        Output::BootStart => json!({ "event": "boot_start" }),
+        Output::Boot(s) => json!({ "event": "boot", "line": s }),
+        Output::BootEnd(r) => json!({ "event": "boot_end", "error": json_error(r) }),
+        Output::SetupStart => json!({ "event": "setup_start" }),
+        Output::Setup(s) => json!({ "event": "setup", "line": s }),
+        Output::SetupEnd(r) => json!({ "event": "setup_end", "error": json_error(r) }),
+        Output::CommandStart => json!({ "event": "command_start" }),
+        Output::Command(s) => json!({ "event": "command", "line": s }),
+        Output::CommandEnd(r) => json!({
+            "event": "command_end",
+            "exit_code": r.as_ref().ok(),
+            "error": json_error(r),
+        }),
+        Output::Timings(t) => {
+            let secs = |d: Option<Duration>| d.map(|d| d.as_secs_f64());
+            json!({
+                "event": "timings",
+                "spawn": secs(t.spawn),
+                "boot": secs(t.boot),
+                "setup": secs(t.setup),
+                "command": secs(t.command),
+            })
+        }
+    }
+}
+
+impl<W: Write + Send> OutputSink for JsonSink<W> {
+    fn send(&self, output: Output) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut event = json_event(&output);
+        if let Some(target) = &inner.target {
+            event["target"] = Value::String(target.clone());
+        }
+        inner.write_line(&event.to_string());
+    }
+
+    fn target(&self, name: &str) {
+        self.inner.lock().unwrap().target = Some(name.to_string());
+    }
+}
+
+/// Writes updates as a plain text log
+///
+/// The log is laid out like the console UI, but nothing is ever collapsed.
+pub struct LogSink<W: Write + Send> {
+    inner: Mutex<LineWriter<W>>,
+}
+
+impl<W: Write + Send> LogSink<W> {
+    /// Construct a sink writing to `writer`
+    pub fn new(writer: W) -> Self {
+        Self {
+            inner: Mutex::new(LineWriter {
+                writer,
+                target: None,
+            }),
+        }
+    }
+}
+
+impl LogSink<BufWriter<File>> {
+    /// Construct a sink writing to a new file at `path`
+    pub fn create(path: &Path) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write + Send> OutputSink for LogSink<W> {
+    fn send(&self, output: Output) {
+        let mut inner = self.inner.lock().unwrap();
+        let (line, err) = match &output {
+            Output::BootStart => ("===> Booting".to_string(), None),
+            Output::SetupStart => ("===> Setting up VM".to_string(), None),
+            Output::CommandStart => ("===> Running command".to_string(), None),
+            Output::Boot(s) | Output::Setup(s) | Output::Command(s) => (s.clone(), None),
+            Output::BootEnd(r) | Output::SetupEnd(r) => match r {
+                Ok(_) => return,
+                Err(e) => (String::new(), Some(e)),
+            },
+            Output::CommandEnd(r) => match r {
+                Ok(rc) => (format!("Command exited with code: {rc}"), None),
+                Err(e) => (String::new(), Some(e)),
+            },
+            Output::Timings(t) if t.is_empty() => return,
+            Output::Timings(t) => (format!("Timings: {t}"), None),
+        };
+        match err {
+            // NB: use debug formatting to get full trace
+            Some(e) => inner.write_line(&format!("{:?}", e)),
+            None => inner.write_line(&line),
+        }
+    }
+
+    fn target(&self, name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.target = Some(name.to_string());
+        inner.write_line(&format!("=> {name}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    /// Writer whose output can be inspected while a sink owns it
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn run(sink: &dyn OutputSink) {
+        sink.target("test");
+        sink.send(Output::BootStart);
+        sink.send(Output::Boot("booting".into()));
+        sink.send(Output::BootEnd(Ok(())));
+        sink.send(Output::CommandStart);
+        sink.send(Output::Command("hello".into()));
+        sink.send(Output::CommandEnd(
+            Err(anyhow!("root cause")).context("Failed to run command"),
+        ));
+    }
+
+    #[test]
+    fn test_clone_error() {
+        let e = Err::<(), _>(anyhow!("root cause"))
+            .context("middle")
+            .context("top")
+            .unwrap_err();
+        let copy = clone_error(&e);
+        let causes = |e: &Error| e.chain().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(causes(&copy), causes(&e));
+        assert_eq!(format!("{:#}", copy), "top: middle: root cause");
+    }
+
+    #[test]
+    fn test_json_sink() {
+        let buf = Buffer::default();
+        run(&JsonSink::new(buf.clone()));
+
+        let events: Vec<Value> = buf
+            .contents()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(events.len(), 6);
+        assert_eq!(
+            events[1],
+            json!({ "event": "boot", "line": "booting", "target": "test" })
+        );
+        assert_eq!(events[2]["error"], Value::Null);
+        assert_eq!(
+            events[5],
+            json!({
+                "event": "command_end",
+                "exit_code": null,
+                "error": "Failed to run command: root cause",
+                "target": "test",
+            })
+        );
+    }
+
+    #[test]
+    fn test_json_sink_timings() {
+        let buf = Buffer::default();
+        let sink = JsonSink::new(buf.clone());
+        sink.send(Output::Timings(Timings {
+            spawn: Some(Duration::from_millis(500)),
+            ..Default::default()
+        }));
+
+        let event: Value = serde_json::from_str(buf.contents().trim_end()).unwrap();
+        assert_eq!(
+            event,
+            json!({
+                "event": "timings",
+                "spawn": 0.5,
+                "boot": null,
+                "setup": null,
+                "command": null,
+            })
+        );
+    }
+
+    #[test]
+    fn test_log_sink() {
+        let buf = Buffer::default();
+        run(&LogSink::new(buf.clone()));
+
+        let contents = buf.contents();
+        let lines: Vec<_> = contents.lines().collect();
+        // A backtrace may follow, depending on the environment
+        assert_eq!(
+            lines[..9],
+            [
+                "=> test",
+                "===> Booting",
+                "booting",
+                "===> Running command",
+                "hello",
+                "Failed to run command",
+                "",
+                "Caused by:",
+                "    root cause",
+            ]
+        );
+
+        let buf = Buffer::default();
+        let sink = LogSink::new(buf.clone());
+        sink.send(Output::Timings(Timings::default()));
+        sink.send(Output::Timings(Timings {
+            boot: Some(Duration::from_millis(1500)),
+            ..Default::default()
+        }));
+        assert_eq!(buf.contents(), "Timings: boot 1.50s\n");
+    }
+
+    #[test]
+    fn test_tee() {
+        let (sender, receiver) = channel();
+        let buf = Buffer::default();
+        let tee = Tee::new(vec![
+            sender.into_sink(),
+            JsonSink::new(buf.clone()).into_sink(),
+        ]);
+        run(&tee);
+        drop(tee);
+
+        let events: Vec<_> = receiver.iter().collect();
+        assert_eq!(events.len(), 6);
+        assert!(
+            matches!(&events[5], Output::CommandEnd(Err(e)) if e.to_string() == "Failed to run command")
+        );
+        assert_eq!(buf.contents().lines().count(), 6);
+    }
+
+    /// Records the updates it handled when flushed
+    #[derive(Default)]
+    struct Flushes {
+        pending: Mutex<usize>,
+        flushed: Mutex<Vec<usize>>,
+    }
+
+    impl OutputSink for Flushes {
+        fn send(&self, _output: Output) {
+            *self.pending.lock().unwrap() += 1;
+        }
+
+        fn flush(&self) {
+            let n = std::mem::take(&mut *self.pending.lock().unwrap());
+            self.flushed.lock().unwrap().push(n);
+        }
+    }
+
+    #[test]
+    fn test_tee_flush() {
+        let flushes = Arc::new(Flushes::default());
+        let (sender, _receiver) = channel();
+        let tee = Tee::new(vec![flushes.clone(), sender.into_sink()]);
+        run(&tee);
+        tee.flush();
+        tee.send(Output::BootStart);
+        tee.flush();
+
+        assert_eq!(*flushes.flushed.lock().unwrap(), [6, 1]);
+    }
 }
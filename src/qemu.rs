@@ -6,62 +6,662 @@ use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::hash::Hash;
 use std::hash::Hasher;
-use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::io::{self, BufRead, BufReader, ErrorKind, IsTerminal, Read, Write};
 use std::marker::Send;
+use std::mem;
+use std::net::Shutdown;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::RawFd;
 use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, Stdio};
-use std::sync::mpsc::Sender;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
+use std::thread::JoinHandle;
 use std::time;
 use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context, Result};
+use console::Term;
 use log::{debug, log_enabled, warn, Level};
 use qapi::{qga, qmp, Qmp};
-use rand::Rng;
+use scopeguard::ScopeGuard;
 use serde_derive::Serialize;
 use tempfile::{Builder, NamedTempFile};
 use tinytemplate::{format_unescaped, TinyTemplate};
 
-use crate::output::Output;
+use crate::cache::{cache_dir, CacheKey};
+use crate::cloud_init::{gen_seed, Seed};
+use crate::control;
+use crate::elf;
+use crate::output::{Output, Sink};
 use crate::qga::QgaWrapper;
-use crate::{Mount, Target, VMConfig};
+use crate::result::Timings;
+use crate::rootfs;
+use crate::run_dir::{init_prefix, RunDir};
+use crate::tpm::Swtpm;
+use crate::{
+    Controller, ControllerKind, Disk, DiskBus, DiskMedia, MemoryBackend, MemoryBackendKind, Mount,
+    OverlayUpper, Rootfs, RootfsOverlay, Target, TpmModel, VMConfig,
+};
 
 const INIT_TEMPLATE: &str = include_str!("init/init.sh.template");
 const COMMAND_TEMPLATE: &str = include_str!("init/command.template");
+const SHELL_TEMPLATE: &str = include_str!("init/shell.template");
+const CTL_TEMPLATE: &str = include_str!("init/ctl.template");
 // Needs to be `/dev/root` for kernel to "find" the 9pfs as rootfs
 const ROOTFS_9P_FS_MOUNT_TAG: &str = "/dev/root";
 const SHARED_9P_FS_MOUNT_TAG: &str = "vmtest-shared";
+// Shares init with guests that boot from a rootfs image
+const BOOT_9P_FS_MOUNT_TAG: &str = "vmtest-boot";
+// Where guests that boot from a rootfs image mount BOOT_9P_FS_MOUNT_TAG
+const BOOT_9P_FS_MOUNT_PATH: &str = "/mnt";
 const COMMAND_OUTPUT_PORT_NAME: &str = "org.qemu.virtio_serial.0";
+const SHELL_PORT_NAME: &str = "org.vmtest.shell.0";
+const CONTROL_PORT_NAME: &str = "org.vmtest.control.0";
 const MAGIC_INTERACTIVE_COMMAND: &str = "-";
 
 const SHARED_9P_FS_MOUNT_PATH: &str = "/mnt/vmtest";
+// Prefix of the marker written to a virtio-serial port once all output is in
+const END_OF_OUTPUT_PREFIX: &str = "vmtest-end-of-output-";
+// How long to wait for QEMU to quit after a cancellation before killing it
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
 const MOUNT_OPTS_9P_FS: &str = "trans=virtio,cache=mmap,msize=1048576";
-const OVMF_PATHS: &[&str] = &[
+
+/// A UEFI firmware build
+struct Firmware {
+    /// Firmware code image
+    code: &'static str,
+    /// Template for the firmware's variable store.
+    ///
+    /// `None` if variables are built into `code`.
+    vars: Option<&'static str>,
+}
+
+/// Shorthand to declare a firmware with a separate variable store
+const fn split(code: &'static str, vars: &'static str) -> Firmware {
+    Firmware {
+        code,
+        vars: Some(vars),
+    }
+}
+
+/// Known x86_64 firmware locations, in order of preference
+const X86_64_FIRMWARE: &[Firmware] = &[
+    // Fedora
+    split(
+        "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+        "/usr/share/edk2/ovmf/OVMF_VARS.fd",
+    ),
+    // Ubuntu
+    split(
+        "/usr/share/OVMF/OVMF_CODE_4M.fd",
+        "/usr/share/OVMF/OVMF_VARS_4M.fd",
+    ),
+    split(
+        "/usr/share/OVMF/OVMF_CODE.fd",
+        "/usr/share/OVMF/OVMF_VARS.fd",
+    ),
+    Firmware {
+        code: "/usr/share/ovmf/OVMF.fd",
+        vars: None,
+    },
+    // Arch linux
+    split(
+        "/usr/share/edk2/x64/OVMF_CODE.4m.fd",
+        "/usr/share/edk2/x64/OVMF_VARS.4m.fd",
+    ),
+    Firmware {
+        code: "/usr/share/edk2/x64/OVMF.4m.fd",
+        vars: None,
+    },
+    split(
+        "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+        "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd",
+    ),
+];
+
+/// Known x86_64 Secure Boot firmware locations, in order of preference
+///
+/// The variable stores have the Microsoft keys enrolled, so Secure Boot is
+/// enabled from the first boot.
+const X86_64_SECURE_BOOT_FIRMWARE: &[Firmware] = &[
+    // Fedora
+    split(
+        "/usr/share/edk2/ovmf/OVMF_CODE.secboot.fd",
+        "/usr/share/edk2/ovmf/OVMF_VARS.secboot.fd",
+    ),
+    // Ubuntu
+    split(
+        "/usr/share/OVMF/OVMF_CODE_4M.secboot.fd",
+        "/usr/share/OVMF/OVMF_VARS_4M.ms.fd",
+    ),
+    split(
+        "/usr/share/OVMF/OVMF_CODE.secboot.fd",
+        "/usr/share/OVMF/OVMF_VARS.ms.fd",
+    ),
+];
+
+/// Known aarch64 firmware locations, in order of preference
+const AARCH64_FIRMWARE: &[Firmware] = &[
     // Fedora
-    "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+    split(
+        "/usr/share/edk2/aarch64/QEMU_EFI-pflash.raw",
+        "/usr/share/edk2/aarch64/vars-template-pflash.raw",
+    ),
     // Ubuntu
-    "/usr/share/OVMF/OVMF_CODE.fd",
-    "/usr/share/ovmf/OVMF.fd",
+    split(
+        "/usr/share/AAVMF/AAVMF_CODE.fd",
+        "/usr/share/AAVMF/AAVMF_VARS.fd",
+    ),
     // Arch linux
-    // TODO(dxu): parameterize by architecture
-    "/usr/share/edk2/x64/OVMF.4m.fd",
-    "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+    split(
+        "/usr/share/edk2/aarch64/QEMU_CODE.fd",
+        "/usr/share/edk2/aarch64/QEMU_VARS.fd",
+    ),
+];
+
+/// Known riscv64 firmware locations, in order of preference
+const RISCV64_FIRMWARE: &[Firmware] = &[
+    // Fedora
+    split(
+        "/usr/share/edk2/riscv/RISCV_VIRT_CODE.fd",
+        "/usr/share/edk2/riscv/RISCV_VIRT_VARS.fd",
+    ),
+    // Ubuntu
+    split(
+        "/usr/share/qemu-efi-riscv64/RISCV_VIRT_CODE.fd",
+        "/usr/share/qemu-efi-riscv64/RISCV_VIRT_VARS.fd",
+    ),
+    // Arch linux
+    split(
+        "/usr/share/edk2/riscv64/RISCV_VIRT_CODE.fd",
+        "/usr/share/edk2/riscv64/RISCV_VIRT_VARS.fd",
+    ),
+];
+
+/// Known i386 firmware locations, in order of preference
+const I386_FIRMWARE: &[Firmware] = &[
+    // Fedora
+    split(
+        "/usr/share/edk2/ovmf-ia32/OVMF_CODE.fd",
+        "/usr/share/edk2/ovmf-ia32/OVMF_VARS.fd",
+    ),
+    // Arch linux
+    split(
+        "/usr/share/edk2/ia32/OVMF_CODE.4m.fd",
+        "/usr/share/edk2/ia32/OVMF_VARS.4m.fd",
+    ),
+];
+
+/// Known armv7 firmware locations, in order of preference
+const ARMV7_FIRMWARE: &[Firmware] = &[
+    // Fedora
+    split(
+        "/usr/share/edk2/arm/QEMU_EFI-pflash.raw",
+        "/usr/share/edk2/arm/vars-template-pflash.raw",
+    ),
+    // Ubuntu
+    split(
+        "/usr/share/AAVMF/AAVMF32_CODE.fd",
+        "/usr/share/AAVMF/AAVMF32_VARS.fd",
+    ),
+    // Arch linux
+    split(
+        "/usr/share/edk2/arm/QEMU_CODE.fd",
+        "/usr/share/edk2/arm/QEMU_VARS.fd",
+    ),
+];
+
+/// Known loongarch64 firmware locations, in order of preference
+const LOONGARCH64_FIRMWARE: &[Firmware] = &[
+    // Fedora
+    Firmware {
+        code: "/usr/share/edk2/loongarch64/QEMU_EFI.fd",
+        vars: None,
+    },
+    // Ubuntu
+    Firmware {
+        code: "/usr/share/qemu-efi-loongarch64/QEMU_EFI.fd",
+        vars: None,
+    },
+];
+
+/// How virtio devices are attached to the VM
+///
+/// There is no virtio-mmio transport as every supported machine type has a
+/// PCI bus. Machines without one, like `microvm`, are not supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum VirtioTransport {
+    #[default]
+    Pci,
+    /// s390x channel I/O
+    Ccw,
+}
+
+impl VirtioTransport {
+    /// Name of the `device` virtio device on this transport
+    ///
+    /// For example `virtio-blk` becomes `virtio-blk-pci`.
+    fn device(self, device: &str) -> String {
+        match self {
+            Self::Pci => format!("{device}-pci"),
+            Self::Ccw => format!("{device}-ccw"),
+        }
+    }
+}
+
+/// How to run guests of a single architecture
+struct ArchProfile {
+    /// Name of the architecture in the target's `arch`
+    arch: &'static str,
+    /// Name of the architecture in [`ARCH`] on hosts of this architecture
+    host_arch: &'static str,
+    /// Suffix of the `qemu-system-*` binary
+    qemu: &'static str,
+    /// Machine type, if QEMU's default does not work
+    machine: Option<&'static str>,
+    /// CPU model to emulate without KVM
+    cpu: &'static str,
+    /// Serial console, as passed to the kernel's `console=`
+    console: &'static str,
+    /// Kernel argument that enables early console output, if any
+    earlycon: Option<&'static str>,
+    /// How virtio devices are attached
+    transport: VirtioTransport,
+    /// Known UEFI firmware locations, in order of preference
+    firmware: &'static [Firmware],
+    /// ELF `e_machine` of guest userspace binaries
+    elf_machine: u16,
+    /// Whether guest userspace binaries are big endian
+    elf_big_endian: bool,
+    /// Name of the architecture in OCI image platforms
+    oci_arch: &'static str,
+}
+
+/// Supported guest architectures
+const ARCH_PROFILES: &[ArchProfile] = &[
+    ArchProfile {
+        arch: "x86_64",
+        host_arch: "x86_64",
+        qemu: "x86_64",
+        machine: None,
+        cpu: "qemu64",
+        // The first serial port, as vmtest has always passed it
+        console: "0,115200",
+        earlycon: Some("earlyprintk=serial,0,115200"),
+        transport: VirtioTransport::Pci,
+        firmware: X86_64_FIRMWARE,
+        elf_machine: 62,
+        elf_big_endian: false,
+        oci_arch: "amd64",
+    },
+    ArchProfile {
+        arch: "i386",
+        host_arch: "x86",
+        qemu: "i386",
+        machine: None,
+        cpu: "qemu32",
+        console: "0,115200",
+        earlycon: Some("earlyprintk=serial,0,115200"),
+        transport: VirtioTransport::Pci,
+        firmware: I386_FIRMWARE,
+        elf_machine: 3,
+        elf_big_endian: false,
+        oci_arch: "386",
+    },
+    ArchProfile {
+        arch: "aarch64",
+        host_arch: "aarch64",
+        qemu: "aarch64",
+        machine: Some("virt,gic-version=3"),
+        cpu: "max",
+        console: "ttyAMA0,115200",
+        earlycon: Some("earlycon=pl011,0x9000000"),
+        transport: VirtioTransport::Pci,
+        firmware: AARCH64_FIRMWARE,
+        elf_machine: 183,
+        elf_big_endian: false,
+        oci_arch: "arm64",
+    },
+    ArchProfile {
+        arch: "armv7",
+        host_arch: "arm",
+        qemu: "arm",
+        machine: Some("virt"),
+        cpu: "cortex-a15",
+        console: "ttyAMA0,115200",
+        earlycon: Some("earlycon=pl011,0x9000000"),
+        transport: VirtioTransport::Pci,
+        firmware: ARMV7_FIRMWARE,
+        elf_machine: 40,
+        elf_big_endian: false,
+        oci_arch: "arm",
+    },
+    ArchProfile {
+        arch: "riscv64",
+        host_arch: "riscv64",
+        qemu: "riscv64",
+        machine: Some("virt"),
+        cpu: "rv64,sscofpmf=true",
+        console: "ttyS0,115200",
+        earlycon: Some("earlycon=sbi"),
+        transport: VirtioTransport::Pci,
+        firmware: RISCV64_FIRMWARE,
+        elf_machine: 243,
+        elf_big_endian: false,
+        oci_arch: "riscv64",
+    },
+    ArchProfile {
+        arch: "ppc64le",
+        host_arch: "powerpc64",
+        qemu: "ppc64",
+        machine: Some("pseries"),
+        cpu: "power9",
+        // The sPAPR virtual terminal
+        console: "hvc0",
+        earlycon: None,
+        transport: VirtioTransport::Pci,
+        // SLOF is built into QEMU
+        firmware: &[],
+        elf_machine: 21,
+        elf_big_endian: false,
+        oci_arch: "ppc64le",
+    },
+    ArchProfile {
+        arch: "s390x",
+        host_arch: "s390x",
+        qemu: "s390x",
+        machine: Some("s390-ccw-virtio"),
+        cpu: "max",
+        // The SCLP console gets early output without an earlycon
+        console: "ttysclp0",
+        earlycon: None,
+        transport: VirtioTransport::Ccw,
+        // The s390-ccw BIOS is built into QEMU
+        firmware: &[],
+        elf_machine: 22,
+        elf_big_endian: true,
+        oci_arch: "s390x",
+    },
+    ArchProfile {
+        arch: "loongarch64",
+        host_arch: "loongarch64",
+        qemu: "loongarch64",
+        machine: Some("virt"),
+        cpu: "la464",
+        console: "ttyS0,115200",
+        earlycon: Some("earlycon=uart,mmio,0x1fe001e0"),
+        transport: VirtioTransport::Pci,
+        firmware: LOONGARCH64_FIRMWARE,
+        elf_machine: 258,
+        elf_big_endian: false,
+        oci_arch: "loong64",
+    },
 ];
 
+/// Look up the profile for `arch`
+///
+/// Host architecture names like `powerpc64` are accepted as well, so that
+/// the default `arch` works on every supported host.
+fn arch_profile(arch: &str) -> Option<&'static ArchProfile> {
+    ARCH_PROFILES
+        .iter()
+        .find(|p| p.arch == arch)
+        .or_else(|| ARCH_PROFILES.iter().find(|p| p.host_arch == arch))
+}
+
+/// Names of the supported guest architectures
+pub(crate) fn supported_arches() -> impl Iterator<Item = &'static str> {
+    ARCH_PROFILES.iter().map(|p| p.arch)
+}
+
+/// Whether `arch` names a supported guest architecture
+pub(crate) fn arch_supported(arch: &str) -> bool {
+    arch_profile(arch).is_some()
+}
+
+/// Name of `arch` in OCI image platforms
+pub(crate) fn oci_arch(arch: &str) -> Option<&'static str> {
+    arch_profile(arch).map(|p| p.oci_arch)
+}
+
+/// Whether guests of `arch` can boot UEFI firmware
+pub(crate) fn arch_supports_uefi(arch: &str) -> bool {
+    arch_profile(arch).is_some_and(|p| !p.firmware.is_empty())
+}
+
+/// Name of the architecture ELF binaries with `header` run on
+fn elf_arch(header: &elf::Header) -> Option<&'static str> {
+    ARCH_PROFILES
+        .iter()
+        .find(|p| p.elf_machine == header.machine && p.elf_big_endian == header.big_endian)
+        .map(|p| p.arch)
+}
+
+/// Check that the binaries the guest runs from `rootfs` are built for `profile`
+///
+/// Booting a rootfs of the wrong architecture otherwise only fails once
+/// the kernel cannot execute init, with an unhelpful panic.
+fn check_rootfs_arch(rootfs: &Path, profile: &ArchProfile) -> Result<()> {
+    let bash = rootfs::resolve(rootfs, Path::new("/bin/bash"))
+        .ok_or_else(|| anyhow!("rootfs {} has no /bin/bash", rootfs.display()))?;
+    // init looks up qemu-ga through the guest's $PATH
+    let qga = env::split_paths(&guest_path())
+        .find_map(|dir| rootfs::resolve(rootfs, &dir.join("qemu-ga")))
+        .ok_or_else(|| {
+            anyhow!(
+                "rootfs {} has no qemu-ga in $PATH. Install qemu-guest-agent into it.",
+                rootfs.display()
+            )
+        })?;
+
+    for binary in [bash, qga] {
+        // Wrapper scripts run whatever their interpreter runs
+        let header = match elf::read_header(&binary)? {
+            Some(h) => h,
+            None => continue,
+        };
+        if header.machine != profile.elf_machine || header.big_endian != profile.elf_big_endian {
+            bail!(
+                "{} in rootfs {} is built for {}, but the target arch is {}",
+                binary.strip_prefix(rootfs).unwrap_or(&binary).display(),
+                rootfs.display(),
+                elf_arch(&header).unwrap_or("an unsupported architecture"),
+                profile.arch,
+            );
+        }
+    }
+
+    Ok(())
+}
+
 /// A shorthand type representing a QMP stream over a Unix domain socket
 type QmpUnixStream = qapi::Stream<BufReader<UnixStream>, UnixStream>;
 
+/// What to do with the VM snapshot of a target
+///
+/// A snapshot consists of the VM state and a qcow2 overlay on top of the
+/// image holding the disk writes up to the point the state was saved.
+enum Snapshot {
+    /// No snapshot exists yet. Save one to `state` and `disk` after boot.
+    Save {
+        state: PathBuf,
+        disk: PathBuf,
+        /// Overlay the booting VM writes to, copied to `disk` when saving
+        overlay: NamedTempFile,
+    },
+    /// Resume from the snapshot instead of booting
+    Restore { state: PathBuf, disk: PathBuf },
+}
+
+/// Handles to a booted and set up VM
+///
+/// The QEMU process is killed when this is dropped, unless it has already
+/// been shut down through [`Qemu::stop`].
+pub struct VmHandle {
+    child: ScopeGuard<SharedChild, fn(SharedChild)>,
+    qga: QgaWrapper,
+    qmp: Qmp<QmpUnixStream>,
+}
+
+/// Cancels a run from another thread
+///
+/// Clones refer to the same run. Cancelling asks QEMU to quit over a
+/// dedicated QMP socket and kills it if it does not exit in time. The run
+/// then fails and cleans up after itself as it would after any other error.
+#[derive(Clone, Default)]
+pub struct Canceller {
+    inner: Arc<CancelState>,
+}
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    /// QMP socket and process of the running QEMU, if any
+    vm: Mutex<Option<(PathBuf, SharedChild)>>,
+}
+
+impl Canceller {
+    /// Cancel the run
+    ///
+    /// Blocks until QEMU has exited. Runs that have not booted QEMU yet
+    /// fail before doing so.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let vm = self.inner.vm.lock().unwrap().clone();
+        if let Some((sock, child)) = vm {
+            quit_qemu(&sock, &child);
+        }
+    }
+
+    /// Cancel the run without waiting for QEMU to exit
+    ///
+    /// The run counts as cancelled right away. QEMU is shut down as in
+    /// [`Canceller::cancel`] on a separate thread.
+    pub fn cancel_in_background(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let canceller = self.clone();
+        thread::spawn(move || canceller.cancel());
+    }
+
+    /// Whether or not the run was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Register the QEMU process a cancellation should shut down
+    ///
+    /// Returns an error if the run was cancelled already.
+    fn attach(&self, qmp_sock: &Path, child: &SharedChild) -> Result<()> {
+        *self.inner.vm.lock().unwrap() = Some((qmp_sock.to_owned(), child.clone()));
+        // Checked after registering so a concurrent cancel() cannot miss QEMU
+        if self.is_cancelled() {
+            bail!("Run was cancelled");
+        }
+
+        Ok(())
+    }
+
+    /// Forget about the QEMU process once it has exited
+    fn detach(&self) {
+        *self.inner.vm.lock().unwrap() = None;
+    }
+}
+
+/// The QEMU process, shared between a run and its [`Canceller`]
+///
+/// Waiting polls instead of blocking, so that the canceller can kill the
+/// process meanwhile. The process is only ever signalled through its
+/// [`Child`], which knows once it has been reaped, so a reused pid is never
+/// hit.
+#[derive(Clone)]
+pub(crate) struct SharedChild(Arc<Mutex<Child>>);
+
+impl SharedChild {
+    fn new(child: Child) -> Self {
+        Self(Arc::new(Mutex::new(child)))
+    }
+
+    /// Lock the process, e.g. to take its output pipes
+    fn lock(&self) -> MutexGuard<'_, Child> {
+        self.0.lock().unwrap()
+    }
+
+    /// Returns how the process exited, if it has
+    pub fn try_wait(&self) -> io::Result<Option<ExitStatus>> {
+        self.lock().try_wait()
+    }
+
+    /// Wait for the process to exit
+    fn wait(&self) -> io::Result<ExitStatus> {
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(status);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Kill the process, unless it has exited already
+    fn kill(&self) -> io::Result<()> {
+        let mut child = self.lock();
+        match child.try_wait()? {
+            Some(_) => Ok(()),
+            None => child.kill(),
+        }
+    }
+}
+
+/// Ask the QEMU process `child` to quit through the QMP socket at `qmp_sock`
+///
+/// QEMU is killed if it is still around after [`CANCEL_TIMEOUT`].
+fn quit_qemu(qmp_sock: &Path, child: &SharedChild) {
+    let quit = || -> Result<()> {
+        let stream = UnixStream::connect(qmp_sock).context("Failed to connect QMP")?;
+        stream.set_read_timeout(Some(CANCEL_TIMEOUT))?;
+        let mut qmp = Qmp::from_stream(&stream);
+        qmp.handshake().context("QMP handshake failed")?;
+        qmp.execute(&qmp::quit {})?;
+        Ok(())
+    };
+    if let Err(e) = quit() {
+        debug!("Failed to quit QEMU over QMP: {:?}", e);
+    }
+
+    let running = || matches!(child.try_wait(), Ok(None));
+    let end = time::Instant::now() + CANCEL_TIMEOUT;
+    while running() && time::Instant::now() < end {
+        thread::sleep(Duration::from_millis(50));
+    }
+    if running() {
+        warn!("QEMU did not quit in time, killing it");
+        if let Err(e) = child.kill() {
+            warn!("Failed to kill QEMU: {e}");
+        }
+    }
+}
+
 /// Represents a single QEMU instance
 pub struct Qemu {
     process: Command,
     qga_sock: PathBuf,
     qmp_sock: PathBuf,
+    /// QMP socket reserved for shutting QEMU down on cancellation
+    cancel_sock: PathBuf,
     command: String,
+    /// User to run `command` as
+    user: Option<String>,
     /// virtio-serial socket that streams command output
     command_sock: PathBuf,
+    /// virtio-serial socket that interactive shells are attached to, if a
+    /// shell may be attached
+    shell_sock: Option<PathBuf>,
+    /// virtio-serial socket that `vmtest-ctl` requests arrive on, if enabled
+    control_sock: Option<PathBuf>,
     host_shared: PathBuf,
     /// Path to somewhere on the host that the guest should use as rootfs
     rootfs: PathBuf,
@@ -71,9 +671,37 @@ pub struct Qemu {
     ///
     /// This object will be cleared as part of the `run` invocation.
     init: Option<NamedTempFile>,
-    updates: Sender<Output>,
+    /// Private directory holding the sockets above and all other per-run
+    /// files, removed last on drop
+    run_dir: Option<RunDir>,
+    /// Scratch disk backing the rootfs overlay, if any
+    overlay_disk: Option<NamedTempFile>,
+    /// cloud-init seed attached to the VM, if any
+    cloud_init_seed: Option<Seed>,
+    /// Per-run copy of the UEFI variable store, if any
+    uefi_vars: Option<NamedTempFile>,
+    /// Software TPM attached to the VM, if any
+    swtpm: Option<Swtpm>,
+    /// How the command finds the additional disks attached to the VM
+    disks: Vec<DiskContext>,
+    /// Backing files of the scratch disks in `disks`
+    scratch_disks: Vec<NamedTempFile>,
+    updates: Sink,
     /// Whether or not we are running an image target
     image: bool,
+    /// VM snapshot to save or restore, if enabled
+    snapshot: Option<Snapshot>,
+    /// Whether QEMU's console output should be withheld from `updates`
+    ///
+    /// Used to keep console output from garbling an attached shell.
+    console_muted: Arc<AtomicBool>,
+    /// Thread forwarding QEMU's console output, joined once QEMU is gone so
+    /// that no output trails the run
+    console: Option<JoinHandle<()>>,
+    /// Handle other threads cancel this run through
+    canceller: Canceller,
+    /// How long each stage took, not yet reported to `updates`
+    timings: Timings,
 }
 
 /// Used by templating engine to render command
@@ -87,6 +715,47 @@ struct CommandContext<'data> {
     command: &'data str,
     /// virtio-serial output port name
     command_output_port_name: &'data str,
+    /// User to run the command as, if not root
+    user: Option<&'data str>,
+    /// Additional disks to expose to the command
+    disks: Vec<DiskContext>,
+    /// virtio-serial control port name
+    control_port_name: &'data str,
+    /// The `vmtest-ctl` helper to install, if the VM can be controlled
+    control_script: Option<String>,
+}
+
+/// Used by templating engine to render the `vmtest-ctl` helper
+#[derive(Serialize)]
+struct CtlContext<'data> {
+    /// virtio-serial control port name
+    control_port_name: &'data str,
+}
+
+/// Used by templating engine to render how the command locates a disk
+#[derive(Serialize, Clone, Debug, PartialEq)]
+struct DiskContext {
+    /// Identifier the guest reports for the disk
+    id: String,
+    /// Environment variable to export the guest device path in
+    env: String,
+}
+
+/// Used by templating engine to render the interactive shell script
+#[derive(Serialize)]
+struct ShellContext<'data> {
+    /// True if shell should change working directory before starting.
+    should_cd: bool,
+    /// Path to directory shared between guest/host, quoted for the shell
+    host_shared: String,
+    /// virtio-serial port name the shell is attached to
+    shell_port_name: &'data str,
+    /// $TERM the shell should use, quoted for the shell
+    term: String,
+    /// Terminal height
+    rows: u16,
+    /// Terminal width
+    cols: u16,
 }
 
 /// Used by templating engine to render init.sh
@@ -94,8 +763,20 @@ struct CommandContext<'data> {
 struct InitContext {
     /// $PATH the guest should use
     path: String,
+    /// True if the rootfs should be overlaid with a writable layer
+    overlay: bool,
+    /// True if the writable layer lives on the overlay disk
+    overlay_disk: bool,
+    /// Serial number of the overlay disk
+    overlay_disk_serial: &'static str,
+    /// Size limit of the writable layer when backed by tmpfs
+    overlay_size: Option<String>,
+    /// Where the share holding init is mounted, if init is not in the rootfs
+    boot_mount: Option<&'static str>,
 }
 
+const OVERLAY_DISK_SERIAL: &str = "vmtest-overlay";
+const DEFAULT_OVERLAY_DISK_SIZE: &str = "8G";
 const QEMU_DEFAULT_ARGS: &[&str] = &["-nodefaults", "-display", "none"];
 
 /// Gets instance of template engine
@@ -111,20 +792,25 @@ fn get_templates() -> TinyTemplate<'static> {
     // error compiling the template. Any errors are trivial bugs.
     tt.add_template("cmd", COMMAND_TEMPLATE).unwrap();
     tt.add_template("init", INIT_TEMPLATE).unwrap();
+    tt.add_template("shell", SHELL_TEMPLATE).unwrap();
+    tt.add_template("ctl", CTL_TEMPLATE).unwrap();
 
     tt
 }
 
-/// Whether or not the host supports KVM
-fn host_supports_kvm(arch: &str) -> bool {
-    arch == ARCH && Path::new("/dev/kvm").exists()
+/// Render the `vmtest-ctl` helper
+fn ctl_script() -> String {
+    let context = CtlContext {
+        control_port_name: CONTROL_PORT_NAME,
+    };
+
+    // Ignore errors cuz only trivial bugs are possible
+    get_templates().render("ctl", &context).unwrap()
 }
 
-// Generate a path to a randomly named socket
-fn gen_sock(prefix: &str) -> PathBuf {
-    let id = rand::thread_rng().gen_range(100_000..1_000_000);
-    let sock = format!("/tmp/{prefix}-{id}.sock");
-    PathBuf::from(sock)
+/// Whether or not the host supports KVM
+fn host_supports_kvm(arch: &str) -> bool {
+    arch_profile(arch).is_some_and(|p| p.host_arch == ARCH) && Path::new("/dev/kvm").exists()
 }
 
 // Given a guest temp dir and a host init path, generate the path to the init file
@@ -155,37 +841,66 @@ fn guest_init_path(guest_temp_dir: PathBuf, host_init_path: PathBuf) -> Result<P
     Ok(guest_init_path)
 }
 
-/// Generates init.sh that guest will use as pid 1
-fn init_script() -> String {
-    let path = match env::var("PATH") {
+/// `$PATH` inside the guest, which is inherited from the host
+fn guest_path() -> String {
+    match env::var("PATH") {
         Ok(p) => p,
         Err(_) => "/bin:/sbin:/usr/bin:/usr/sbin".to_string(),
-    };
+    }
+}
 
+/// Generates init.sh that guest will use as pid 1
+///
+/// `boot_share` specifies whether init runs from the share at
+/// [`BOOT_9P_FS_MOUNT_PATH`] rather than from the rootfs.
+fn init_script(overlay: Option<&RootfsOverlay>, boot_share: bool) -> String {
     // Ignore errors cuz only trivial bugs are possible
-    let context = InitContext { path };
+    let context = InitContext {
+        path: guest_path(),
+        overlay: overlay.is_some(),
+        overlay_disk: overlay.is_some_and(|o| o.upper == OverlayUpper::Disk),
+        overlay_disk_serial: OVERLAY_DISK_SERIAL,
+        overlay_size: overlay.and_then(|o| o.size.clone()),
+        boot_mount: boot_share.then_some(BOOT_9P_FS_MOUNT_PATH),
+    };
     get_templates().render("init", &context).unwrap()
 }
 
+/// Generate a directory with the init script for booting from a rootfs image
+///
+/// The directory is created inside `run_dir` and shared with the guest as
+/// [`BOOT_9P_FS_MOUNT_TAG`]. Returns the directory and the path to the init
+/// script inside the guest.
+fn gen_boot_dir(run_dir: &Path, overlay: Option<&RootfsOverlay>) -> Result<(PathBuf, PathBuf)> {
+    let dir = run_dir.join("boot");
+    fs::create_dir(&dir).context("Failed to create boot directory")?;
+    let init = dir.join("init.sh");
+    fs::write(&init, init_script(overlay, true)).context("Failed to write init")?;
+    fs::set_permissions(&init, fs::Permissions::from_mode(0o755))
+        .context("Failed to set executable bits on init")?;
+
+    Ok((dir, Path::new(BOOT_9P_FS_MOUNT_PATH).join("init.sh")))
+}
+
 // Given a rootfs, generate a tempfile with the init script inside.
 // Returns the tempfile and the path to the init script inside the guest.
 // When rootfs is /, both the tempfile filename and guest init path are equal.
 // When rootfs is different than /, the guest init path is the same as the
 // tempfile filename, but with the rootfs path stripped off.
-fn gen_init(rootfs: &Path) -> Result<(NamedTempFile, PathBuf)> {
+fn gen_init(rootfs: &Path, overlay: Option<&RootfsOverlay>) -> Result<(NamedTempFile, PathBuf)> {
     let guest_temp_dir = std::env::temp_dir();
     let mut host_dest_dir = rootfs.to_path_buf().into_os_string();
     host_dest_dir.push(&guest_temp_dir);
 
     let mut host_init = Builder::new()
-        .prefix("vmtest-init")
+        .prefix(&init_prefix())
         .suffix(".sh")
         .rand_bytes(5)
         .tempfile_in::<OsString>(host_dest_dir)
         .context("Failed to create tempfile")?;
 
     host_init
-        .write_all(init_script().as_bytes())
+        .write_all(init_script(overlay, false).as_bytes())
         .context("Failed to write init to tmpfs")?;
 
     // Set write bits on script
@@ -209,22 +924,383 @@ fn gen_init(rootfs: &Path) -> Result<(NamedTempFile, PathBuf)> {
     Ok((host_init, guest_init))
 }
 
+/// Parse a size like `512M` or `8G` into bytes
+///
+/// Accepts the same binary suffixes as QEMU and the kernel.
+pub(crate) fn parse_size(size: &str) -> Result<u64> {
+    let (digits, shift) = match size.chars().last() {
+        Some('K' | 'k') => (&size[..size.len() - 1], 10),
+        Some('M' | 'm') => (&size[..size.len() - 1], 20),
+        Some('G' | 'g') => (&size[..size.len() - 1], 30),
+        Some('T' | 't') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+    let value: u64 = digits
+        .parse()
+        .with_context(|| format!("Invalid size '{size}'"))?;
+
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("Size '{size}' is too large"))
+}
+
+/// Parse a guest memory size the way the `-m` flag does
+///
+/// Unlike [`parse_size`], sizes without a suffix are in MiB.
+pub(crate) fn parse_memory(size: &str) -> Result<u64> {
+    if size.ends_with(|c: char| c.is_ascii_digit()) {
+        parse_size(&format!("{size}M"))
+    } else {
+        parse_size(size)
+    }
+}
+
+/// Parse a CPU list like `0-3,6` into inclusive ranges
+pub(crate) fn parse_cpu_list(cpus: &str) -> Result<Vec<(u16, u16)>> {
+    cpus.split(',')
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let start: u16 = start
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid CPU list '{cpus}'"))?;
+            let end: u16 = end
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid CPU list '{cpus}'"))?;
+            if start > end {
+                bail!("Invalid CPU range '{range}' in CPU list '{cpus}'");
+            }
+
+            Ok((start, end))
+        })
+        .collect()
+}
+
+/// Create a sparse scratch disk in `dir` to back the rootfs overlay
+fn gen_overlay_disk(dir: &Path, size: Option<&str>) -> Result<NamedTempFile> {
+    let size = parse_size(size.unwrap_or(DEFAULT_OVERLAY_DISK_SIZE))?;
+    let disk = Builder::new()
+        .prefix("vmtest-overlay")
+        .suffix(".img")
+        .rand_bytes(5)
+        .tempfile_in(dir)
+        .context("Failed to create overlay disk")?;
+    disk.as_file()
+        .set_len(size)
+        .context("Failed to size overlay disk")?;
+
+    Ok(disk)
+}
+
+/// Generate arguments for attaching a TPM backed by the swtpm at `sock`
+fn tpm_args(arch: &str, model: Option<TpmModel>, sock: &Path) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+
+    args.push("-chardev".into());
+    let mut arg = OsString::new();
+    arg.push("socket,id=chrtpm,path=");
+    arg.push(escape_opt(sock));
+    args.push(arg);
+
+    args.push("-tpmdev".into());
+    args.push("emulator,id=tpm0,chardev=chrtpm".into());
+
+    // Config validation ensures CRB is only used on x86_64
+    let device = match (arch, model.unwrap_or(TpmModel::Tis)) {
+        ("aarch64", _) => "tpm-tis-device",
+        (_, TpmModel::Tis) => "tpm-tis",
+        (_, TpmModel::Crb) => "tpm-crb",
+    };
+    args.push("-device".into());
+    args.push(format!("{device},tpmdev=tpm0").into());
+
+    args
+}
+
+/// Generate arguments for attaching a cloud-init NoCloud seed
+///
+/// cloud-init finds the seed by its volume label.
+fn cloud_init_args(seed: &Path, transport: VirtioTransport) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+
+    args.push("-drive".into());
+    let mut arg = OsString::new();
+    arg.push("file=");
+    arg.push(escape_opt(seed));
+    arg.push(",format=raw,if=none,id=cidata,readonly=on");
+    args.push(arg);
+
+    args.push("-device".into());
+    args.push(format!("{},drive=cidata", transport.device("virtio-blk")).into());
+
+    args
+}
+
+/// Generate arguments for attaching the rootfs overlay disk
+///
+/// The guest finds the disk by its serial number.
+fn overlay_disk_args(file: &Path, transport: VirtioTransport) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+
+    args.push("-drive".into());
+    let mut arg = OsString::new();
+    arg.push("file=");
+    arg.push(escape_opt(file));
+    arg.push(",format=raw,if=none,id=overlay");
+    args.push(arg);
+
+    args.push("-device".into());
+    args.push(
+        format!(
+            "{},drive=overlay,serial={OVERLAY_DISK_SERIAL}",
+            transport.device("virtio-blk")
+        )
+        .into(),
+    );
+
+    args
+}
+
+/// Create a sparse scratch disk in `dir` for `disk` using qemu-img
+fn gen_scratch_disk(dir: &Path, disk: &Disk, size: &str) -> Result<NamedTempFile> {
+    let file = Builder::new()
+        .prefix(&format!("vmtest-disk-{}", disk.name))
+        .suffix(".img")
+        .rand_bytes(5)
+        .tempfile_in(dir)
+        .context("Failed to create scratch disk")?;
+
+    let out = Command::new("qemu-img")
+        .arg("create")
+        .arg("-q")
+        .arg("-f")
+        .arg(disk.format.as_deref().unwrap_or("raw"))
+        .arg(file.path())
+        .arg(size)
+        .output()
+        .context("Failed to run qemu-img. Is it installed?")?;
+    if !out.status.success() {
+        bail!(
+            "Failed to create scratch disk '{}': {}",
+            disk.name,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    Ok(file)
+}
+
+/// Builds the QEMU arguments for additional storage controllers and disks
+///
+/// Every disk is attached with an identifier the guest can find it by
+/// regardless of probe order. Controllers must be added before the disks
+/// attached to them.
+#[derive(Default)]
+struct StorageBuilder {
+    /// How virtio controllers and disks are attached
+    transport: VirtioTransport,
+    args: Vec<OsString>,
+    /// Kind of each named controller and the number of disks attached to it
+    controllers: HashMap<String, (ControllerKind, u32)>,
+    /// Whether the shared SCSI controller for disks without one was added
+    default_scsi: bool,
+    /// How the guest finds each disk
+    disks: Vec<DiskContext>,
+}
+
+impl StorageBuilder {
+    fn new(transport: VirtioTransport) -> Self {
+        Self {
+            transport,
+            ..Default::default()
+        }
+    }
+
+    /// Add a storage controller
+    fn controller(&mut self, controller: &Controller) {
+        let id = format!("vmctrl-{}", controller.name);
+        let mut device = match controller.kind {
+            ControllerKind::VirtioScsi => {
+                format!("{},id={id}", self.transport.device("virtio-scsi"))
+            }
+            ControllerKind::Nvme => format!("nvme,id={id},serial={}", controller.name),
+        };
+        if let Some(queues) = controller.queues {
+            match controller.kind {
+                ControllerKind::VirtioScsi => device.push_str(&format!(",num_queues={queues}")),
+                ControllerKind::Nvme => device.push_str(&format!(",max_ioqpairs={queues}")),
+            }
+        }
+        self.args.push("-device".into());
+        self.args.push(device.into());
+
+        self.controllers
+            .insert(controller.name.clone(), (controller.kind, 0));
+    }
+
+    /// Id of the controller named `name`, and the number of disks on it
+    /// including the one being added
+    fn attach(&mut self, name: &str) -> (String, u32) {
+        // Config validation ensures disks only reference existing controllers
+        let (_, count) = self
+            .controllers
+            .get_mut(name)
+            .expect("controller must exist");
+        *count += 1;
+
+        (format!("vmctrl-{name}"), *count)
+    }
+
+    /// Add a disk
+    fn disk(&mut self, disk: &Disk) {
+        // Config validation and `Qemu::new()` ensure every disk has a path
+        let path = disk.path.as_deref().expect("disk path must be set");
+        let id = format!("vmdisk-{}", disk.name);
+
+        let mut drive = OsString::from("file=");
+        drive.push(escape_opt(path));
+        drive.push(format!(",if=none,id={id}"));
+        if let Some(format) = &disk.format {
+            drive.push(format!(",format={format}"));
+        }
+        if disk.media == DiskMedia::Cdrom {
+            drive.push(",media=cdrom");
+        }
+        if disk.readonly || disk.media == DiskMedia::Cdrom {
+            drive.push(",readonly=on");
+        }
+        self.args.push("-drive".into());
+        self.args.push(drive);
+
+        // Each bus reports the serial number to the guest differently, see
+        // command.template
+        let (mut device, guest_id) = match disk.bus() {
+            DiskBus::VirtioBlk => {
+                let mut device = format!(
+                    "{},drive={id},serial={}",
+                    self.transport.device("virtio-blk"),
+                    disk.name
+                );
+                if let Some(queues) = disk.queues {
+                    device.push_str(&format!(",num-queues={queues}"));
+                }
+                (device, disk.name.clone())
+            }
+            DiskBus::VirtioScsi => {
+                let bus = match &disk.controller {
+                    Some(name) => self.attach(name).0,
+                    None => {
+                        if !self.default_scsi {
+                            self.args.push("-device".into());
+                            let device = self.transport.device("virtio-scsi");
+                            self.args.push(format!("{device},id=scsi0").into());
+                            self.default_scsi = true;
+                        }
+                        "scsi0".into()
+                    }
+                };
+                let model = match disk.media {
+                    DiskMedia::Disk => "scsi-hd",
+                    DiskMedia::Cdrom => "scsi-cd",
+                };
+                let device = format!("{model},bus={bus}.0,drive={id},serial={}", disk.name);
+                (device, disk.name.clone())
+            }
+            DiskBus::Nvme => {
+                // Disks without a controller get one of their own
+                let (bus, serial, nsid) = match &disk.controller {
+                    Some(name) => {
+                        let (bus, nsid) = self.attach(name);
+                        (bus, name.clone(), nsid)
+                    }
+                    None => {
+                        let bus = format!("vmnvme-{}", disk.name);
+                        let mut controller = format!("nvme,id={bus},serial={}", disk.name);
+                        if let Some(queues) = disk.queues {
+                            controller.push_str(&format!(",max_ioqpairs={queues}"));
+                        }
+                        self.args.push("-device".into());
+                        self.args.push(controller.into());
+                        (bus, disk.name.clone(), 1)
+                    }
+                };
+                let device = format!("nvme-ns,bus={bus},drive={id},nsid={nsid}");
+                (device, format!("{serial}:{nsid}"))
+            }
+        };
+
+        if let Some(size) = disk.logical_block_size {
+            device.push_str(&format!(",logical_block_size={size}"));
+        }
+        if let Some(size) = disk.physical_block_size {
+            device.push_str(&format!(",physical_block_size={size}"));
+        }
+        self.args.push("-device".into());
+        self.args.push(device.into());
+
+        self.disks.push(DiskContext {
+            id: guest_id,
+            env: disk.env_var(),
+        });
+    }
+
+    /// Returns the QEMU arguments and how the guest finds each disk
+    fn build(self) -> (Vec<OsString>, Vec<DiskContext>) {
+        (self.args, self.disks)
+    }
+}
+
+/// Escape `path` for use as a value in a QEMU option list
+///
+/// Options are separated by commas, so QEMU expects literal commas to be
+/// doubled.
+fn escape_opt(path: &Path) -> OsString {
+    let mut escaped = Vec::new();
+    for &b in path.as_os_str().as_bytes() {
+        if b == b',' {
+            escaped.push(b',');
+        }
+        escaped.push(b);
+    }
+    OsString::from_vec(escaped)
+}
+
 /// Generate arguments for inserting a file as a drive into the guest
-fn drive_args(file: &Path, index: u32) -> Vec<OsString> {
+///
+/// `snapshot` specifies whether guest writes should be discarded rather
+/// than written back to `file`. `format` is the format of `file`, which is
+/// probed if not provided.
+fn drive_args(
+    file: &Path,
+    index: u32,
+    snapshot: bool,
+    format: Option<&str>,
+    transport: VirtioTransport,
+) -> Vec<OsString> {
     let mut args: Vec<OsString> = Vec::new();
     let disk_id = format!("disk{}", hash(file));
+    let mut drive = OsString::from("file=");
+    drive.push(escape_opt(file));
+    drive.push(format!(",index={index},media=disk,if=none,id={disk_id}"));
+    if snapshot {
+        drive.push(",snapshot=on");
+    }
+    if let Some(format) = format {
+        drive.push(format!(",format={format}"));
+    }
     args.push("-drive".into());
+    args.push(drive);
+    args.push("-device".into());
     args.push(
         format!(
-            "file={},index={},media=disk,if=none,id={}",
-            file.display(),
-            index,
-            disk_id
+            "{},drive={},bootindex={}",
+            transport.device("virtio-blk"),
+            disk_id,
+            index
         )
         .into(),
     );
-    args.push("-device".into());
-    args.push(format!("virtio-blk-pci,drive={},bootindex={}", disk_id, index).into());
 
     args
 }
@@ -237,7 +1313,7 @@ fn guest_agent_args(sock: &Path) -> Vec<OsString> {
 
     let mut arg = OsString::new();
     arg.push("socket,path=");
-    arg.push(sock);
+    arg.push(escape_opt(sock));
     arg.push(",server=on,wait=off,id=qga0");
     args.push(arg);
 
@@ -251,10 +1327,10 @@ fn guest_agent_args(sock: &Path) -> Vec<OsString> {
 }
 
 /// Generate arguments for full KVM virtualization if host supports it
-fn kvm_args<'a>(arch: &str, kvm_cpu_args: &'a str) -> Vec<&'a str> {
+fn kvm_args<'a>(profile: &ArchProfile, kvm_cpu_args: &'a str) -> Vec<&'a str> {
     let mut args = Vec::new();
 
-    if host_supports_kvm(arch) {
+    if host_supports_kvm(profile.arch) {
         args.push("-enable-kvm");
         args.push("-cpu");
         if kvm_cpu_args.is_empty() {
@@ -264,27 +1340,22 @@ fn kvm_args<'a>(arch: &str, kvm_cpu_args: &'a str) -> Vec<&'a str> {
         }
     } else {
         args.push("-cpu");
-        match arch {
-            "aarch64" | "s390x" => {
-                args.push("max");
-            }
-            "riscv64" => {
-                args.push("rv64,sscofpmf=true");
-            }
-            _ => {
-                args.push("qemu64");
-            }
-        }
+        args.push(profile.cpu);
     }
     args
 }
 
 /// Generate arguments for which qemu machine to use
-fn machine_args(arch: &str) -> Vec<&'static str> {
-    match arch {
-        "aarch64" => vec!["-machine", "virt,gic-version=3"],
-        "riscv64" => vec!["-machine", "virt"],
-        _ => vec![],
+fn machine_args(profile: &ArchProfile, secure_boot: bool) -> Vec<&'static str> {
+    // Secure Boot firmware keeps the variable store out of reach of the OS
+    // through SMM, which requires q35. Config validation ensures Secure Boot
+    // is only used on x86_64, which otherwise uses QEMU's default machine.
+    if secure_boot {
+        return vec!["-machine", "q35,smm=on"];
+    }
+    match profile.machine {
+        Some(machine) => vec!["-machine", machine],
+        None => vec![],
     }
 }
 
@@ -296,7 +1367,7 @@ fn machine_protocol_args(sock: &Path) -> Vec<OsString> {
 
     let mut arg = OsString::new();
     arg.push("unix:");
-    arg.push(sock);
+    arg.push(escape_opt(sock));
     arg.push(",server=on,wait=off");
     args.push(arg);
 
@@ -314,12 +1385,12 @@ fn plan9_fs_args(host_shared: &Path, id: &str, mount_tag: &str, ro: bool) -> Vec
 
     let mut arg = OsString::new();
     arg.push(format!("local,id={id},path="));
-    arg.push(if host_shared.as_os_str().is_empty() {
+    arg.push(escape_opt(if host_shared.as_os_str().is_empty() {
         // This case occurs when the config file path is just "vmtest.toml"
         Path::new(".")
     } else {
         host_shared
-    });
+    }));
     arg.push(format!(
         ",mount_tag={mount_tag},security_model=none,multidevs=remap"
     ));
@@ -331,44 +1402,142 @@ fn plan9_fs_args(host_shared: &Path, id: &str, mount_tag: &str, ro: bool) -> Vec
     args
 }
 
-fn uefi_firmware_args(bios: Option<&Path>) -> Vec<OsString> {
-    let mut args = Vec::new();
+/// Known firmware locations for `arch`, in order of preference
+fn firmware_candidates(arch: &str, secure_boot: bool) -> &'static [Firmware] {
+    match (arch, secure_boot) {
+        ("x86_64", false) => X86_64_FIRMWARE,
+        ("x86_64", true) => X86_64_SECURE_BOOT_FIRMWARE,
+        (_, false) => arch_profile(arch).map_or(&[], |p| p.firmware),
+        _ => &[],
+    }
+}
+
+/// Locate installed UEFI firmware for `arch`
+fn find_firmware(arch: &str, secure_boot: bool) -> Result<&'static Firmware> {
+    let firmware = firmware_candidates(arch, secure_boot)
+        .iter()
+        .find(|f| Path::new(f.code).exists() && f.vars.map_or(true, |v| Path::new(v).exists()))
+        .ok_or_else(|| {
+            anyhow!(
+                "Failed to locate {}UEFI firmware for {}. Install edk2 firmware or set 'bios'",
+                if secure_boot { "Secure Boot " } else { "" },
+                arch
+            )
+        })?;
+    debug!("Found UEFI firmware: {}", firmware.code);
+
+    Ok(firmware)
+}
+
+/// Make a copy of the variable store template at `template` in `dir` for a
+/// single run
+///
+/// Guest changes to UEFI variables are thrown away with the copy.
+fn gen_uefi_vars(dir: &Path, template: &Path) -> Result<NamedTempFile> {
+    let vars = Builder::new()
+        .prefix("vmtest-uefi-vars")
+        .suffix(".fd")
+        .rand_bytes(5)
+        .tempfile_in(dir)
+        .context("Failed to create UEFI variable store")?;
+    fs::copy(template, vars.path()).with_context(|| {
+        format!(
+            "Failed to copy UEFI variable store from {}",
+            template.display()
+        )
+    })?;
+
+    Ok(vars)
+}
+
+/// Generate arguments for booting firmware with a separate variable store
+fn pflash_args(code: &Path, vars: &Path, secure_boot: bool) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+
+    let mut arg = OsString::from("if=pflash,format=raw,unit=0,readonly=on,file=");
+    arg.push(escape_opt(code));
+    args.push("-drive".into());
+    args.push(arg);
+
+    let mut arg = OsString::from("if=pflash,format=raw,unit=1,file=");
+    arg.push(escape_opt(vars));
+    args.push("-drive".into());
+    args.push(arg);
 
-    args.push("-bios".into());
+    // Only allow SMM to write to the variable store. See `machine_args`.
+    if secure_boot {
+        args.push("-global".into());
+        args.push("driver=cfi.pflash01,property=secure,value=on".into());
+    }
 
+    args
+}
+
+/// Generate arguments for booting UEFI firmware
+///
+/// A user supplied `bios` is used as is. Otherwise installed firmware for
+/// `arch` is located. Returns the per-run copy of the variable store, made
+/// in `dir`, along with the arguments, if the firmware has one.
+fn uefi_firmware_args(
+    dir: &Path,
+    arch: &str,
+    bios: Option<&Path>,
+    secure_boot: bool,
+) -> Result<(Vec<OsString>, Option<NamedTempFile>)> {
     if let Some(path) = bios {
-        args.push(path.into());
-        return args;
+        return Ok((vec!["-bios".into(), path.into()], None));
     }
 
-    let mut chosen = OVMF_PATHS[0];
-    for path in OVMF_PATHS {
-        if Path::new(path).exists() {
-            debug!("Found OVMF firmware: {}", path);
-            chosen = path;
-            break;
-        }
-    }
-    args.push(chosen.into());
+    let firmware = find_firmware(arch, secure_boot)?;
+    match firmware.vars {
+        Some(template) => {
+            let vars = gen_uefi_vars(dir, Path::new(template))?;
+            let args = pflash_args(Path::new(firmware.code), vars.path(), secure_boot);
+            Ok((args, Some(vars)))
+        }
+        None => Ok((vec!["-bios".into(), firmware.code.into()], None)),
+    }
+}
+
+/// Filesystem a kernel target boots from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KernelRoot<'a> {
+    /// The rootfs directory, shared over 9p
+    Plan9,
+    /// A read-only rootfs image with this filesystem type, attached as the
+    /// first virtio disk. Init is shared separately over 9p.
+    Image(&'a str),
+}
+
+/// Generate arguments for attaching a read-only rootfs image
+fn rootfs_image_args(file: &Path, transport: VirtioTransport) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+
+    args.push("-drive".into());
+    let mut arg = OsString::new();
+    arg.push("file=");
+    arg.push(escape_opt(file));
+    arg.push(",format=raw,if=none,id=rootfs,readonly=on");
+    args.push(arg);
+
+    args.push("-device".into());
+    args.push(format!("{},drive=rootfs", transport.device("virtio-blk")).into());
 
     args
 }
 
-/// Generate which serial device to use based on the architecture used.
-fn console_device(arch: &str) -> String {
-    match arch {
-        "aarch64" => "ttyAMA0".into(),
-        _ => "0".into(),
-    }
-}
 /// Generate arguments for running a kernel with current userspace
 ///
 /// The basic idea is we'll map host root onto guest root. And then use
 /// the host's systemd as init but boot into `rescue.target` in the guest.
+///
+/// `ro_root` specifies whether the rootfs is mounted read-only.
 fn kernel_args(
     kernel: &Path,
-    arch: &str,
+    profile: &ArchProfile,
+    root: KernelRoot,
     init: &Path,
+    ro_root: bool,
     additional_kargs: Option<&str>,
 ) -> Vec<OsString> {
     let mut args = Vec::new();
@@ -383,21 +1552,35 @@ fn kernel_args(
     // The guest kernel command line args
     let mut cmdline: Vec<OsString> = Vec::new();
 
-    // Tell kernel the rootfs is 9p
-    cmdline.push("rootfstype=9p".into());
-    cmdline.push(format!("rootflags={}", MOUNT_OPTS_9P_FS).into());
-
-    // Mount rootfs readable/writable to make experience more smooth.
-    // Lots of tools expect to be able to write logs or change global
-    // state. The user can override this setting by supplying an
-    // additional `ro` kernel command line argument.
-    cmdline.push("rw".into());
+    match root {
+        KernelRoot::Plan9 => {
+            // Tell kernel the rootfs is 9p
+            cmdline.push("rootfstype=9p".into());
+            cmdline.push(format!("rootflags={}", MOUNT_OPTS_9P_FS).into());
+
+            // Mount rootfs readable/writable to make experience more smooth.
+            // Lots of tools expect to be able to write logs or change global
+            // state. The user can override this setting by supplying an
+            // additional `ro` kernel command line argument.
+            //
+            // With an overlay, init provides the writable view instead.
+            cmdline.push(if ro_root { "ro" } else { "rw" }.into());
+        }
+        KernelRoot::Image(fstype) => {
+            // The image is always the first disk
+            cmdline.push("root=/dev/vda".into());
+            cmdline.push(format!("rootfstype={fstype}").into());
+            cmdline.push("ro".into());
+        }
+    }
 
     // Show as much console output as we can bear
-    cmdline.push(format!("earlyprintk=serial,{},115200", console_device(arch)).into());
+    if let Some(earlycon) = profile.earlycon {
+        cmdline.push(earlycon.into());
+    }
     // Disable userspace writing ratelimits
     cmdline.push("printk.devkmsg=on".into());
-    cmdline.push(format!("console={},115200", console_device(arch)).into());
+    cmdline.push(format!("console={}", profile.console).into());
     cmdline.push("loglevel=7".into());
 
     // We are not using RAID and this will help speed up boot
@@ -407,9 +1590,15 @@ fn kernel_args(
     //
     // Note we are assuming the host's tmpfs is attached to rootfs. Which
     // seems like a reasonable assumption.
+    //
+    // A rootfs image does not contain init, so bash from the image mounts
+    // the share holding it first.
     let mut init_arg = OsString::new();
     init_arg.push("init=");
-    init_arg.push(init);
+    match root {
+        KernelRoot::Plan9 => init_arg.push(init),
+        KernelRoot::Image(_) => init_arg.push("/bin/bash"),
+    }
     cmdline.push(init_arg);
 
     // Trigger an immediate reboot on panic.
@@ -421,6 +1610,16 @@ fn kernel_args(
         cmdline.extend(kargs.split_whitespace().map(|karg| OsStr::new(karg).into()));
     }
 
+    // Everything after `--` is passed to init, so this has to come last
+    if let KernelRoot::Image(_) = root {
+        let mut boot = OsString::from(format!(
+            "-- -c \"mount -t 9p -o {MOUNT_OPTS_9P_FS} {BOOT_9P_FS_MOUNT_TAG} {BOOT_9P_FS_MOUNT_PATH} && exec "
+        ));
+        boot.push(init);
+        boot.push("\"");
+        cmdline.push(boot);
+    }
+
     // Set host side qemu kernel command line
     args.push("-append".into());
     args.push(cmdline.join(OsStr::new(" ")));
@@ -428,9 +1627,12 @@ fn kernel_args(
     args
 }
 
-/// Generate arguments for setting up virtio-serial device to stream
-/// command output from guest to host.
-fn virtio_serial_args(host_sock: &Path) -> Vec<OsString> {
+/// Generate arguments for setting up a virtio-serial device that connects
+/// a port in the guest to a unix domain socket on the host.
+///
+/// `id` is the ID of the chardev backing the port
+/// `port_name` is used inside guest to find the port
+fn virtio_serial_args(host_sock: &Path, id: &str, port_name: &str) -> Vec<OsString> {
     let mut args: Vec<OsString> = Vec::new();
 
     args.push("-device".into());
@@ -439,15 +1641,12 @@ fn virtio_serial_args(host_sock: &Path) -> Vec<OsString> {
     args.push("-chardev".into());
     let mut arg = OsString::new();
     arg.push("socket,path=");
-    arg.push(host_sock);
-    arg.push(",server=on,wait=off,id=cmdout");
+    arg.push(escape_opt(host_sock));
+    arg.push(format!(",server=on,wait=off,id={id}"));
     args.push(arg);
 
     args.push("-device".into());
-    arg = OsString::new();
-    arg.push("virtserialport,chardev=cmdout,name=");
-    arg.push(COMMAND_OUTPUT_PORT_NAME);
-    args.push(arg);
+    args.push(format!("virtserialport,chardev={id},name={port_name}").into());
 
     args
 }
@@ -459,14 +1658,347 @@ fn hash<T: Hash + ?Sized>(s: &T) -> u64 {
     h.finish()
 }
 
-fn vmconfig_args(vm: &VMConfig) -> Vec<OsString> {
-    let mut args = vec![
+/// Quote `s` for use in a shell command
+fn shell_quote(s: impl AsRef<OsStr>) -> String {
+    format!("'{}'", s.as_ref().to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// Compute the cache key of a VM snapshot
+///
+/// The key covers the QEMU invocation and the image, so any change to the
+/// target's config or image results in a different key. `ephemeral` are
+/// per-run paths (like sockets). They are replaced by a placeholder, so the
+/// rest of the arguments they appear in still count.
+fn snapshot_key(c: &Command, image: &Path, ephemeral: &[&Path]) -> Result<String> {
+    // Paths appear escaped in the arguments
+    let ephemeral = ephemeral
+        .iter()
+        .map(|p| escape_opt(p).to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let mut key = CacheKey::default();
+    key.add(c.get_program().as_bytes());
+    for arg in c.get_args() {
+        let mut arg = arg.to_string_lossy().into_owned();
+        for path in &ephemeral {
+            arg = arg.replace(path.as_str(), "<ephemeral>");
+        }
+        key.add(arg.as_bytes());
+    }
+    key.add(image.as_os_str().as_bytes());
+    key.add_metadata(image)?;
+
+    Ok(key.finish())
+}
+
+/// Returns the format of the disk image at `image`, as detected by `qemu-img`
+fn image_format(image: &Path) -> Result<String> {
+    let out = Command::new("qemu-img")
+        .args(["info", "--output=json"])
+        .arg(image)
+        .output()
+        .context("Failed to run qemu-img. Make sure it is installed")?;
+    if !out.status.success() {
+        bail!(
+            "qemu-img info failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    let info: serde_json::Value =
+        serde_json::from_slice(&out.stdout).context("Failed to parse qemu-img info output")?;
+
+    info["format"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("qemu-img info did not report a format"))
+}
+
+/// Create a qcow2 overlay on top of `image` that the VM writes to while a
+/// snapshot is taken
+fn snapshot_overlay(image: &Path, dir: &Path) -> Result<NamedTempFile> {
+    // The overlay refers to the image by path, so it must be absolute
+    let image = fs::canonicalize(image)
+        .with_context(|| format!("Failed to resolve {}", image.display()))?;
+    let format = image_format(&image)?;
+    let overlay = Builder::new()
+        .prefix("overlay.")
+        .suffix(".qcow2")
+        .tempfile_in(dir)
+        .context("Failed to create snapshot overlay")?;
+    let out = Command::new("qemu-img")
+        .args(["create", "-q", "-f", "qcow2", "-F", &format, "-b"])
+        .arg(&image)
+        .arg(overlay.path())
+        .output()
+        .context("Failed to run qemu-img. Make sure it is installed")?;
+    if !out.status.success() {
+        bail!(
+            "qemu-img create failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    Ok(overlay)
+}
+
+/// Locate the snapshot for the QEMU invocation in `c` and configure QEMU to
+/// resume from it if it exists.
+///
+/// Also attaches `image` as the root disk. Writes always go to an overlay,
+/// so the image keeps matching the snapshot.
+fn snapshot_args(
+    c: &mut Command,
+    image: &Path,
+    transport: VirtioTransport,
+    ephemeral: &[&Path],
+) -> Result<Snapshot> {
+    let key = snapshot_key(c, image, ephemeral)?;
+    let dir = cache_dir("snapshots")?;
+    let state = dir.join(format!("{key}.state"));
+    let disk = dir.join(format!("{key}.qcow2"));
+
+    if state.exists() && disk.exists() {
+        debug!("Restoring VM snapshot: {}", state.display());
+        // Writes of this run go to a temporary overlay QEMU discards
+        c.args(drive_args(&disk, 1, true, Some("qcow2"), transport));
+        c.arg("-incoming")
+            .arg(format!("exec:cat {}", shell_quote(&state)));
+        Ok(Snapshot::Restore { state, disk })
+    } else {
+        debug!("No VM snapshot found, will save to: {}", state.display());
+        // A state without a disk was left behind by a run that died while
+        // moving its snapshot into place. It would keep this run from saving.
+        let _ = fs::remove_file(&state);
+        let overlay = snapshot_overlay(image, &dir)?;
+        c.args(drive_args(
+            overlay.path(),
+            1,
+            false,
+            Some("qcow2"),
+            transport,
+        ));
+        Ok(Snapshot::Save {
+            state,
+            disk,
+            overlay,
+        })
+    }
+}
+
+/// Save the VM state to `state` and the disk to `disk`
+///
+/// `overlay` is the overlay the VM writes to. Once migration completes,
+/// QEMU has flushed it and stops writing to it until the guest is resumed,
+/// so it matches the saved state.
+///
+/// Migration pauses the guest, so the guest is resumed afterwards regardless
+/// of whether the snapshot was saved.
+fn save_snapshot(
+    qmp: &mut Qmp<QmpUnixStream>,
+    state: &Path,
+    disk: &Path,
+    overlay: &Path,
+) -> Result<()> {
+    // Write to unique temporary files first so that neither a failed save
+    // nor another run saving the same snapshot at the same time leaves
+    // behind a truncated snapshot that a later run would try to resume.
+    // They are removed on drop unless moved into place.
+    let dir = state.parent().unwrap_or(Path::new("."));
+    let tmp = NamedTempFile::new_in(dir).context("Failed to create snapshot file")?;
+    let disk_tmp = NamedTempFile::new_in(dir).context("Failed to create snapshot disk")?;
+    qmp.execute(&qmp::migrate {
+        uri: format!("exec:cat > {}", shell_quote(tmp.path())),
+        blk: None,
+        detach: None,
+        inc: None,
+        resume: None,
+    })
+    .context("Failed to start migration")?;
+
+    let result = loop {
+        let info = match qmp.execute(&qmp::query_migrate {}) {
+            Ok(i) => i,
+            Err(e) => break Err(anyhow!(e).context("Failed to query migration")),
+        };
+        match info.status {
+            Some(qmp::MigrationStatus::completed) => break Ok(()),
+            Some(qmp::MigrationStatus::failed) | Some(qmp::MigrationStatus::cancelled) => {
+                break Err(anyhow!(
+                    "Migration failed: {}",
+                    info.error_desc.unwrap_or_default()
+                ))
+            }
+            _ => thread::sleep(Duration::from_millis(50)),
+        }
+    };
+    // The disk must be copied before the guest writes to it again
+    let result = result.and_then(|_| {
+        fs::copy(overlay, disk_tmp.path()).context("Failed to copy snapshot disk")?;
+        Ok(())
+    });
+
+    qmp.execute(&qmp::cont {})
+        .context("Failed to resume VM after saving snapshot")?;
+    result?;
+
+    // Both files are complete now. The state goes first and never replaces
+    // another run's, so that the run whose state is in place also provides
+    // the disk. Runs saving the same snapshot concurrently back off.
+    tmp.persist_noclobber(state)
+        .map_err(|e| e.error)
+        .context("Failed to move snapshot into place")?;
+    if let Err(e) = disk_tmp.persist(disk) {
+        let _ = fs::remove_file(state);
+        return Err(e.error).context("Failed to move snapshot disk into place");
+    }
+
+    Ok(())
+}
+
+/// Generate the `-object` value for a `size` byte memory backend
+///
+/// `backend` of `None` is plain anonymous memory.
+fn memory_backend_object(backend: Option<&MemoryBackend>, id: &str, size: u64) -> OsString {
+    let mut obj = OsString::new();
+    obj.push(match backend.map(|b| b.kind).unwrap_or_default() {
+        MemoryBackendKind::Anonymous => "memory-backend-ram",
+        MemoryBackendKind::Memfd => "memory-backend-memfd",
+        MemoryBackendKind::Hugetlbfs => "memory-backend-file",
+    });
+    obj.push(format!(",id={id},size={size}"));
+
+    if let Some(backend) = backend {
+        if backend.kind == MemoryBackendKind::Hugetlbfs {
+            obj.push(",mem-path=");
+            obj.push(escape_opt(
+                &backend
+                    .path
+                    .clone()
+                    .unwrap_or_else(MemoryBackend::default_path),
+            ));
+        }
+        if backend.prealloc {
+            obj.push(",prealloc=on");
+        }
+        match backend.share {
+            Some(true) => obj.push(",share=on"),
+            Some(false) => obj.push(",share=off"),
+            None => (),
+        }
+    }
+
+    obj
+}
+
+/// Page size of the hugetlbfs mounted at `path`
+///
+/// `mounts` and `meminfo` are the contents of `/proc/mounts` and
+/// `/proc/meminfo`.
+fn hugetlbfs_page_size(mounts: &str, meminfo: &str, path: &Path) -> Result<u64> {
+    let options = mounts
+        .lines()
+        .map(|l| l.split_whitespace().collect::<Vec<_>>())
+        .find(|f| f.len() >= 4 && f[2] == "hugetlbfs" && Path::new(f[1]) == path)
+        .map(|f| f[3])
+        .ok_or_else(|| {
+            anyhow!(
+                "{} is not a hugetlbfs mount. Are hugepages set up on the host?",
+                path.display()
+            )
+        })?;
+
+    // Mounts without a pagesize option use the default hugepage size
+    if let Some(size) = options.split(',').find_map(|o| o.strip_prefix("pagesize=")) {
+        return parse_size(size);
+    }
+    meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("Hugepagesize:"))
+        .and_then(|v| v.trim().strip_suffix("kB"))
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(|kb| kb << 10)
+        .ok_or_else(|| anyhow!("Host does not support hugepages"))
+}
+
+/// Check the host has enough free hugepages at `path` for `size` bytes
+fn check_hugepages(path: &Path, size: u64) -> Result<()> {
+    let mounts = fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    let meminfo = fs::read_to_string("/proc/meminfo").context("Failed to read /proc/meminfo")?;
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let page_size = hugetlbfs_page_size(&mounts, &meminfo, &path)?;
+    let page_kb = page_size >> 10;
+    if size % page_size != 0 {
+        bail!("Guest memory must be a multiple of the {page_kb}kB hugepage size");
+    }
+
+    let free_path = format!("/sys/kernel/mm/hugepages/hugepages-{page_kb}kB/free_hugepages");
+    let free: u64 = fs::read_to_string(&free_path)
+        .with_context(|| format!("Failed to read {free_path}"))?
+        .trim()
+        .parse()
+        .with_context(|| format!("Failed to parse {free_path}"))?;
+    let needed = size / page_size;
+    if free < needed {
+        bail!(
+            "Host has {free} free {page_kb}kB hugepages but the VM needs {needed}. Reserve more through /proc/sys/vm/nr_hugepages"
+        );
+    }
+
+    Ok(())
+}
+
+/// Generate arguments for the CPUs and memory of the VM
+fn cpu_memory_args(vm: &VMConfig) -> Result<Vec<OsString>> {
+    let mut smp = vm.num_cpus.to_string();
+    if let Some(max_cpus) = vm.max_cpus {
+        smp.push_str(&format!(",maxcpus={max_cpus}"));
+    }
+    if let Some(t) = &vm.topology {
+        smp.push_str(&format!(
+            ",sockets={},cores={},threads={}",
+            t.sockets, t.cores, t.threads
+        ));
+    }
+    let mut args: Vec<OsString> = vec![
         "-smp".into(),
-        vm.num_cpus.to_string().into(),
+        smp.into(),
         "-m".into(),
         vm.memory.clone().into(),
     ];
 
+    let backend = vm.memory_backend.as_ref();
+    if backend.is_some() && vm.numa.is_empty() {
+        let size = parse_memory(&vm.memory)?;
+        args.push("-object".into());
+        args.push(memory_backend_object(backend, "vmtest-mem", size));
+        args.push("-machine".into());
+        args.push("memory-backend=vmtest-mem".into());
+    }
+
+    for (id, node) in vm.numa.iter().enumerate() {
+        let size = parse_memory(&node.memory)?;
+        args.push("-object".into());
+        args.push(memory_backend_object(
+            backend,
+            &format!("numa-mem{id}"),
+            size,
+        ));
+
+        let mut numa = format!("node,nodeid={id},memdev=numa-mem{id}");
+        if let Some(cpus) = &node.cpus {
+            for (start, end) in parse_cpu_list(cpus)? {
+                numa.push_str(&format!(",cpus={start}-{end}"));
+            }
+        }
+        args.push("-numa".into());
+        args.push(numa.into());
+    }
+
+    Ok(args)
+}
+
+fn vmconfig_args(vm: &VMConfig, transport: VirtioTransport) -> Result<Vec<OsString>> {
+    let mut args = cpu_memory_args(vm)?;
+
     for mount in vm.mounts.values() {
         let name = format!("mount{}", hash(&mount.host_path));
         args.append(&mut plan9_fs_args(
@@ -485,9 +2017,14 @@ fn vmconfig_args(vm: &VMConfig) -> Vec<OsString> {
         .collect::<Vec<OsString>>();
     args.append(&mut extra_args);
 
+    if vm.balloon {
+        args.push("-device".into());
+        args.push(format!("{},id=balloon0", transport.device("virtio-balloon")).into());
+    }
+
     // NOTE: bios handled in the UEFI code.
 
-    args
+    Ok(args)
 }
 
 /// Stream command output to the output sink
@@ -497,13 +2034,15 @@ fn vmconfig_args(vm: &VMConfig) -> Vec<OsString> {
 /// command output and text on screen (as opposed to non-blocking reads with
 /// sleeps).
 ///
-/// We implicitly rely on the stream closing (via synchronous qemu exit) to
-/// terminate this thread.
-fn stream_command_output<F>(stream: UnixStream, output: F)
+/// The thread terminates once the `end` marker (see [`mark_end_of_output`])
+/// is read or the stream closes, e.g. through qemu exit or an explicit
+/// shutdown. The marker itself is not passed on.
+fn stream_command_output<F>(stream: UnixStream, output: F, end: String) -> JoinHandle<()>
 where
     F: Fn(String) + Send + 'static,
 {
     let mut reader = BufReader::new(stream);
+    let end = format!("\0{end}");
 
     thread::spawn(move || {
         loop {
@@ -515,39 +2054,147 @@ where
                     if let Some('\n') = line.chars().last() {
                         line.pop();
                     }
+                    // Output without a trailing newline ends up in front of the marker
+                    if let Some(rest) = line.strip_suffix(&end) {
+                        if !rest.is_empty() {
+                            output(rest.to_string());
+                        }
+                        break;
+                    }
                     output(line);
                 }
                 Err(e) => debug!("Failed to read from command output stream: {}", e),
             };
         }
-    });
+    })
 }
 
-/// Run a process inside the VM and wait until completion
+/// Generate a marker for [`mark_end_of_output`]
 ///
-/// NB: this is not a shell, so you won't get shell features unless you run a
-/// `bash -c '...'`
+/// The marker is random so output of the guest cannot end a stream early by
+/// accident.
+fn gen_end_marker() -> String {
+    format!("{END_OF_OUTPUT_PREFIX}{:016x}", rand::random::<u64>())
+}
+
+/// Write the `end` marker to the virtio-serial port named `port_name`
 ///
-/// `propagate_env` specifies if the calling environment should be propagated
-/// into the VM. This is useful for running user specified commands which may
-/// depend on the calling environment.
+/// Output written to the port by processes that already exited is queued
+/// ahead of the marker, so once the host reads the marker it has all of that
+/// output. The marker is written as a NUL byte, `end` and a newline; NUL is
+/// not expected in the output of commands or terminals.
 ///
-/// `output_stream` is a unix domain socket that contains the streamed output
-/// of `cmd`. Provide this when output latency is important (for example with
-/// potentially long running `cmd`s).
+/// Returns an error if the marker could not be written, e.g. because the
+/// port was not found in the guest.
+fn mark_end_of_output(qga: &QgaWrapper, port_name: &str, end: &str) -> Result<()> {
+    let script = format!(
+        r#"for dir in /sys/class/virtio-ports/*; do
+    if [[ "$(cat "$dir/name")" == "{port_name}" ]]; then
+        printf '\0%s\n' "{end}" > "/dev/$(basename "$dir")"
+        exit
+    fi
+done
+exit 1"#
+    );
+    let rc = run_in_vm(qga, &|_| {}, "bash", &["-c", &script], false)?;
+    if rc != 0 {
+        bail!("Failed to write end of output marker: exit code {rc}");
+    }
+
+    Ok(())
+}
+
+/// Copy `reader` to `writer` until the `end` marker is read
 ///
-/// Returns the exit code if command is run
-fn run_in_vm<F>(
-    qga: &QgaWrapper,
-    output: &F,
-    cmd: &str,
-    args: &[&str],
-    propagate_env: bool,
-    output_stream: Option<UnixStream>,
-) -> Result<i64>
-where
-    F: Fn(String) + Clone + Send + 'static,
-{
+/// See [`mark_end_of_output`]. Returns once the marker is read or `reader`
+/// closes. Everything before the marker is written out as soon as it is
+/// known not to be part of it, so interactive output is not held back.
+fn copy_until_end<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    end: &str,
+) -> std::io::Result<()> {
+    let marker = format!("\0{end}\n").into_bytes();
+    let mut pending = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            writer.write_all(&pending)?;
+            return writer.flush();
+        }
+        pending.extend_from_slice(&buf[..n]);
+
+        if let Some(pos) = pending.windows(marker.len()).position(|w| w == marker) {
+            writer.write_all(&pending[..pos])?;
+            return writer.flush();
+        }
+
+        // Hold back what could be the start of the marker
+        let held = (1..marker.len())
+            .rev()
+            .find(|&len| pending.ends_with(&marker[..len]))
+            .unwrap_or(0);
+        let ready = pending.len() - held;
+        writer.write_all(&pending[..ready])?;
+        writer.flush()?;
+        pending.drain(..ready);
+    }
+}
+
+/// Copy from the file descriptor `fd` to `writer` until `stop` is set or
+/// `fd` reaches EOF
+///
+/// Unlike [`std::io::copy`], this does not block on reading, so the thread
+/// running it can be joined even if no more input arrives.
+fn copy_until_stopped<W: Write>(
+    fd: RawFd,
+    writer: &mut W,
+    stop: &AtomicBool,
+) -> std::io::Result<()> {
+    let mut buf = [0u8; 4096];
+    while !stop.load(Ordering::Relaxed) {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Wake up regularly to check whether to stop
+        let ready = unsafe { libc::poll(&mut pollfd, 1, 100) };
+        if ready < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        } else if ready == 0 {
+            continue;
+        }
+
+        // Read from the fd directly, as buffering would hide pending input
+        // from poll
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        if n < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        } else if n == 0 {
+            break;
+        }
+        writer.write_all(&buf[..n as usize])?;
+    }
+
+    Ok(())
+}
+
+/// Start a process inside the VM without waiting for it
+///
+/// See [`run_in_vm`] for the meaning of the arguments.
+///
+/// Returns the PID of the process in the guest
+fn spawn_in_vm(qga: &QgaWrapper, cmd: &str, args: &[&str], propagate_env: bool) -> Result<i64> {
     let version = qga.version();
     let qga_args = qga::guest_exec {
         path: cmd.to_string(),
@@ -571,13 +2218,31 @@ where
     let handle = qga
         .guest_exec(qga_args)
         .context("Failed to QGA guest-exec")?;
-    let pid = handle.pid;
 
-    // If requested, start streaming output. We will still use guest-exec
-    // output facilities as backup (and for streaming error messages).
-    if let Some(stream) = output_stream {
-        stream_command_output(stream, (*output).clone());
-    }
+    Ok(handle.pid)
+}
+
+/// Run a process inside the VM and wait until completion
+///
+/// NB: this is not a shell, so you won't get shell features unless you run a
+/// `bash -c '...'`
+///
+/// `propagate_env` specifies if the calling environment should be propagated
+/// into the VM. This is useful for running user specified commands which may
+/// depend on the calling environment.
+///
+/// Returns the exit code if command is run
+fn run_in_vm<F>(
+    qga: &QgaWrapper,
+    output: &F,
+    cmd: &str,
+    args: &[&str],
+    propagate_env: bool,
+) -> Result<i64>
+where
+    F: Fn(String),
+{
+    let pid = spawn_in_vm(qga, cmd, args, propagate_env)?;
 
     let now = time::Instant::now();
     let mut period = Duration::from_millis(200);
@@ -655,15 +2320,53 @@ fn connect_to_uds(path: &Path) -> Result<UnixStream> {
 impl Qemu {
     /// Construct a QEMU instance backing a vmtest target.
     ///
+    /// `shell` is whether a shell may be attached to the VM, either through
+    /// [`Qemu::shell`] or because the command failed in [`Qemu::run`].
+    ///
     /// Does not run anything yet.
-    pub fn new(updates: Sender<Output>, target: Target, host_shared: &Path) -> Result<Self> {
-        let qga_sock = gen_sock("qga");
-        let qmp_sock = gen_sock("qmp");
-        let command_sock = gen_sock("cmdout");
-        let (init, guest_init) = gen_init(&target.rootfs).context("Failed to generate init")?;
+    pub fn new(updates: Sink, target: Target, host_shared: &Path, shell: bool) -> Result<Self> {
+        let run_dir = RunDir::new()?;
+        let qga_sock = run_dir.sock("qga");
+        let qmp_sock = run_dir.sock("qmp");
+        let cancel_sock = run_dir.sock("cancel");
+        let command_sock = run_dir.sock("cmdout");
+        let shell_sock = shell.then(|| run_dir.sock("shell"));
+        let control_sock =
+            (target.vm.balloon || target.vm.max_cpus.is_some()).then(|| run_dir.sock("control"));
+        let profile = arch_profile(&target.arch)
+            .ok_or_else(|| anyhow!("Unsupported arch '{}'", target.arch))?;
+        let rootfs = match &target.rootfs {
+            Rootfs::Path(path) => path.clone(),
+            Rootfs::FromHost { .. } => panic!("setup_qemu should've built the rootfs"),
+        };
+        let rootfs_image = target
+            .kernel
+            .as_ref()
+            .and_then(|_| rootfs::image_fstype(&rootfs));
+        // A rootfs image is read-only, so it always gets a writable overlay
+        let rootfs_overlay = target
+            .rootfs_overlay
+            .clone()
+            .or_else(|| rootfs_image.map(|_| RootfsOverlay::default()));
+        let mut init = None;
+        let mut boot_dir = None;
+        let guest_init = if rootfs_image.is_some() {
+            let (dir, guest_init) = gen_boot_dir(run_dir.path(), rootfs_overlay.as_ref())
+                .context("Failed to generate init")?;
+            boot_dir = Some(dir);
+            guest_init
+        } else {
+            if target.kernel.is_some() {
+                check_rootfs_arch(&rootfs, profile)?;
+            }
+            let (file, guest_init) =
+                gen_init(&rootfs, rootfs_overlay.as_ref()).context("Failed to generate init")?;
+            init = Some(file);
+            guest_init
+        };
         let program = target
             .qemu_command
-            .unwrap_or_else(|| format!("qemu-system-{}", target.arch));
+            .unwrap_or_else(|| format!("qemu-system-{}", profile.qemu));
         Self::verify_qemu_exists(&program)?;
 
         // Start the main QEMU process
@@ -674,32 +2377,82 @@ impl Qemu {
             .arg("-serial")
             .arg("mon:stdio")
             .args(kvm_args(
-                &target.arch,
+                profile,
                 target.kvm_cpu_args.as_deref().unwrap_or("host"),
             ))
-            .args(machine_args(&target.arch))
+            .args(machine_args(profile, target.secure_boot))
             .args(machine_protocol_args(&qmp_sock))
+            .args(machine_protocol_args(&cancel_sock))
             .args(guest_agent_args(&qga_sock))
-            .args(virtio_serial_args(&command_sock));
+            .args(virtio_serial_args(
+                &command_sock,
+                "cmdout",
+                COMMAND_OUTPUT_PORT_NAME,
+            ));
+        if let Some(sock) = &shell_sock {
+            c.args(virtio_serial_args(sock, "shell", SHELL_PORT_NAME));
+        }
+        if let Some(sock) = &control_sock {
+            c.args(virtio_serial_args(sock, "control", CONTROL_PORT_NAME));
+        }
         // Always ensure the rootfs is first.
+        let mut overlay_disk = None;
+        let mut cloud_init_seed: Option<Seed> = None;
+        let mut uefi_vars = None;
         if let Some(image) = &target.image {
-            c.args(drive_args(image, 1));
+            // Snapshots attach the image last, once the snapshot is located
+            if !target.snapshot {
+                c.args(drive_args(image, 1, false, None, profile.transport));
+            }
             if target.uefi {
-                c.args(uefi_firmware_args(target.vm.bios.as_deref()));
+                let (args, vars) = uefi_firmware_args(
+                    run_dir.path(),
+                    &target.arch,
+                    target.vm.bios.as_deref(),
+                    target.secure_boot,
+                )
+                .context("Failed to set up UEFI firmware")?;
+                c.args(args);
+                uefi_vars = vars;
+            }
+            if let Some(config) = &target.cloud_init {
+                let seed = gen_seed(run_dir.path(), config)
+                    .context("Failed to generate cloud-init seed")?;
+                c.args(cloud_init_args(seed.path(), profile.transport));
+                cloud_init_seed = Some(seed);
             }
         } else if let Some(kernel) = &target.kernel {
-            c.args(plan9_fs_args(
-                target.rootfs.as_path(),
-                "root",
-                ROOTFS_9P_FS_MOUNT_TAG,
-                false,
-            ));
+            let overlay = rootfs_overlay.as_ref();
+            let root = match (rootfs_image, &boot_dir) {
+                (Some(fstype), Some(dir)) => {
+                    c.args(rootfs_image_args(&rootfs, profile.transport));
+                    c.args(plan9_fs_args(dir, "boot", BOOT_9P_FS_MOUNT_TAG, true));
+                    KernelRoot::Image(fstype)
+                }
+                _ => {
+                    // An overlaid rootfs is never written to, so enforce that on the host
+                    c.args(plan9_fs_args(
+                        rootfs.as_path(),
+                        "root",
+                        ROOTFS_9P_FS_MOUNT_TAG,
+                        overlay.is_some(),
+                    ));
+                    KernelRoot::Plan9
+                }
+            };
             c.args(kernel_args(
                 kernel,
-                &target.arch,
+                profile,
+                root,
                 guest_init.as_path(),
+                overlay.is_some(),
                 target.kernel_args.as_deref(),
             ));
+            if let Some(o) = overlay.filter(|o| o.upper == OverlayUpper::Disk) {
+                let disk = gen_overlay_disk(run_dir.path(), o.size.as_deref())?;
+                c.args(overlay_disk_args(disk.path(), profile.transport));
+                overlay_disk = Some(disk);
+            }
         } else {
             panic!("Config validation should've enforced XOR");
         }
@@ -710,7 +2463,66 @@ impl Qemu {
             SHARED_9P_FS_MOUNT_TAG,
             false,
         ));
-        c.args(vmconfig_args(&target.vm));
+        c.args(vmconfig_args(&target.vm, profile.transport).context("Failed to configure VM")?);
+        if let Some(backend) = &target.vm.memory_backend {
+            if backend.kind == MemoryBackendKind::Hugetlbfs {
+                let path = backend
+                    .path
+                    .clone()
+                    .unwrap_or_else(MemoryBackend::default_path);
+                check_hugepages(&path, parse_memory(&target.vm.memory)?)
+                    .context("Failed to set up hugepages")?;
+            }
+        }
+
+        let swtpm = if target.vm.tpm {
+            let swtpm = Swtpm::spawn(run_dir.path()).context("Failed to start swtpm")?;
+            c.args(tpm_args(&target.arch, target.vm.tpm_model, &swtpm.sock()));
+            Some(swtpm)
+        } else {
+            None
+        };
+
+        // Scratch disks get a fresh backing file every run
+        let mut disks = target.vm.disks;
+        let mut scratch_disks = Vec::new();
+        for disk in disks.iter_mut() {
+            if let Some(size) = &disk.size {
+                let file = gen_scratch_disk(run_dir.path(), disk, size)?;
+                disk.path = Some(file.path().to_path_buf());
+                scratch_disks.push(file);
+            }
+        }
+        let mut storage = StorageBuilder::new(profile.transport);
+        for controller in &target.vm.controllers {
+            storage.controller(controller);
+        }
+        for disk in &disks {
+            storage.disk(disk);
+        }
+        let (storage_args, disks) = storage.build();
+        c.args(storage_args);
+
+        // This must come last so the snapshot covers the full invocation
+        let snapshot = match &target.image {
+            Some(image) if target.snapshot => Some(
+                snapshot_args(
+                    &mut c,
+                    image,
+                    profile.transport,
+                    &[&qga_sock, &qmp_sock, &cancel_sock, &command_sock]
+                        .map(PathBuf::as_path)
+                        .into_iter()
+                        .chain(shell_sock.as_deref())
+                        .chain(control_sock.as_deref())
+                        .chain(cloud_init_seed.iter().map(Seed::path))
+                        .chain(uefi_vars.iter().map(NamedTempFile::path))
+                        .collect::<Vec<_>>(),
+                )
+                .context("Failed to locate VM snapshot")?,
+            ),
+            _ => None,
+        };
 
         if log_enabled!(Level::Error) {
             let args = c
@@ -728,15 +2540,31 @@ impl Qemu {
             process: c,
             qga_sock,
             qmp_sock,
+            cancel_sock,
             command: target.command,
+            user: target.user,
             command_sock,
+            shell_sock,
+            control_sock,
             host_shared: host_shared.to_owned(),
-            rootfs: target.rootfs,
+            rootfs,
             arch: target.arch,
             mounts: target.vm.mounts,
-            init: Some(init),
+            init,
+            run_dir: Some(run_dir),
+            overlay_disk,
+            cloud_init_seed,
+            uefi_vars,
+            swtpm,
+            disks,
+            scratch_disks,
             updates,
             image: target.image.is_some(),
+            snapshot,
+            console_muted: Arc::new(AtomicBool::new(false)),
+            console: None,
+            canceller: Canceller::default(),
+            timings: Timings::default(),
         };
 
         // We still need to possibly redirect the standard streams.
@@ -758,7 +2586,7 @@ impl Qemu {
     }
 
     /// Waits for QMP and QGA sockets to appear
-    fn wait_for_qemu(&self, child: &mut Child) -> Result<()> {
+    fn wait_for_qemu(&self, child: &SharedChild) -> Result<()> {
         let now = time::Instant::now();
         let timeout = Duration::from_secs(5);
 
@@ -800,27 +2628,42 @@ impl Qemu {
         bail!("QEMU sockets did not appear in time");
     }
 
-    /// Generates a bash script that runs `self.command`
-    fn command_script(&self) -> String {
+    /// Generates a bash script that runs `command`
+    fn command_script(&self, command: &str) -> String {
         let context = CommandContext {
             // Only `cd` for kernel targets that share userspace with host
             should_cd: !self.image && self.rootfs == Target::default_rootfs(),
             host_shared: &self.host_shared,
-            command: &self.command,
+            command,
             command_output_port_name: COMMAND_OUTPUT_PORT_NAME,
+            user: self.user.as_deref(),
+            disks: self.disks.clone(),
+            control_port_name: CONTROL_PORT_NAME,
+            control_script: self.control_sock.as_ref().map(|_| ctl_script()),
         };
 
         // Ignore errors cuz only trivial bugs are possible
         get_templates().render("cmd", &context).unwrap()
     }
 
-    /// Run this target's command inside the VM
+    /// Run `command` inside the VM
     ///
     /// Note the command is run in a bash shell
-    fn run_command(&self, qga: &QgaWrapper) -> Result<i64> {
+    ///
+    /// All of the command's output has been sent to `updates` by the time
+    /// this returns. The command output stream is disconnected as well, so
+    /// that the next command can reconnect to it.
+    ///
+    /// `qmp` carries out `vmtest-ctl` requests while the command runs.
+    fn run_command(
+        &self,
+        qga: &QgaWrapper,
+        qmp: &mut Qmp<QmpUnixStream>,
+        command: &str,
+    ) -> Result<i64> {
         let updates = self.updates.clone();
         let output_fn = move |line: String| {
-            let _ = updates.send(Output::Command(line));
+            updates.send(Output::Command(line));
         };
 
         // Set read timeout to None so we can block indefinitely in case the VM
@@ -833,23 +2676,151 @@ impl Qemu {
         }
         qga.set_read_timeout(None)?;
 
+        // Start streaming output. We will still use guest-exec output
+        // facilities as backup (and for streaming error messages).
         let output_stream = connect_to_uds(&self.command_sock)
             .context("Failed to connect to command output socket")?;
+        let drain = output_stream
+            .try_clone()
+            .context("Failed to clone command output socket")?;
+        let end = gen_end_marker();
+        let streamer = stream_command_output(output_stream, output_fn.clone(), end.clone());
+        let control = match &self.control_sock {
+            Some(sock) => {
+                Some(connect_to_uds(sock).context("Failed to connect to control socket")?)
+            }
+            None => None,
+        };
 
+        // The command is also passed as a positional argument for when the
+        // script needs to hand it off to another shell.
         let cmd = "bash";
-        let script = self.command_script();
-        let args = ["-c", &script];
+        let script = self.command_script(command);
+        let args = ["-c", &script, "bash", command];
+
+        let rc = thread::scope(|s| {
+            // Serve `vmtest-ctl` requests for as long as the command runs
+            let server = match control {
+                Some(stream) => {
+                    let stop = stream
+                        .try_clone()
+                        .context("Failed to clone control socket")?;
+                    Some((stop, s.spawn(move || control::serve(stream, qmp))))
+                }
+                None => None,
+            };
 
-        // Note we are propagating environment variables for this command
-        // only if it's a kernel target.
-        run_in_vm(
-            qga,
-            &output_fn,
-            cmd,
-            &args,
-            !self.image,
-            Some(output_stream),
-        )
+            // Note we are propagating environment variables for this command
+            // only if it's a kernel target.
+            let rc = run_in_vm(qga, &output_fn, cmd, &args, !self.image);
+
+            // Like the output chardev, the control chardev only serves a
+            // single connection at a time
+            if let Some((stop, server)) = server {
+                let _ = stop.shutdown(Shutdown::Both);
+                let _ = server.join();
+            }
+
+            rc
+        });
+
+        // Wait for in-flight output to arrive. If the command did not run or
+        // the guest has no output port, there is nothing to wait for.
+        let marked = rc.is_ok() && {
+            match mark_end_of_output(qga, COMMAND_OUTPUT_PORT_NAME, &end) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("Not waiting for trailing command output: {:?}", e);
+                    false
+                }
+            }
+        };
+        if !marked {
+            let _ = drain.shutdown(Shutdown::Both);
+        }
+        let _ = streamer.join();
+        // QEMU only serves a single connection on the output chardev at a
+        // time, so hang up for the next command to connect
+        let _ = drain.shutdown(Shutdown::Both);
+
+        rc
+    }
+
+    /// Generates a bash script that attaches a login shell to the shell port
+    fn shell_script(&self) -> String {
+        let (rows, cols) = Term::stdout().size();
+        let context = ShellContext {
+            should_cd: !self.image && self.rootfs == Target::default_rootfs(),
+            host_shared: shell_quote(&self.host_shared),
+            shell_port_name: SHELL_PORT_NAME,
+            term: shell_quote(env::var_os("TERM").unwrap_or_else(|| "vt100".into())),
+            rows,
+            cols,
+        };
+
+        // Ignore errors cuz only trivial bugs are possible
+        get_templates().render("shell", &context).unwrap()
+    }
+
+    /// Attach the terminal to a login shell inside the VM
+    ///
+    /// The shell runs on a pty inside the guest which is bridged to the
+    /// terminal over a virtio-serial port. Returns once the shell exits.
+    ///
+    /// Returns the exit code of the shell
+    fn attach_shell(&self, qga: &QgaWrapper) -> Result<i64> {
+        let sock = self
+            .shell_sock
+            .as_ref()
+            .ok_or_else(|| anyhow!("VM has no shell port"))?;
+        let stream = connect_to_uds(sock).context("Failed to connect to shell socket")?;
+        let mut to_guest = stream.try_clone().context("Failed to clone shell socket")?;
+        let mut from_guest = stream.try_clone().context("Failed to clone shell socket")?;
+
+        let script = self.shell_script();
+        let pid = spawn_in_vm(qga, "bash", &["-c", &script], !self.image)
+            .context("Failed to start shell")?;
+
+        // Keep console output from garbling the shell
+        self.console_muted.store(true, Ordering::Relaxed);
+        let _raw = RawTerminal::new();
+        scopeguard::defer! {
+            self.console_muted.store(false, Ordering::Relaxed);
+        }
+
+        let stop_input = Arc::new(AtomicBool::new(false));
+        let stop = stop_input.clone();
+        let input = thread::spawn(move || {
+            let _ = copy_until_stopped(libc::STDIN_FILENO, &mut to_guest, &stop);
+        });
+        let end = gen_end_marker();
+        let printer_end = end.clone();
+        let printer = thread::spawn(move || {
+            let _ = copy_until_end(&mut from_guest, &mut std::io::stdout(), &printer_end);
+        });
+
+        // Shells are interactive, so poll at a steady and snappy rate
+        let status = loop {
+            let status = qga
+                .guest_exec_status(pid)
+                .context("Failed to QGA guest-exec-status")?;
+            if status.exited {
+                break status;
+            }
+            thread::sleep(Duration::from_millis(100));
+        };
+
+        // Wait for trailing output to arrive and then hang up
+        if let Err(e) = mark_end_of_output(qga, SHELL_PORT_NAME, &end) {
+            debug!("Not waiting for trailing shell output: {:?}", e);
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let _ = printer.join();
+        let _ = stream.shutdown(Shutdown::Both);
+        stop_input.store(true, Ordering::Relaxed);
+        let _ = input.join();
+
+        Ok(status.exitcode.unwrap_or(0))
     }
 
     /// Mount shared directory in the guest
@@ -862,10 +2833,10 @@ impl Qemu {
     ) -> Result<()> {
         let updates = self.updates.clone();
         let output_fn = move |line: String| {
-            let _ = updates.send(Output::Setup(line));
+            updates.send(Output::Setup(line));
         };
 
-        let rc = run_in_vm(qga, &output_fn, "mkdir", &["-p", guest_path], false, None)?;
+        let rc = run_in_vm(qga, &output_fn, "mkdir", &["-p", guest_path], false)?;
         if rc != 0 {
             bail!("Failed to mkdir {}: exit code {}", guest_path, rc);
         }
@@ -884,7 +2855,6 @@ impl Qemu {
                 "mount",
                 &["-t", "9p", "-o", &mount_opts, mount_tag, guest_path],
                 false,
-                None,
             )?;
 
             // Exit code 32 from mount(1) indicates mount failure.
@@ -905,7 +2875,7 @@ impl Qemu {
 
     /// Sync guest filesystems so any in-flight data has time to go out to host
     fn sync(&self, qga: &QgaWrapper) -> Result<()> {
-        let rc = run_in_vm(qga, &|_| {}, "sync", &[], false, None)?;
+        let rc = run_in_vm(qga, &|_| {}, "sync", &[], false)?;
         if rc != 0 {
             bail!("Failed to sync guest filesystems: exit code {}", rc);
         }
@@ -914,7 +2884,7 @@ impl Qemu {
     }
 
     /// Cleans up qemu child process if necessary
-    fn child_cleanup(mut child: Child) {
+    fn child_cleanup(child: SharedChild) {
         match child.try_wait() {
             Ok(Some(e)) => {
                 debug!("Child already exited with {e}");
@@ -938,14 +2908,16 @@ impl Qemu {
 
         // Dump stdout/stderr in case it's useful for debugging
         if log_enabled!(Level::Debug) {
-            if let Some(mut io) = child.stdout {
+            let stdout = child.lock().stdout.take();
+            if let Some(mut io) = stdout {
                 let mut s = String::new();
                 match io.read_to_string(&mut s) {
                     Ok(_) => debug!("qemu stdout: {s}"),
                     Err(e) => debug!("failed to get qemu stdout: {e}"),
                 }
             }
-            if let Some(mut io) = child.stderr {
+            let stderr = child.lock().stderr.take();
+            if let Some(mut io) = stderr {
                 let mut s = String::new();
                 match io.read_to_string(&mut s) {
                     Ok(_) => debug!("qemu stderr: {s}"),
@@ -964,7 +2936,14 @@ impl Qemu {
     ///
     /// Calling this function will spawn a thread that takes ownership
     /// over the child's stdout and reads until the the process exits.
-    fn stream_child_output(updates: Sender<Output>, child: &mut Child) {
+    /// Returns the thread's handle.
+    ///
+    /// Output is dropped while `muted` is set.
+    fn stream_child_output(
+        updates: Sink,
+        child: &mut Child,
+        muted: Arc<AtomicBool>,
+    ) -> JoinHandle<()> {
         // unwrap() should never fail b/c we are capturing stdout
         let stdout = child.stdout.take().unwrap();
         let mut reader = BufReader::new(stdout);
@@ -974,15 +2953,38 @@ impl Qemu {
                 let mut line = String::new();
                 match reader.read_line(&mut line) {
                     Ok(0) => break,
+                    Ok(_) if muted.load(Ordering::Relaxed) => (),
                     Ok(_) => {
                         // Remove newline
                         line.pop();
-                        let _ = updates.send(Output::Boot(line));
+                        updates.send(Output::Boot(line));
                     }
                     Err(e) => debug!("Failed to read from qemu stdout: {}", e),
                 };
             }
-        });
+        })
+    }
+
+    /// Report the stages timed so far through the `updates` sink
+    ///
+    /// Timings are only reported once; stages timed afterwards are
+    /// reported by the next call.
+    fn report_timings(&mut self) {
+        let timings = mem::take(&mut self.timings);
+        if !timings.is_empty() {
+            self.updates.send(Output::Timings(timings));
+        }
+    }
+
+    /// Wait for all of QEMU's console output to be forwarded
+    ///
+    /// Must only be called once QEMU is gone, as the output ends with it.
+    fn join_console(&mut self) {
+        if let Some(console) = self.console.take() {
+            if console.join().is_err() {
+                warn!("Console output thread panicked");
+            }
+        }
     }
 
     /// Extracts stderr out from the child.
@@ -991,11 +2993,12 @@ impl Qemu {
     /// back to the user.
     ///
     /// Any failures in extraction will be encoded into the return string.
-    fn extract_child_stderr(child: &mut Child) -> String {
+    fn extract_child_stderr(child: &SharedChild) -> String {
         let mut err = String::new();
 
-        // unwrap() should never fail b/c we are capturing stderr
-        let mut stderr = child.stderr.take().unwrap();
+        // unwrap() should never fail b/c we are capturing stderr. Reading
+        // blocks until QEMU exits, so the lock must not be held meanwhile.
+        let mut stderr = child.lock().stderr.take().unwrap();
         if let Err(e) = stderr.read_to_string(&mut err) {
             err += &format!("<failed to read child stderr: {}>", e);
         }
@@ -1030,11 +3033,21 @@ impl Qemu {
     fn boot_vm(
         &mut self,
     ) -> Result<(
-        scopeguard::ScopeGuard<Child, impl FnOnce(Child)>,
+        ScopeGuard<SharedChild, fn(SharedChild)>,
         QgaWrapper,
         Qmp<QmpUnixStream>,
     )> {
-        let _ = self.updates.send(Output::BootStart);
+        self.updates.send(Output::BootStart);
+        if self.canceller.is_cancelled() {
+            bail!("Run was cancelled");
+        }
+        // Keep QEMU out of our process group, so that terminal signals only
+        // reach us and we shut it down. An interactive QEMU reads from the
+        // terminal, which only the foreground process group may do.
+        if !self.interactive() {
+            self.process.process_group(0);
+        }
+        let spawned = time::Instant::now();
         let mut child = match self.process.spawn() {
             Ok(c) => c,
             Err(e) => {
@@ -1044,21 +3057,31 @@ impl Qemu {
         if !self.interactive() {
             // If we are running a command, we need to stream stdout
             // to the receiver.
-            Self::stream_child_output(self.updates.clone(), &mut child);
+            self.console = Some(Self::stream_child_output(
+                self.updates.clone(),
+                &mut child,
+                self.console_muted.clone(),
+            ));
         }
 
         // Ensure child is cleaned up even if we bail early
-        let mut child = scopeguard::guard(child, Self::child_cleanup);
+        let child = scopeguard::guard(
+            SharedChild::new(child),
+            Self::child_cleanup as fn(SharedChild),
+        );
+        self.canceller.attach(&self.cancel_sock, &child)?;
 
-        if let Err(e) = self.wait_for_qemu(&mut child) {
+        if let Err(e) = self.wait_for_qemu(&child) {
             return Err(e).context("Failed waiting for QEMU to be ready");
         }
+        self.timings.spawn = Some(spawned.elapsed());
+        let booting = time::Instant::now();
 
         // Connect to QMP socket
         let qmp_unix_stream = match connect_to_uds(&self.qmp_sock) {
             Ok(s) => s,
             Err(e) => {
-                let err = Self::extract_child_stderr(&mut child);
+                let err = Self::extract_child_stderr(&child);
                 return Err(e).context("Failed to connect QMP").context(err);
             }
         };
@@ -1080,22 +3103,37 @@ impl Qemu {
         let qmp_info = match qmp.handshake() {
             Ok(i) => i,
             Err(e) => {
-                let err = Self::extract_child_stderr(&mut child);
+                let err = Self::extract_child_stderr(&child);
                 return Err(e).context("QMP handshake failed").context(err);
             }
         };
         debug!("QMP info: {:#?}", qmp_info);
 
         // Connect to QGA socket
-        let qga = QgaWrapper::new(&self.qga_sock, host_supports_kvm(&self.arch), &mut child);
+        let qga = QgaWrapper::new(&self.qga_sock, host_supports_kvm(&self.arch), &child);
         let qga = match qga {
             Ok(q) => q,
             Err(e) => {
                 return Err(e).context("Failed to connect QGA");
             }
         };
+        self.timings.boot = Some(booting.elapsed());
+
+        // Snapshot right after boot, before any shared directories are
+        // mounted, as QEMU refuses to migrate guests with mounted 9p exports.
+        if let Some(Snapshot::Save {
+            state,
+            disk,
+            overlay,
+        }) = &self.snapshot
+        {
+            match save_snapshot(&mut qmp, state, disk, overlay.path()) {
+                Ok(_) => debug!("Saved VM snapshot: {}", state.display()),
+                Err(e) => warn!("Failed to save VM snapshot: {:?}", e),
+            }
+        }
 
-        let _ = self.updates.send(Output::BootEnd(Ok(())));
+        self.updates.send(Output::BootEnd(Ok(())));
 
         Ok((child, qga, qmp))
     }
@@ -1106,7 +3144,8 @@ impl Qemu {
     /// by mounting the shared directory and any other additional mounts.
     fn setup_vm(&mut self, qga: &QgaWrapper) -> Result<()> {
         // Mount shared directory inside guest
-        let _ = self.updates.send(Output::SetupStart);
+        self.updates.send(Output::SetupStart);
+        let started = time::Instant::now();
         if let Err(e) =
             self.mount_in_guest(qga, SHARED_9P_FS_MOUNT_PATH, SHARED_9P_FS_MOUNT_TAG, false)
         {
@@ -1122,27 +3161,47 @@ impl Qemu {
                 return Err(e).context(format!("Failed to mount {} in guest", guest_path));
             }
         }
-        let _ = self.updates.send(Output::SetupEnd(Ok(())));
+        self.timings.setup = Some(started.elapsed());
+        self.updates.send(Output::SetupEnd(Ok(())));
         Ok(())
     }
 
-    /// Run the target to completion
+    /// Replace the sink real time updates are sent to
     ///
-    /// Errors and return status are reported through the `updates` channel passed into the
-    /// constructor.
-    pub fn run(mut self) {
+    /// Note that QEMU's boot output continues to be sent to the sink that
+    /// was active when the VM was started.
+    pub fn set_updates(&mut self, updates: Sink) {
+        self.updates = updates;
+    }
+
+    /// Boot and set up the VM
+    ///
+    /// Returns handles to the running VM on success. Errors are reported
+    /// through the `updates` sink and `None` is returned.
+    pub fn start(&mut self) -> Option<VmHandle> {
         // Start QEMU
-        let (mut child, qga, mut qmp) = match self.boot_vm() {
+        let (child, qga, qmp) = match self.boot_vm() {
             Ok((c, qga, qmp)) => (c, qga, qmp),
             Err(e) => {
-                let _ = self.updates.send(Output::BootEnd(Err(e)));
-                return;
+                let e = match &self.snapshot {
+                    // Discard the snapshot so the next run boots from scratch
+                    Some(Snapshot::Restore { state, disk }) => {
+                        let _ = fs::remove_file(state);
+                        let _ = fs::remove_file(disk);
+                        e.context("Failed to resume from VM snapshot. It has been discarded")
+                    }
+                    _ => e,
+                };
+                self.updates
+                    .send(Output::BootEnd(Err(self.cancelled_or(e))));
+                return None;
             }
         };
 
         if let Err(e) = self.setup_vm(&qga) {
-            let _ = self.updates.send(Output::SetupEnd(Err(e)));
-            return;
+            self.updates
+                .send(Output::SetupEnd(Err(self.cancelled_or(e))));
+            return None;
         }
 
         // We can safely remove our init script temporary file at this
@@ -1151,57 +3210,263 @@ impl Qemu {
         // killed forcefully or is just exited early via Ctrl-C.
         drop(self.init.take());
 
-        // At this stage qemu should be prompting us with a shell prompt if running
-        // in interactive mode.
-        // Once the child has returned, we are done and can exit.
-        if self.interactive() {
-            child.wait().expect("command wasn't running");
-            return;
+        Some(VmHandle { child, qga, qmp })
+    }
+
+    /// Replace the handle other threads cancel this run through
+    pub fn set_canceller(&mut self, canceller: Canceller) {
+        self.canceller = canceller;
+    }
+
+    /// Returns the handle other threads cancel this run through
+    pub fn canceller(&self) -> Canceller {
+        self.canceller.clone()
+    }
+
+    /// Replace `e` with a cancellation error if the run was cancelled
+    ///
+    /// Errors after a cancellation are just fallout of QEMU going away.
+    fn cancelled_or(&self, e: anyhow::Error) -> anyhow::Error {
+        if self.canceller.is_cancelled() {
+            anyhow!("Run was cancelled")
+        } else {
+            e
         }
+    }
 
-        // Run command in VM
-        let _ = self.updates.send(Output::CommandStart);
-        match self.run_command(&qga) {
+    /// Run `command` inside a VM previously started with [`Qemu::start`]
+    ///
+    /// More commands may be run in the VM afterwards.
+    ///
+    /// The exit code or error is reported through the `updates` sink.
+    /// Returns whether the command ran and exited successfully.
+    pub fn exec(&mut self, vm: &mut VmHandle, command: &str) -> bool {
+        self.updates.send(Output::CommandStart);
+        let started = time::Instant::now();
+        let rc = self.run_command(&vm.qga, &mut vm.qmp, command);
+        if rc.is_ok() {
+            self.timings.command = Some(started.elapsed());
+        }
+        match rc {
+            Ok(_) if self.canceller.is_cancelled() => {
+                self.updates
+                    .send(Output::CommandEnd(Err(anyhow!("Run was cancelled"))));
+                false
+            }
             Ok(rc) => {
-                let _ = self.updates.send(Output::CommandEnd(Ok(rc)));
+                self.updates.send(Output::CommandEnd(Ok(rc)));
+                rc == 0
             }
             Err(e) => {
-                let _ = self
-                    .updates
-                    .send(Output::CommandEnd(Err(e).context("Failed to run command")));
+                let e = e.context("Failed to run command");
+                self.updates
+                    .send(Output::CommandEnd(Err(self.cancelled_or(e))));
+                false
             }
         }
+    }
+
+    /// Sync guest filesystems and shut down the VM
+    ///
+    /// Returns how QEMU exited, if it could be waited on, once all of its
+    /// console output has been forwarded.
+    pub fn stop(&mut self, vm: VmHandle) -> Option<ExitStatus> {
+        let VmHandle {
+            child,
+            qga,
+            mut qmp,
+        } = vm;
 
         if let Err(e) = self.sync(&qga) {
             warn!("Failed to sync filesystem: {}", e);
         }
 
         // Quit and wait for QEMU to exit
-        match qmp.execute(&qmp::quit {}) {
+        let status = match qmp.execute(&qmp::quit {}) {
             Ok(_) => match child.wait() {
-                Ok(s) => debug!("Exit code: {:?}", s.code()),
-                Err(e) => warn!("Failed to wait on child: {}", e),
+                Ok(s) => {
+                    debug!("Exit code: {:?}", s.code());
+                    Some(s)
+                }
+                Err(e) => {
+                    warn!("Failed to wait on child: {}", e);
+                    None
+                }
             },
             // TODO(dxu): debug why we are getting errors here
-            Err(e) => debug!("Failed to gracefully quit QEMU: {e}"),
+            Err(e) => {
+                debug!("Failed to gracefully quit QEMU: {e}");
+                None
+            }
+        };
+        self.canceller.detach();
+        // Kills QEMU if it did not quit
+        drop(child);
+        self.join_console();
+
+        status
+    }
+
+    /// Boot the VM and attach the terminal to a login shell inside it
+    ///
+    /// For kernel targets, the login shell on the serial console is used.
+    /// Image targets get a login shell on a pty bridged over virtio-serial,
+    /// which is reported as the command stage.
+    ///
+    /// Errors are reported through the `updates` sink passed into the
+    /// constructor.
+    pub fn shell(mut self) {
+        if !self.image {
+            // Our init already runs a login shell on the serial console
+            self.command = MAGIC_INTERACTIVE_COMMAND.into();
+            self.process
+                .stdin(Stdio::inherit())
+                .stdout(Stdio::inherit());
+        }
+
+        let vm = match self.start() {
+            Some(vm) => vm,
+            None => return,
+        };
+
+        if self.interactive() {
+            vm.child.wait().expect("command wasn't running");
+            return;
+        }
+
+        self.updates.send(Output::CommandStart);
+        let rc = self.attach_shell(&vm.qga).context("Failed to attach shell");
+        self.updates.send(Output::CommandEnd(rc));
+        self.stop(vm);
+    }
+
+    /// Run the target to completion
+    ///
+    /// Errors and return status are reported through the `updates` sink passed into the
+    /// constructor. Returns how QEMU exited, if it got that far.
+    pub fn run(mut self) -> Option<ExitStatus> {
+        let mut vm = self.start()?;
+
+        // At this stage qemu should be prompting us with a shell prompt if running
+        // in interactive mode.
+        // Once the child has returned, we are done and can exit.
+        if self.interactive() {
+            return Some(vm.child.wait().expect("command wasn't running"));
+        }
+
+        // Run command in VM
+        let command = self.command.clone();
+        let succeeded = self.exec(&mut vm, &command);
+        // Nothing is timed past the command. Report before anything that
+        // may stop the receiver from listening, like a debug shell.
+        self.report_timings();
+
+        // A shell port is only set up for `run` if a shell should be
+        // attached on failure. The shell runs in the same guest as the failed
+        // command, so the state the command left behind can be inspected.
+        if !succeeded && self.shell_sock.is_some() {
+            // Let the receiver finish reporting before the shell takes over
+            self.updates.flush();
+            if let Err(e) = self.attach_shell(&vm.qga) {
+                warn!("Failed to attach debug shell: {:?}", e);
+            }
         }
+
+        self.stop(vm)
     }
 }
 
 impl Drop for Qemu {
     fn drop(&mut self) {
-        let _ = fs::remove_file(self.qga_sock.as_path());
-        let _ = fs::remove_file(self.qmp_sock.as_path());
-        let _ = fs::remove_file(self.command_sock.as_path());
+        // Any running QEMU has been killed by now
+        self.join_console();
+        self.report_timings();
+        self.canceller.detach();
+        drop(self.overlay_disk.take());
+        drop(self.cloud_init_seed.take());
+        drop(self.uefi_vars.take());
+        drop(self.swtpm.take());
+        self.scratch_disks.clear();
+        drop(self.run_dir.take());
+    }
+}
+
+/// Puts the terminal into raw mode for as long as it is alive
+///
+/// Keystrokes like Ctrl-C must reach the guest instead of being handled
+/// locally. Does nothing if stdin is not a terminal.
+struct RawTerminal {
+    /// Terminal settings to restore, as reported by `stty -g`
+    saved: Option<String>,
+}
+
+impl RawTerminal {
+    fn new() -> Self {
+        if !std::io::stdin().is_terminal() {
+            return Self { saved: None };
+        }
+
+        // NB: stty operates on the terminal connected to its stdin
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stderr(Stdio::inherit())
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string());
+        if saved.is_some() {
+            if let Err(e) = Command::new("stty").args(["raw", "-echo"]).status() {
+                warn!("Failed to put terminal in raw mode: {e}");
+            }
+        }
+
+        Self { saved }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            if let Err(e) = Command::new("stty").arg(saved).status() {
+                warn!("Failed to restore terminal settings: {e}");
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::guest_init_path;
+    use super::{
+        arch_profile, check_rootfs_arch, copy_until_end, copy_until_stopped, cpu_memory_args,
+        ctl_script, drive_args, firmware_candidates, get_templates, guest_init_path, hash,
+        hugetlbfs_page_size, init_script, kernel_args, machine_args, machine_protocol_args,
+        memory_backend_object, parse_cpu_list, parse_memory, parse_size, pflash_args, shell_quote,
+        snapshot_key, stream_command_output, tpm_args, vmconfig_args, Canceller, CommandContext,
+        DiskContext, KernelRoot, SharedChild, ShellContext, StorageBuilder, VirtioTransport,
+        BOOT_9P_FS_MOUNT_PATH, BOOT_9P_FS_MOUNT_TAG, CANCEL_TIMEOUT, COMMAND_OUTPUT_PORT_NAME,
+        COMMAND_TEMPLATE, CONTROL_PORT_NAME, CTL_TEMPLATE, INIT_TEMPLATE, MOUNT_OPTS_9P_FS,
+        SHELL_TEMPLATE,
+    };
+    use crate::rootfs;
+    use crate::{
+        Controller, ControllerKind, Disk, DiskBus, DiskMedia, MemoryBackend, MemoryBackendKind,
+        NumaNode, OverlayUpper, RootfsOverlay, Topology, TpmModel, VMConfig,
+    };
+    use regex::Regex;
     use rstest::rstest;
 
-    use std::path::PathBuf;
+    use std::collections::BTreeSet;
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+
+    use tempfile::{tempdir, NamedTempFile};
 
     #[rstest]
     // no trailing /
@@ -1221,6 +3486,88 @@ mod tests {
         assert_eq!(r, PathBuf::from(expected));
     }
 
+    #[test]
+    fn test_snapshot_key_ignores_ephemeral_paths() {
+        let image = NamedTempFile::new().unwrap();
+        let cmd = |sock: &str, memory: &str| {
+            let mut c = Command::new("qemu-system-x86_64");
+            c.args(machine_protocol_args(Path::new(sock)))
+                .args(["-m", memory]);
+            c
+        };
+
+        let key =
+            |c: &Command, sock: &str| snapshot_key(c, image.path(), &[Path::new(sock)]).unwrap();
+        let a = key(&cmd("/tmp/qmp-1.sock", "4G"), "/tmp/qmp-1.sock");
+        let b = key(&cmd("/tmp/qmp-2.sock", "4G"), "/tmp/qmp-2.sock");
+        let c = key(&cmd("/tmp/qmp-2.sock", "2G"), "/tmp/qmp-2.sock");
+        assert_eq!(a, b);
+        assert_ne!(b, c);
+
+        // Options next to an ephemeral path still count
+        let drive = |path: &str, readonly: &str| {
+            let mut c = Command::new("qemu-system-x86_64");
+            c.arg("-drive")
+                .arg(format!("file={path},format=raw,readonly={readonly}"));
+            key(&c, path)
+        };
+        assert_eq!(drive("/tmp/a.img", "on"), drive("/tmp/b.img", "on"));
+        assert_ne!(drive("/tmp/a.img", "on"), drive("/tmp/a.img", "off"));
+
+        // The image is not part of the command, but still keys the snapshot
+        let other = NamedTempFile::new().unwrap();
+        let c = cmd("/tmp/qmp-1.sock", "4G");
+        assert_ne!(a, snapshot_key(&c, other.path(), &[]).unwrap());
+    }
+
+    #[test]
+    fn test_drive_args() {
+        let args = drive_args(
+            Path::new("/images/a,b.qcow2"),
+            1,
+            true,
+            Some("qcow2"),
+            VirtioTransport::Pci,
+        );
+        assert_eq!(
+            args,
+            [
+                "-drive",
+                &format!(
+                    "file=/images/a,,b.qcow2,index=1,media=disk,if=none,id=disk{},snapshot=on,format=qcow2",
+                    hash(Path::new("/images/a,b.qcow2"))
+                ),
+                "-device",
+                &format!(
+                    "virtio-blk-pci,drive=disk{},bootindex=1",
+                    hash(Path::new("/images/a,b.qcow2"))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote(Path::new("/a b")), "'/a b'");
+        assert_eq!(shell_quote(Path::new("/it's")), r"'/it'\''s'");
+        assert_eq!(shell_quote("xterm; reboot"), "'xterm; reboot'");
+    }
+
+    #[test]
+    fn test_shell_template_quotes() {
+        let context = ShellContext {
+            should_cd: true,
+            host_shared: shell_quote(Path::new("/home/me/my project")),
+            shell_port_name: "shell",
+            term: shell_quote("xterm; touch /pwned"),
+            rows: 24,
+            cols: 80,
+        };
+        let script = get_templates().render("shell", &context).unwrap();
+        assert!(script.contains("cd '/home/me/my project'\n"));
+        assert!(script.contains("export TERM='xterm; touch /pwned'\n"));
+    }
+
     #[rstest]
     // This should never happen given that host_init_path is made by appending guest_temp_dir to rootfs.
     // for now it will return a guest_init_path which won't work.
@@ -1230,4 +3577,792 @@ mod tests {
     fn test_invalid_guest_init_path(#[case] guest_temp_dir: &str, #[case] host_init_path: &str) {
         guest_init_path(guest_temp_dir.into(), host_init_path.into()).unwrap_err();
     }
+
+    #[rstest]
+    #[case(None, false, false)]
+    #[case(Some("nobody"), false, false)]
+    #[case(Some("1000:1000"), false, false)]
+    #[case(None, true, false)]
+    #[case(None, false, true)]
+    #[case(Some("nobody"), false, true)]
+    fn test_command_template_is_valid_bash(
+        #[case] user: Option<&str>,
+        #[case] with_disks: bool,
+        #[case] with_control: bool,
+    ) {
+        let disks = if with_disks {
+            vec![
+                DiskContext {
+                    id: "scratch".into(),
+                    env: "VMTEST_DISK_SCRATCH".into(),
+                },
+                DiskContext {
+                    id: "nvme0:1".into(),
+                    env: "VMTEST_DISK_DATA".into(),
+                },
+            ]
+        } else {
+            Vec::new()
+        };
+        let context = CommandContext {
+            should_cd: true,
+            host_shared: Path::new("/mnt/vmtest"),
+            command: "echo hello",
+            command_output_port_name: COMMAND_OUTPUT_PORT_NAME,
+            user,
+            disks,
+            control_port_name: CONTROL_PORT_NAME,
+            control_script: with_control.then(ctl_script),
+        };
+        let script = get_templates().render("cmd", &context).unwrap();
+
+        let status = Command::new("bash")
+            .args(["-n", "-c", &script])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[rstest]
+    #[case("4096", Some(4096))]
+    #[case("512K", Some(512 << 10))]
+    #[case("256m", Some(256 << 20))]
+    #[case("8G", Some(8 << 30))]
+    #[case("1T", Some(1 << 40))]
+    #[case("", None)]
+    #[case("G", None)]
+    #[case("1.5G", None)]
+    #[case("-1G", None)]
+    #[case("99999999999T", None)]
+    fn test_parse_size(#[case] size: &str, #[case] expected: Option<u64>) {
+        assert_eq!(parse_size(size).ok(), expected);
+    }
+
+    #[rstest]
+    #[case(None, false)]
+    #[case(Some(RootfsOverlay::default()), false)]
+    #[case(Some(RootfsOverlay { upper: OverlayUpper::Tmpfs, size: Some("1G".into()) }), false)]
+    #[case(Some(RootfsOverlay { upper: OverlayUpper::Disk, size: None }), false)]
+    #[case(Some(RootfsOverlay::default()), true)]
+    fn test_init_template_is_valid_bash(
+        #[case] overlay: Option<RootfsOverlay>,
+        #[case] boot_share: bool,
+    ) {
+        let script = init_script(overlay.as_ref(), boot_share);
+        assert_eq!(script.contains("pivot_root"), overlay.is_some());
+        assert_eq!(
+            script.contains(&format!("mount --move {BOOT_9P_FS_MOUNT_PATH} ")),
+            boot_share
+        );
+
+        let status = Command::new("bash")
+            .args(["-n", "-c", &script])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Names of the programs `script` runs, by a rough parse of the script
+    ///
+    /// Builtins and functions the script defines are left out.
+    fn script_commands(script: &str) -> BTreeSet<String> {
+        const BUILTINS: &[&str] = &[
+            "break", "case", "cd", "continue", "done", "echo", "esac", "exit", "export", "false",
+            "fi", "for", "local", "printf", "read", "return", "set", "shift", "trap", "true",
+            "umask", "unset",
+        ];
+        // Words that may precede the command
+        const PREFIXES: &[&str] = &[
+            "!", "do", "elif", "else", "exec", "if", "then", "until", "while",
+        ];
+        let template = Regex::new(r"\{[^{}]*\}").unwrap();
+        let case_pattern = Regex::new(r"^\s*[a-z0-9*_.-]+(\|[a-z0-9*_.-]+)*\)").unwrap();
+        // Strings without substitutions or command lists are just text
+        let literal = Regex::new(r#""[^"$;`]*""#).unwrap();
+        let function = Regex::new(r"^\s*([a-z_][a-z0-9_]*)\(\)").unwrap();
+        let name = Regex::new(r"^[a-z][a-z0-9._-]*$").unwrap();
+
+        let mut defined = BTreeSet::new();
+        let mut commands = BTreeSet::new();
+        for line in script.lines().filter(|l| !l.trim_start().starts_with('#')) {
+            if let Some(c) = function.captures(line) {
+                defined.insert(c[1].to_string());
+            }
+            let line = template.replace_all(line, "");
+            let line = case_pattern.replace(&line, "");
+            let line = literal.replace_all(&line, "");
+            for segment in line.split(['|', ';', '&', '(', ')', '\'', '`']) {
+                // Options may pass a quoted command on, as in `--command "stty ..."`
+                let command = segment
+                    .split_whitespace()
+                    .map(|w| w.trim_start_matches('"'))
+                    .find(|w| !PREFIXES.contains(w) && !w.contains('=') && !w.starts_with('-'));
+                if let Some(command) = command.filter(|c| name.is_match(c)) {
+                    commands.insert(command.to_string());
+                }
+            }
+        }
+
+        commands
+            .into_iter()
+            .filter(|c| !BUILTINS.contains(&c.as_str()) && !defined.contains(c))
+            .collect()
+    }
+
+    #[test]
+    fn test_script_commands() {
+        let script = "# comment\nlog() \\{\n    echo \"$*\"\n}\nx=$(cat /f | sort) && exec setpriv \\\n    env bash -c \"$1\"\ncase \"$1\" in\n    a|b) trap 'poweroff -f' EXIT ;;\nesac\nlog hi";
+        assert_eq!(
+            script_commands(script),
+            ["cat", "env", "poweroff", "setpriv", "sort"]
+                .map(String::from)
+                .into()
+        );
+    }
+
+    /// Every program the guest runs must be put into a rootfs built from
+    /// host executables
+    #[test]
+    fn test_guest_commands_are_gathered() {
+        let gathered = rootfs::INIT_EXECUTABLES
+            .iter()
+            .chain(rootfs::OPTIONAL_EXECUTABLES)
+            .map(|e| e.to_string())
+            .collect::<BTreeSet<_>>();
+        let mut used = [
+            INIT_TEMPLATE,
+            COMMAND_TEMPLATE,
+            SHELL_TEMPLATE,
+            CTL_TEMPLATE,
+        ]
+        .iter()
+        .flat_map(|t| script_commands(t))
+        .collect::<BTreeSet<_>>();
+        // Run through the guest agent
+        used.extend(["bash", "mkdir", "mount", "sync"].map(String::from));
+        assert!(used.contains("qemu-ga"), "{used:?}");
+
+        let missing = used.difference(&gathered).collect::<Vec<_>>();
+        assert!(missing.is_empty(), "Not gathered: {missing:?}");
+    }
+
+    #[test]
+    fn test_storage_builder() {
+        let controllers = vec![
+            Controller {
+                name: "nvme0".into(),
+                kind: ControllerKind::Nvme,
+                queues: Some(4),
+            },
+            Controller {
+                name: "scsi_mq".into(),
+                kind: ControllerKind::VirtioScsi,
+                queues: Some(8),
+            },
+        ];
+        let disks = vec![
+            Disk {
+                name: "scratch".into(),
+                path: Some("/tmp/scratch.img".into()),
+                format: Some("raw".into()),
+                queues: Some(2),
+                ..Default::default()
+            },
+            Disk {
+                name: "media".into(),
+                path: Some("/images/install.iso".into()),
+                media: DiskMedia::Cdrom,
+                ..Default::default()
+            },
+            Disk {
+                name: "data".into(),
+                path: Some("/images/data.qcow2".into()),
+                readonly: true,
+                bus: Some(DiskBus::Nvme),
+                ..Default::default()
+            },
+            Disk {
+                name: "ns1".into(),
+                path: Some("/tmp/ns1.img".into()),
+                bus: Some(DiskBus::Nvme),
+                controller: Some("nvme0".into()),
+                logical_block_size: Some(4096),
+                ..Default::default()
+            },
+            Disk {
+                name: "ns2".into(),
+                path: Some("/tmp/ns2.img".into()),
+                bus: Some(DiskBus::Nvme),
+                controller: Some("nvme0".into()),
+                logical_block_size: Some(512),
+                physical_block_size: Some(4096),
+                ..Default::default()
+            },
+            Disk {
+                name: "mq".into(),
+                path: Some("/tmp/mq.img".into()),
+                bus: Some(DiskBus::VirtioScsi),
+                controller: Some("scsi_mq".into()),
+                ..Default::default()
+            },
+        ];
+
+        let mut storage = StorageBuilder::default();
+        for controller in &controllers {
+            storage.controller(controller);
+        }
+        for disk in &disks {
+            storage.disk(disk);
+        }
+        let (args, guest_disks) = storage.build();
+        let args: Vec<String> = args.into_iter().map(|a| a.into_string().unwrap()).collect();
+
+        assert_eq!(
+            args,
+            vec![
+                "-device",
+                "nvme,id=vmctrl-nvme0,serial=nvme0,max_ioqpairs=4",
+                "-device",
+                "virtio-scsi-pci,id=vmctrl-scsi_mq,num_queues=8",
+                "-drive",
+                "file=/tmp/scratch.img,if=none,id=vmdisk-scratch,format=raw",
+                "-device",
+                "virtio-blk-pci,drive=vmdisk-scratch,serial=scratch,num-queues=2",
+                "-drive",
+                "file=/images/install.iso,if=none,id=vmdisk-media,media=cdrom,readonly=on",
+                "-device",
+                "virtio-scsi-pci,id=scsi0",
+                "-device",
+                "scsi-cd,bus=scsi0.0,drive=vmdisk-media,serial=media",
+                "-drive",
+                "file=/images/data.qcow2,if=none,id=vmdisk-data,readonly=on",
+                "-device",
+                "nvme,id=vmnvme-data,serial=data",
+                "-device",
+                "nvme-ns,bus=vmnvme-data,drive=vmdisk-data,nsid=1",
+                "-drive",
+                "file=/tmp/ns1.img,if=none,id=vmdisk-ns1",
+                "-device",
+                "nvme-ns,bus=vmctrl-nvme0,drive=vmdisk-ns1,nsid=1,logical_block_size=4096",
+                "-drive",
+                "file=/tmp/ns2.img,if=none,id=vmdisk-ns2",
+                "-device",
+                "nvme-ns,bus=vmctrl-nvme0,drive=vmdisk-ns2,nsid=2,logical_block_size=512,physical_block_size=4096",
+                "-drive",
+                "file=/tmp/mq.img,if=none,id=vmdisk-mq",
+                "-device",
+                "scsi-hd,bus=vmctrl-scsi_mq.0,drive=vmdisk-mq,serial=mq",
+            ]
+        );
+
+        let ids: Vec<&str> = guest_disks.iter().map(|d| d.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec!["scratch", "media", "data:1", "nvme0:1", "nvme0:2", "mq"]
+        );
+    }
+
+    #[rstest]
+    #[case("x86_64", None, "tpm-tis,tpmdev=tpm0")]
+    #[case("x86_64", Some(TpmModel::Crb), "tpm-crb,tpmdev=tpm0")]
+    #[case("aarch64", None, "tpm-tis-device,tpmdev=tpm0")]
+    fn test_tpm_args(#[case] arch: &str, #[case] model: Option<TpmModel>, #[case] device: &str) {
+        let args = tpm_args(arch, model, Path::new("/tmp/swtpm/swtpm.sock"));
+        assert_eq!(
+            args,
+            vec![
+                "-chardev",
+                "socket,id=chrtpm,path=/tmp/swtpm/swtpm.sock",
+                "-tpmdev",
+                "emulator,id=tpm0,chardev=chrtpm",
+                "-device",
+                device,
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case("x86_64", false)]
+    #[case("x86_64", true)]
+    #[case("aarch64", false)]
+    #[case("riscv64", false)]
+    #[case("i386", false)]
+    #[case("armv7", false)]
+    #[case("loongarch64", false)]
+    fn test_firmware_candidates(#[case] arch: &str, #[case] secure_boot: bool) {
+        let candidates = firmware_candidates(arch, secure_boot);
+        assert!(!candidates.is_empty());
+        // Secure Boot is meaningless without a variable store holding the keys
+        if secure_boot {
+            assert!(candidates.iter().all(|f| f.vars.is_some()));
+        }
+    }
+
+    #[test]
+    fn test_pflash_args() {
+        let code = Path::new("/usr/share/OVMF/OVMF_CODE_4M.secboot.fd");
+        let vars = Path::new("/tmp/vmtest-uefi-vars.fd");
+
+        assert_eq!(
+            pflash_args(code, vars, false),
+            vec![
+                "-drive",
+                "if=pflash,format=raw,unit=0,readonly=on,file=/usr/share/OVMF/OVMF_CODE_4M.secboot.fd",
+                "-drive",
+                "if=pflash,format=raw,unit=1,file=/tmp/vmtest-uefi-vars.fd",
+            ]
+        );
+        assert_eq!(
+            pflash_args(code, vars, true)[4..],
+            ["-global", "driver=cfi.pflash01,property=secure,value=on"]
+        );
+    }
+
+    #[rstest]
+    #[case("x86_64", false, &[])]
+    // Secure Boot must not add a second -machine
+    #[case("x86_64", true, &["-machine", "q35,smm=on"])]
+    #[case("aarch64", false, &["-machine", "virt,gic-version=3"])]
+    fn test_machine_args(#[case] arch: &str, #[case] secure_boot: bool, #[case] expected: &[&str]) {
+        let profile = arch_profile(arch).unwrap();
+        assert_eq!(machine_args(profile, secure_boot), expected);
+    }
+
+    #[rstest]
+    #[case("4G", Some(4 << 30))]
+    #[case("512", Some(512 << 20))]
+    #[case("1T", Some(1 << 40))]
+    #[case("4GB", None)]
+    fn test_parse_memory(#[case] size: &str, #[case] expected: Option<u64>) {
+        assert_eq!(parse_memory(size).ok(), expected);
+    }
+
+    #[rstest]
+    #[case("0", Some(vec![(0, 0)]))]
+    #[case("0-3", Some(vec![(0, 3)]))]
+    #[case("0,2, 4-7", Some(vec![(0, 0), (2, 2), (4, 7)]))]
+    #[case("", None)]
+    #[case("3-1", None)]
+    #[case("a-b", None)]
+    fn test_parse_cpu_list(#[case] cpus: &str, #[case] expected: Option<Vec<(u16, u16)>>) {
+        assert_eq!(parse_cpu_list(cpus).ok(), expected);
+    }
+
+    #[test]
+    fn test_cpu_memory_args() {
+        let vm = VMConfig {
+            num_cpus: 8,
+            topology: Some(Topology {
+                sockets: 2,
+                cores: 2,
+                threads: 2,
+            }),
+            memory: "3G".into(),
+            numa: vec![
+                NumaNode {
+                    cpus: Some("0-3,6".into()),
+                    memory: "2G".into(),
+                },
+                NumaNode {
+                    cpus: Some("4-5,7".into()),
+                    memory: "1024".into(),
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            cpu_memory_args(&vm).unwrap(),
+            vec![
+                "-smp",
+                "8,sockets=2,cores=2,threads=2",
+                "-m",
+                "3G",
+                "-object",
+                "memory-backend-ram,id=numa-mem0,size=2147483648",
+                "-numa",
+                "node,nodeid=0,memdev=numa-mem0,cpus=0-3,cpus=6-6",
+                "-object",
+                "memory-backend-ram,id=numa-mem1,size=1073741824",
+                "-numa",
+                "node,nodeid=1,memdev=numa-mem1,cpus=4-5,cpus=7-7",
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case(None, "memory-backend-ram,id=mem,size=1024")]
+    #[case(
+        Some(MemoryBackend { kind: MemoryBackendKind::Memfd, ..Default::default() }),
+        "memory-backend-memfd,id=mem,size=1024"
+    )]
+    #[case(
+        Some(MemoryBackend { kind: MemoryBackendKind::Memfd, share: Some(false), ..Default::default() }),
+        "memory-backend-memfd,id=mem,size=1024,share=off"
+    )]
+    #[case(
+        Some(MemoryBackend { share: Some(true), prealloc: true, ..Default::default() }),
+        "memory-backend-ram,id=mem,size=1024,prealloc=on,share=on"
+    )]
+    #[case(
+        Some(MemoryBackend { kind: MemoryBackendKind::Hugetlbfs, prealloc: true, ..Default::default() }),
+        "memory-backend-file,id=mem,size=1024,mem-path=/dev/hugepages,prealloc=on"
+    )]
+    #[case(
+        Some(MemoryBackend { kind: MemoryBackendKind::Hugetlbfs, path: Some("/mnt/huge".into()), ..Default::default() }),
+        "memory-backend-file,id=mem,size=1024,mem-path=/mnt/huge"
+    )]
+    fn test_memory_backend_object(#[case] backend: Option<MemoryBackend>, #[case] expected: &str) {
+        assert_eq!(
+            memory_backend_object(backend.as_ref(), "mem", 1024),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_memory_backend_args() {
+        let backend = MemoryBackend {
+            kind: MemoryBackendKind::Memfd,
+            ..Default::default()
+        };
+        let vm = VMConfig {
+            memory: "1G".into(),
+            memory_backend: Some(backend.clone()),
+            ..Default::default()
+        };
+        assert_eq!(
+            cpu_memory_args(&vm).unwrap(),
+            vec![
+                "-smp",
+                "2",
+                "-m",
+                "1G",
+                "-object",
+                "memory-backend-memfd,id=vmtest-mem,size=1073741824",
+                "-machine",
+                "memory-backend=vmtest-mem",
+            ]
+        );
+
+        // NUMA nodes use the backend instead
+        let vm = VMConfig {
+            num_cpus: 1,
+            memory: "1G".into(),
+            memory_backend: Some(backend),
+            numa: vec![NumaNode {
+                cpus: Some("0".into()),
+                memory: "1G".into(),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            cpu_memory_args(&vm).unwrap(),
+            vec![
+                "-smp",
+                "1",
+                "-m",
+                "1G",
+                "-object",
+                "memory-backend-memfd,id=numa-mem0,size=1073741824",
+                "-numa",
+                "node,nodeid=0,memdev=numa-mem0,cpus=0-0",
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case("/dev/hugepages", Some(2 << 20))]
+    #[case("/mnt/huge1g", Some(1 << 30))]
+    #[case("/dev/shm", None)]
+    #[case("/nonexistent", None)]
+    fn test_hugetlbfs_page_size(#[case] path: &str, #[case] expected: Option<u64>) {
+        let mounts = "\
+sysfs /sys sysfs rw,nosuid,nodev,noexec,relatime 0 0
+tmpfs /dev/shm tmpfs rw,nosuid,nodev 0 0
+hugetlbfs /dev/hugepages hugetlbfs rw,relatime 0 0
+none /mnt/huge1g hugetlbfs rw,relatime,pagesize=1024M 0 0
+";
+        let meminfo = "MemTotal:       16316412 kB\nHugepagesize:       2048 kB\n";
+        assert_eq!(
+            hugetlbfs_page_size(mounts, meminfo, Path::new(path)).ok(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_ctl_script_is_valid_bash() {
+        let status = Command::new("bash")
+            .args(["-n", "-c", &ctl_script()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[rstest]
+    #[case(VirtioTransport::Pci, "virtio-balloon-pci,id=balloon0")]
+    #[case(VirtioTransport::Ccw, "virtio-balloon-ccw,id=balloon0")]
+    fn test_hotplug_args(#[case] transport: VirtioTransport, #[case] balloon: &str) {
+        let vm = VMConfig {
+            num_cpus: 2,
+            max_cpus: Some(4),
+            balloon: true,
+            ..Default::default()
+        };
+        let args = vmconfig_args(&vm, transport).unwrap();
+        assert_eq!(args[..2], ["-smp", "2,maxcpus=4"]);
+        assert_eq!(args[args.len() - 2..], ["-device", balloon]);
+    }
+
+    #[rstest]
+    #[case("x86_64", "x86_64")]
+    #[case("ppc64le", "ppc64le")]
+    #[case("powerpc64", "ppc64le")]
+    #[case("x86", "i386")]
+    #[case("arm", "armv7")]
+    #[case("s390x", "s390x")]
+    fn test_arch_profile(#[case] arch: &str, #[case] expected: &str) {
+        assert_eq!(arch_profile(arch).unwrap().arch, expected);
+    }
+
+    #[test]
+    fn test_unknown_arch_profile() {
+        assert!(arch_profile("mips64").is_none());
+    }
+
+    /// Start of an ELF file for `machine`
+    fn elf_stub(machine: u16, big_endian: bool) -> Vec<u8> {
+        let mut data = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x02\0".to_vec();
+        if big_endian {
+            data[5] = 2;
+            data.extend(machine.to_be_bytes());
+        } else {
+            data.extend(machine.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_check_rootfs_arch() {
+        let root = tempdir().unwrap();
+        let root = root.path();
+        std::fs::create_dir_all(root.join("usr/bin")).unwrap();
+        std::os::unix::fs::symlink("usr/bin", root.join("bin")).unwrap();
+        let aarch64 = arch_profile("aarch64").unwrap();
+        let s390x = arch_profile("s390x").unwrap();
+
+        let err = check_rootfs_arch(root, aarch64).unwrap_err();
+        assert!(err.to_string().contains("no /bin/bash"), "{err}");
+
+        std::fs::write(root.join("usr/bin/bash"), elf_stub(183, false)).unwrap();
+        let err = check_rootfs_arch(root, aarch64).unwrap_err();
+        assert!(err.to_string().contains("no qemu-ga"), "{err}");
+
+        // Wrapper scripts are not checked
+        std::fs::write(root.join("usr/bin/qemu-ga"), "#!/bin/sh\n").unwrap();
+        check_rootfs_arch(root, aarch64).unwrap();
+
+        std::fs::write(root.join("usr/bin/qemu-ga"), elf_stub(62, false)).unwrap();
+        let err = check_rootfs_arch(root, aarch64).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "usr/bin/qemu-ga in rootfs {} is built for x86_64, but the target arch is aarch64",
+                root.display()
+            )
+        );
+
+        std::fs::write(root.join("usr/bin/bash"), elf_stub(22, true)).unwrap();
+        std::fs::write(root.join("usr/bin/qemu-ga"), elf_stub(22, true)).unwrap();
+        check_rootfs_arch(root, s390x).unwrap();
+        let err = check_rootfs_arch(root, aarch64).unwrap_err();
+        assert!(err.to_string().contains("built for s390x"), "{err}");
+    }
+
+    #[rstest]
+    #[case("x86_64", Some("earlyprintk=serial,0,115200"), "console=0,115200")]
+    #[case("i386", Some("earlyprintk=serial,0,115200"), "console=0,115200")]
+    #[case("aarch64", Some("earlycon=pl011,0x9000000"), "console=ttyAMA0,115200")]
+    #[case("ppc64le", None, "console=hvc0")]
+    #[case("s390x", None, "console=ttysclp0")]
+    #[case(
+        "loongarch64",
+        Some("earlycon=uart,mmio,0x1fe001e0"),
+        "console=ttyS0,115200"
+    )]
+    fn test_kernel_console_args(
+        #[case] arch: &str,
+        #[case] earlycon: Option<&str>,
+        #[case] console: &str,
+    ) {
+        let args = kernel_args(
+            Path::new("/boot/vmlinuz"),
+            arch_profile(arch).unwrap(),
+            KernelRoot::Plan9,
+            Path::new("/tmp/init.sh"),
+            false,
+            None,
+        );
+        let cmdline = args[args.len() - 1].to_str().unwrap();
+        let words: Vec<&str> = cmdline.split(' ').collect();
+        assert!(words.contains(&console));
+        assert_eq!(
+            words
+                .iter()
+                .find(|w| w.starts_with("earlycon") || w.starts_with("earlyprintk")),
+            earlycon.as_ref()
+        );
+    }
+
+    #[test]
+    fn test_kernel_image_root_args() {
+        let args = kernel_args(
+            Path::new("/boot/vmlinuz"),
+            arch_profile("x86_64").unwrap(),
+            KernelRoot::Image("squashfs"),
+            Path::new("/mnt/init.sh"),
+            false,
+            Some("quiet"),
+        );
+        let cmdline = args[args.len() - 1].to_str().unwrap();
+        assert!(cmdline.starts_with("root=/dev/vda rootfstype=squashfs ro "));
+        assert!(cmdline.contains(" init=/bin/bash "));
+        // Init arguments must follow all kernel arguments
+        assert!(cmdline.ends_with(&format!(
+            " quiet -- -c \"mount -t 9p -o {MOUNT_OPTS_9P_FS} {BOOT_9P_FS_MOUNT_TAG} /mnt && exec /mnt/init.sh\""
+        )));
+    }
+
+    #[test]
+    fn test_storage_builder_ccw() {
+        let mut storage = StorageBuilder::new(VirtioTransport::Ccw);
+        storage.disk(&Disk {
+            name: "blk".into(),
+            path: Some("/tmp/blk.img".into()),
+            ..Default::default()
+        });
+        storage.disk(&Disk {
+            name: "scsi".into(),
+            path: Some("/tmp/scsi.img".into()),
+            bus: Some(DiskBus::VirtioScsi),
+            ..Default::default()
+        });
+
+        let (args, _) = storage.build();
+        let devices: Vec<_> = args
+            .chunks(2)
+            .filter(|a| a[0] == "-device")
+            .map(|a| a[1].to_str().unwrap())
+            .collect();
+        assert_eq!(
+            devices,
+            vec![
+                "virtio-blk-ccw,drive=vmdisk-blk,serial=blk",
+                "virtio-scsi-ccw,id=scsi0",
+                "scsi-hd,bus=scsi0.0,drive=vmdisk-scsi,serial=scsi",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_canceller() {
+        let canceller = Canceller::default();
+        let child = SharedChild::new(Command::new("sleep").arg("600").spawn().unwrap());
+        let sock = Path::new("/nonexistent/cancel.sock");
+        canceller.attach(sock, &child).unwrap();
+
+        // No QMP to quit through, so QEMU gets killed
+        canceller.cancel();
+        assert!(canceller.is_cancelled());
+        assert!(child.wait().unwrap().code().is_none());
+
+        // Too late to boot anything else
+        canceller.detach();
+        assert!(canceller.clone().attach(sock, &child).is_err());
+        // The process has been reaped, so there is nothing left to kill
+        child.kill().unwrap();
+    }
+
+    #[test]
+    fn test_canceller_in_background() {
+        let canceller = Canceller::default();
+        let child = SharedChild::new(Command::new("sleep").arg("600").spawn().unwrap());
+        canceller
+            .attach(Path::new("/nonexistent/cancel.sock"), &child)
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        canceller.cancel_in_background();
+        assert!(canceller.is_cancelled());
+        assert!(start.elapsed() < CANCEL_TIMEOUT);
+        // QEMU still gets killed eventually
+        assert!(child.wait().unwrap().code().is_none());
+    }
+
+    /// Reader returning at most `chunk` bytes at a time
+    struct Chunked<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[rstest]
+    #[case(1)]
+    #[case(3)]
+    #[case(4096)]
+    fn test_copy_until_end(#[case] chunk: usize) {
+        let data = b"$ echo\0\x1b]0;title\x07\r\n\0end\nafter";
+        let mut out = Vec::new();
+        copy_until_end(&mut Chunked { data, chunk }, &mut out, "end").unwrap();
+        assert_eq!(out, b"$ echo\0\x1b]0;title\x07\r\n");
+
+        // Without a marker, everything is copied
+        let mut out = Vec::new();
+        copy_until_end(&mut &b"no marker\0en"[..], &mut out, "end").unwrap();
+        assert_eq!(out, b"no marker\0en");
+    }
+
+    #[test]
+    fn test_copy_until_stopped() {
+        let (mut input, fd) = UnixStream::pair().unwrap();
+        let (mut sink, output) = UnixStream::pair().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_copy = stop.clone();
+        let copy = thread::spawn(move || {
+            copy_until_stopped(fd.as_raw_fd(), &mut sink, &stop_copy).unwrap();
+        });
+
+        input.write_all(b"typed").unwrap();
+        let mut buf = [0; 5];
+        (&output).read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"typed");
+
+        // Stopping does not need more input to arrive
+        stop.store(true, Ordering::Relaxed);
+        copy.join().unwrap();
+    }
+
+    #[test]
+    fn test_stream_command_output() {
+        let (mut guest, host) = UnixStream::pair().unwrap();
+        let (sender, receiver) = channel();
+        let streamer = stream_command_output(
+            host,
+            move |line| sender.send(line).unwrap(),
+            "end".to_string(),
+        );
+        // The guest side is kept open, so only the marker ends the stream
+        guest
+            .write_all(b"first\nno newline\0end\nignored\n")
+            .unwrap();
+        streamer.join().unwrap();
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), ["first", "no newline"]);
+    }
 }
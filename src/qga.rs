@@ -1,6 +1,5 @@
 use std::os::unix::net::UnixStream;
 use std::path::Path;
-use std::process::Child;
 use std::str::FromStr;
 use std::thread;
 use std::time::{Duration, Instant};
@@ -10,6 +9,8 @@ use log::{debug, error, info, warn};
 use qapi::{qga, Command as QapiCommand, Qga};
 use rand::Rng;
 
+use crate::qemu::SharedChild;
+
 const KVM_TIMEOUT: Duration = Duration::from_secs(80);
 const EMULATE_TIMEOUT: Duration = Duration::from_secs(120);
 
@@ -58,7 +59,7 @@ impl QgaWrapper {
     /// `sock` is the path to the QGA socket.
     /// `has_kvm` whether or not host supports KVM
     /// `qemu` is the guest VM
-    pub fn new(sock: &Path, has_kvm: bool, qemu: &mut Child) -> Result<Self> {
+    pub fn new(sock: &Path, has_kvm: bool, qemu: &SharedChild) -> Result<Self> {
         let timeout = if has_kvm {
             KVM_TIMEOUT
         } else {
@@ -0,0 +1,350 @@
+use std::fmt;
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error, Result};
+
+use crate::output::{Output, OutputSink};
+
+/// A stage of a target run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Booting the VM
+    Boot,
+    /// Setting up the VM
+    Setup,
+    /// Running the target command
+    Command,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Stage::Boot => "boot",
+            Stage::Setup => "setup",
+            Stage::Command => "command",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// Wall-clock time spent in each stage of a run
+///
+/// A stage is only timed if it completed successfully.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Spawning QEMU until its sockets are ready
+    pub spawn: Option<Duration>,
+    /// Booting the guest until its agent is ready
+    pub boot: Option<Duration>,
+    /// Setting up the VM, mostly mounting shared directories
+    pub setup: Option<Duration>,
+    /// Running the command
+    pub command: Option<Duration>,
+}
+
+/// Format `d` in seconds the way vmtest reports durations
+fn secs(d: Duration) -> String {
+    format!("{:.2}s", d.as_secs_f64())
+}
+
+impl fmt::Display for Timings {
+    /// Lists the timed stages, e.g. `spawn 0.05s, boot 1.20s`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stages = [
+            ("spawn", self.spawn),
+            ("boot", self.boot),
+            ("setup", self.setup),
+            ("command", self.command),
+        ];
+        let timed = stages
+            .iter()
+            .filter_map(|(name, d)| d.map(|d| format!("{name} {}", secs(d))))
+            .collect::<Vec<_>>();
+        write!(f, "{}", timed.join(", "))
+    }
+}
+
+impl Timings {
+    /// Whether or not no stage was timed
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// How long it took from spawning QEMU until the guest was ready
+    pub fn boot_total(&self) -> Option<Duration> {
+        Some(self.spawn? + self.boot?)
+    }
+}
+
+/// Summary of repeated measurements
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats {
+    /// Fastest measurement
+    pub min: Duration,
+    /// Median measurement
+    pub median: Duration,
+    /// 95th percentile measurement
+    pub p95: Duration,
+}
+
+/// Nearest-rank `p`th percentile of the sorted, non-empty `samples`
+fn percentile(samples: &[Duration], p: usize) -> Duration {
+    let rank = ((p * samples.len() + 99) / 100).max(1);
+    samples[rank - 1]
+}
+
+impl Stats {
+    /// Summarize `samples`. Returns `None` if there are none.
+    pub fn new(samples: &[Duration]) -> Option<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        Some(Self {
+            min: *sorted.first()?,
+            median: percentile(&sorted, 50),
+            p95: percentile(&sorted, 95),
+        })
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min {}, median {}, p95 {}",
+            secs(self.min),
+            secs(self.median),
+            secs(self.p95)
+        )
+    }
+}
+
+/// Why a run failed
+#[derive(Debug)]
+pub struct Failure {
+    /// Stage the run failed in
+    pub stage: Stage,
+    /// What went wrong
+    ///
+    /// This may be a copy of the error sent through the run's updates, in
+    /// which case it cannot be downcast.
+    pub error: Error,
+}
+
+/// Outcome of a single target run
+///
+/// A command exiting non-zero is not a failure: the run succeeded in running
+/// it and its exit code is in `exit_code`. `failure` is only set if the VM
+/// could not run the command to completion.
+#[derive(Debug, Default)]
+pub struct RunResult {
+    /// How long each stage took
+    pub timings: Timings,
+    /// Exit code of the command, if it ran to completion
+    pub exit_code: Option<i64>,
+    /// Set if the run failed
+    pub failure: Option<Failure>,
+    /// Output of the command, one entry per line
+    pub output: Vec<String>,
+    /// Output of the VM's console, one entry per line
+    ///
+    /// Only captured for targets that run a command.
+    pub console: Vec<String>,
+    /// How QEMU exited, if it was waited on
+    ///
+    /// QEMU is not waited on if it was killed after an error.
+    pub qemu_status: Option<ExitStatus>,
+}
+
+impl RunResult {
+    /// Whether or not the command ran and exited with code 0
+    pub fn success(&self) -> bool {
+        self.failure.is_none() && self.exit_code == Some(0)
+    }
+
+    /// How long `stage` took, if it finished
+    ///
+    /// Booting includes spawning QEMU.
+    pub fn duration(&self, stage: Stage) -> Option<Duration> {
+        match stage {
+            Stage::Boot => self.timings.boot_total(),
+            Stage::Setup => self.timings.setup,
+            Stage::Command => self.timings.command,
+        }
+    }
+
+    /// The exit code of the command, or the error that kept it from running
+    pub fn into_result(self) -> Result<i64> {
+        match (self.failure, self.exit_code) {
+            (Some(f), _) => Err(f.error),
+            (None, Some(rc)) => Ok(rc),
+            (None, None) => Err(anyhow!("Run finished without running the command")),
+        }
+    }
+}
+
+/// Builds a [`RunResult`] out of a run's updates
+#[derive(Default)]
+pub(crate) struct Recorder {
+    inner: Mutex<RecorderState>,
+}
+
+#[derive(Default)]
+struct RecorderState {
+    result: RunResult,
+}
+
+impl RecorderState {
+    /// Note that `stage` ended with `r`
+    fn end<T>(&mut self, stage: Stage, r: Result<T>) {
+        // Only the first error is of interest, the rest is fallout
+        if let Err(error) = r {
+            if self.result.failure.is_none() {
+                self.result.failure = Some(Failure { stage, error })
+            }
+        }
+    }
+}
+
+impl Recorder {
+    /// Take the result recorded so far
+    ///
+    /// `qemu_status` is how QEMU exited, if known.
+    pub fn finish(&self, qemu_status: Option<ExitStatus>) -> RunResult {
+        let mut inner = self.inner.lock().unwrap();
+        let mut result = std::mem::take(&mut inner.result);
+        result.qemu_status = qemu_status;
+        result
+    }
+}
+
+impl OutputSink for Recorder {
+    fn send(&self, output: Output) {
+        let mut inner = self.inner.lock().unwrap();
+        match output {
+            Output::BootStart | Output::SetupStart | Output::CommandStart => (),
+            Output::Boot(s) => inner.result.console.push(s),
+            Output::Setup(_) => (),
+            Output::Command(s) => inner.result.output.push(s),
+            Output::BootEnd(r) => inner.end(Stage::Boot, r),
+            Output::SetupEnd(r) => inner.end(Stage::Setup, r),
+            Output::CommandEnd(r) => {
+                inner.result.exit_code = r.as_ref().ok().copied();
+                inner.end(Stage::Command, r);
+            }
+            Output::Timings(t) => inner.result.timings = t,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn timings() -> Timings {
+        Timings {
+            spawn: Some(ms(500)),
+            boot: Some(ms(1000)),
+            setup: Some(ms(100)),
+            command: Some(ms(4000)),
+        }
+    }
+
+    #[test]
+    fn test_timings_display() {
+        assert_eq!(
+            timings().to_string(),
+            "spawn 0.50s, boot 1.00s, setup 0.10s, command 4.00s"
+        );
+        let partial = Timings {
+            spawn: Some(ms(50)),
+            ..Default::default()
+        };
+        assert_eq!(partial.to_string(), "spawn 0.05s");
+        assert!(partial.boot_total().is_none());
+        assert!(Timings::default().is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        assert!(Stats::new(&[]).is_none());
+
+        let one = Stats::new(&[ms(7)]).unwrap();
+        assert_eq!((one.min, one.median, one.p95), (ms(7), ms(7), ms(7)));
+
+        // Order of the samples does not matter
+        let samples = (1..=20).rev().map(ms).collect::<Vec<_>>();
+        let stats = Stats::new(&samples).unwrap();
+        assert_eq!(stats.min, ms(1));
+        assert_eq!(stats.median, ms(10));
+        assert_eq!(stats.p95, ms(19));
+        assert_eq!(stats.to_string(), "min 0.00s, median 0.01s, p95 0.02s");
+    }
+
+    #[test]
+    fn test_recorder() {
+        let recorder = Recorder::default();
+        recorder.send(Output::BootStart);
+        recorder.send(Output::Boot("booting".into()));
+        recorder.send(Output::BootEnd(Ok(())));
+        recorder.send(Output::SetupStart);
+        recorder.send(Output::SetupEnd(Ok(())));
+        recorder.send(Output::CommandStart);
+        recorder.send(Output::Command("hello".into()));
+        recorder.send(Output::CommandEnd(Ok(3)));
+        // Console output may trail the command
+        recorder.send(Output::Boot("shutting down".into()));
+        recorder.send(Output::Timings(timings()));
+
+        let result = recorder.finish(None);
+        assert!(!result.success());
+        assert!(result.failure.is_none());
+        assert_eq!(result.exit_code, Some(3));
+        assert_eq!(result.timings, timings());
+        assert_eq!(result.duration(Stage::Boot), Some(ms(1500)));
+        assert_eq!(result.duration(Stage::Command), Some(ms(4000)));
+        assert_eq!(result.output, ["hello"]);
+        assert_eq!(result.console, ["booting", "shutting down"]);
+        assert_eq!(result.into_result().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_recorder_failure() {
+        let recorder = Recorder::default();
+        recorder.send(Output::BootStart);
+        recorder.send(Output::BootEnd(Ok(())));
+        recorder.send(Output::SetupStart);
+        recorder.send(Output::SetupEnd(
+            Err(anyhow!("root cause")).context("Failed to mount"),
+        ));
+
+        let result = recorder.finish(None);
+        assert!(!result.success());
+        assert!(result.timings.is_empty());
+        assert!(result.exit_code.is_none());
+        let failure = result.failure.as_ref().unwrap();
+        assert_eq!(failure.stage, Stage::Setup);
+        assert_eq!(
+            format!("{:#}", failure.error),
+            "Failed to mount: root cause"
+        );
+    }
+
+    #[test]
+    fn test_recorder_no_command() {
+        let recorder = Recorder::default();
+        // Setting up QEMU may fail before boot even starts
+        recorder.send(Output::BootEnd(Err(anyhow!("no kernel"))));
+
+        let result = recorder.finish(None);
+        assert!(result.duration(Stage::Boot).is_none());
+        assert_eq!(result.failure.as_ref().unwrap().stage, Stage::Boot);
+        assert!(result.into_result().is_err());
+    }
+}
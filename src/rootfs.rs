@@ -0,0 +1,977 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use tempfile::Builder;
+
+use crate::cache::{cache_dir, CacheKey};
+use crate::elf;
+
+/// File name suffixes of rootfs tarballs
+const TARBALL_SUFFIXES: &[&str] = &[
+    ".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz", ".tar.bz2", ".tar.zst",
+];
+
+/// Directories the guest mounts filesystems over during boot
+const MOUNT_POINTS: &[&str] = &["dev", "proc", "run", "sys", "tmp"];
+
+/// Executables init needs, which every rootfs built from host executables gets
+pub(crate) const INIT_EXECUTABLES: &[&str] = &[
+    "bash", "basename", "cat", "grep", "ln", "mkdir", "mount", "poweroff", "qemu-ga", "rmdir",
+    "setsid", "umount",
+];
+/// Executables some vmtest features need, included if the host has them
+pub(crate) const OPTIONAL_EXECUTABLES: &[&str] = &[
+    "chmod",
+    "chown",
+    "chroot",
+    "comm",
+    "cut",
+    "env",
+    "flock",
+    "getent",
+    "ls",
+    "mkfs.ext4",
+    "mktemp",
+    "pivot_root",
+    "script",
+    "seq",
+    "setpriv",
+    "sleep",
+    "sort",
+    "stty",
+    "sync",
+    "tail",
+    "tr",
+];
+/// Paths init runs executables from directly rather than through `$PATH`
+const INIT_PATHS: &[&str] = &["/bin/bash", "/bin/mount"];
+/// Directories the dynamic linker always searches
+const DEFAULT_LIB_DIRS: &[&str] = &["/lib64", "/usr/lib64", "/lib", "/usr/lib"];
+const LD_SO_CONF: &str = "/etc/ld.so.conf";
+/// Users and groups of a rootfs built from host executables
+const HOST_ROOTFS_PASSWD: &str =
+    "root:x:0:0:root:/:/bin/bash\nnobody:x:65534:65534:nobody:/:/bin/bash\n";
+const HOST_ROOTFS_GROUP: &str = "root:x:0:\nnogroup:x:65534:\n";
+
+/// Maximum number of symlinks followed by [`resolve`], like the kernel's
+const MAX_SYMLINKS: usize = 40;
+
+/// Whether `path` names a rootfs tarball rather than a directory
+pub fn is_tarball(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    path.is_file() && TARBALL_SUFFIXES.iter().any(|s| name.ends_with(s))
+}
+
+/// Offset of the erofs superblock magic
+const EROFS_MAGIC_OFFSET: usize = 1024;
+const EROFS_MAGIC: &[u8] = &[0xe2, 0xe1, 0xf5, 0xe0];
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+
+/// Name of the file marking a directory as an OCI image layout
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+/// Prefix of whiteout entries in OCI layers
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Whiteout entry that hides everything below its directory in lower layers
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// A content descriptor in an OCI image index or manifest
+#[derive(Deserialize, Debug, Clone)]
+struct Descriptor {
+    #[serde(rename = "mediaType", default)]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+}
+
+/// The platform an OCI image manifest is built for
+#[derive(Deserialize, Debug, Clone)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+/// An OCI image index, including the layout's `index.json`
+#[derive(Deserialize, Debug)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+/// An OCI image manifest
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    layers: Vec<Descriptor>,
+}
+
+impl Descriptor {
+    /// Whether this descriptor points at another index rather than a manifest
+    fn is_index(&self) -> bool {
+        self.media_type.ends_with("image.index.v1+json")
+            || self.media_type.ends_with("manifest.list.v2+json")
+    }
+
+    /// Whether the content runs on the OCI architecture `arch`
+    fn matches(&self, arch: &str) -> bool {
+        self.platform
+            .as_ref()
+            .map_or(true, |p| p.os == "linux" && p.architecture == arch)
+    }
+}
+
+/// Whether `path` is an OCI image layout directory
+pub fn is_oci_layout(path: &Path) -> bool {
+    path.join(OCI_LAYOUT_FILE).is_file()
+}
+
+/// Filesystem type of `path` if it is a squashfs or erofs image
+pub fn image_fstype(path: &Path) -> Option<&'static str> {
+    if !path.is_file() {
+        return None;
+    }
+    let mut data = Vec::with_capacity(EROFS_MAGIC_OFFSET + EROFS_MAGIC.len());
+    File::open(path)
+        .ok()?
+        .take((EROFS_MAGIC_OFFSET + EROFS_MAGIC.len()) as u64)
+        .read_to_end(&mut data)
+        .ok()?;
+
+    if data.starts_with(SQUASHFS_MAGIC) {
+        Some("squashfs")
+    } else if data.get(EROFS_MAGIC_OFFSET..) == Some(EROFS_MAGIC) {
+        Some("erofs")
+    } else {
+        None
+    }
+}
+
+/// Hash of the contents of `path`
+fn content_hash(path: &Path) -> Result<String> {
+    Ok(CacheKey::default().add_contents(path)?.finish())
+}
+
+/// Extract `archive` into `dest` with `tar`
+///
+/// Compression is detected by `tar`.
+fn untar(archive: &Path, dest: &Path, excludes: &[&str]) -> Result<()> {
+    // Device nodes cannot be created without privileges and the guest
+    // mounts devtmpfs over /dev anyway
+    let out = Command::new("tar")
+        .arg("-x")
+        .arg("-f")
+        .arg(archive)
+        .arg("-C")
+        .arg(dest)
+        .args(["--exclude=./dev/*", "--exclude=dev/*"])
+        .args(excludes.iter().map(|e| format!("--exclude={e}")))
+        .output()
+        .context("Failed to run tar")?;
+    if !out.status.success() {
+        bail!(
+            "Failed to unpack {}: {}",
+            archive.display(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Return the directory `name` under `cache`, filling it with `fill` first
+/// if it does not exist yet
+fn unpack_cached<F>(cache: &Path, name: &str, fill: F) -> Result<PathBuf>
+where
+    F: FnOnce(&Path) -> Result<()>,
+{
+    let dir = cache.join(name);
+    if dir.is_dir() {
+        debug!("Reusing unpacked rootfs: {}", dir.display());
+        return Ok(dir);
+    }
+
+    // Unpack next to the final location so that concurrent runs never see
+    // a partially unpacked rootfs
+    let tmp = Builder::new()
+        .prefix(".unpack")
+        .tempdir_in(cache)
+        .context("Failed to create rootfs unpack directory")?;
+    debug!("Unpacking rootfs into {}", dir.display());
+    fill(tmp.path())?;
+    for mount_point in MOUNT_POINTS {
+        let path = tmp.path().join(mount_point);
+        if fs::symlink_metadata(&path).is_err() {
+            fs::create_dir(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+        }
+    }
+
+    match fs::rename(tmp.path(), &dir) {
+        Ok(_) => {
+            // The directory was moved out from under the guard
+            let _ = tmp.into_path();
+        }
+        // Another run unpacked the same rootfs first
+        Err(_) if dir.is_dir() => (),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to move unpacked rootfs to {}", dir.display()))
+        }
+    }
+
+    Ok(dir)
+}
+
+/// Unpack `tarball` into a directory under `cache` named after its contents
+fn unpack_tarball(tarball: &Path, cache: &Path) -> Result<PathBuf> {
+    let name = format!("tar-{}", content_hash(tarball)?);
+    unpack_cached(cache, &name, |dir| untar(tarball, dir, &[]))
+}
+
+/// Path of the blob with `digest` in the OCI image layout at `layout`
+fn oci_blob(layout: &Path, digest: &str) -> Result<PathBuf> {
+    match digest.split_once(':') {
+        Some((algorithm, hex))
+            if !algorithm.is_empty()
+                && !hex.is_empty()
+                && digest
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == ':') =>
+        {
+            Ok(layout.join("blobs").join(algorithm).join(hex))
+        }
+        _ => bail!("Invalid OCI digest '{digest}'"),
+    }
+}
+
+/// Read and parse the JSON blob with `digest`
+fn read_oci_json<T: DeserializeOwned>(layout: &Path, digest: &str) -> Result<T> {
+    let path = oci_blob(layout, digest)?;
+    let data =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&data).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Find the manifest for the OCI architecture `arch` in the image layout
+fn select_oci_manifest(layout: &Path, arch: &str) -> Result<Descriptor> {
+    let path = layout.join("index.json");
+    let data =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut index: Index = serde_json::from_str(&data)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    loop {
+        let descriptor = match index.manifests.iter().find(|d| d.matches(arch)) {
+            Some(d) => d.clone(),
+            None => bail!(
+                "OCI image {} has no manifest for linux/{arch}",
+                layout.display()
+            ),
+        };
+        if !descriptor.is_index() {
+            return Ok(descriptor);
+        }
+        index = read_oci_json(layout, &descriptor.digest)?;
+    }
+}
+
+/// Remove `path` whatever kind of file it is
+fn remove_all(path: &Path) -> Result<()> {
+    let res = match fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e),
+    };
+    res.with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+/// Apply the whiteouts in `layer` to the layers already unpacked in `dir`
+fn apply_whiteouts(layer: &Path, dir: &Path) -> Result<()> {
+    let out = Command::new("tar")
+        .arg("-t")
+        .arg("-f")
+        .arg(layer)
+        .output()
+        .context("Failed to run tar")?;
+    if !out.status.success() {
+        bail!(
+            "Failed to list {}: {}",
+            layer.display(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    for entry in String::from_utf8_lossy(&out.stdout).lines() {
+        let entry = Path::new(entry);
+        let name = match entry.file_name().and_then(|n| n.to_str()) {
+            Some(n) if n.starts_with(WHITEOUT_PREFIX) => n,
+            _ => continue,
+        };
+        let parent = entry.parent().unwrap_or(Path::new(""));
+        if parent
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            bail!(
+                "Invalid whiteout '{}' in {}",
+                entry.display(),
+                layer.display()
+            );
+        }
+        // Lower layers may have turned the parent into a symlink, which must
+        // be followed as the guest would so it cannot lead out of `dir`
+        let parent = match resolve(dir, parent) {
+            Some(parent) if parent.is_dir() => parent,
+            _ => continue,
+        };
+
+        if name == OPAQUE_WHITEOUT {
+            if let Ok(entries) = fs::read_dir(&parent) {
+                for e in entries {
+                    remove_all(&e?.path())?;
+                }
+            }
+        } else {
+            remove_all(&parent.join(&name[WHITEOUT_PREFIX.len()..]))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Unpack the image for the OCI architecture `arch` in the OCI image layout
+/// at `layout` into a directory under `cache` named after its manifest
+fn unpack_oci(layout: &Path, arch: &str, cache: &Path) -> Result<PathBuf> {
+    let descriptor = select_oci_manifest(layout, arch)?;
+    let manifest: Manifest = read_oci_json(layout, &descriptor.digest)?;
+    let name = format!("oci-{}", descriptor.digest.replace(':', "-"));
+
+    unpack_cached(cache, &name, |dir| {
+        for layer in &manifest.layers {
+            let blob = oci_blob(layout, &layer.digest)?;
+            apply_whiteouts(&blob, dir)?;
+            untar(&blob, dir, &[".wh.*"])?;
+        }
+        Ok(())
+    })
+}
+
+/// Whether `path` is an executable file
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Look up the executable `name` in the host's `$PATH`
+pub fn which(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|p| is_executable(p))
+}
+
+/// Library directories listed in the ld.so.conf at `path`
+fn ld_so_conf_dirs(path: &Path, depth: usize) -> Vec<PathBuf> {
+    let file = match File::open(path) {
+        Ok(f) if depth < MAX_SYMLINKS => f,
+        _ => return Vec::new(),
+    };
+
+    let mut dirs = Vec::new();
+    for line in BufReader::new(file).lines().map_while(|l| l.ok()) {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(pattern) = line.strip_prefix("include") {
+            let pattern = path.parent().unwrap_or(Path::new("/")).join(pattern.trim());
+            // Only a wildcard in the file name is supported, like `*.conf`
+            let (dir, name) = match (pattern.parent(), pattern.file_name()) {
+                (Some(d), Some(n)) => (d, n.to_string_lossy()),
+                _ => continue,
+            };
+            let (prefix, suffix) = name.split_once('*').unwrap_or((&name, ""));
+            let mut includes: Vec<PathBuf> = match fs::read_dir(dir) {
+                Ok(entries) => entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.path())
+                    .filter(|p| {
+                        let n = p.file_name().unwrap_or_default().to_string_lossy();
+                        if name.contains('*') {
+                            n.starts_with(prefix) && n.ends_with(suffix)
+                        } else {
+                            n == name
+                        }
+                    })
+                    .collect(),
+                Err(_) => continue,
+            };
+            includes.sort();
+            for include in includes {
+                dirs.extend(ld_so_conf_dirs(&include, depth + 1));
+            }
+        } else if line.starts_with('/') {
+            dirs.push(PathBuf::from(line));
+        }
+    }
+
+    dirs
+}
+
+/// Executables and libraries to copy into a rootfs built from the host
+struct HostFiles {
+    /// Directories shared libraries are searched in after the runpath
+    lib_dirs: Vec<PathBuf>,
+    /// Host paths of all files gathered so far
+    files: BTreeSet<PathBuf>,
+}
+
+impl HostFiles {
+    fn new() -> Self {
+        let mut lib_dirs: Vec<PathBuf> = env::var_os("LD_LIBRARY_PATH")
+            .map(|p| env::split_paths(&p).collect())
+            .unwrap_or_default();
+        lib_dirs.extend(ld_so_conf_dirs(Path::new(LD_SO_CONF), 0));
+        lib_dirs.extend(DEFAULT_LIB_DIRS.iter().map(PathBuf::from));
+
+        Self {
+            lib_dirs,
+            files: BTreeSet::new(),
+        }
+    }
+
+    /// Find the library `name` needed by an ELF file of `header` in `origin`
+    fn find_library(
+        &self,
+        name: &str,
+        runpath: &[String],
+        origin: &Path,
+        header: &elf::Header,
+    ) -> Option<PathBuf> {
+        if name.contains('/') {
+            return Some(PathBuf::from(name));
+        }
+
+        let origin = origin.to_string_lossy();
+        runpath
+            .iter()
+            .map(|dir| {
+                PathBuf::from(
+                    dir.replace("${ORIGIN}", &origin)
+                        .replace("$ORIGIN", &origin),
+                )
+            })
+            .chain(self.lib_dirs.iter().cloned())
+            .map(|dir| dir.join(name))
+            // Multilib hosts keep libraries for other ABIs around as well
+            .find(|p| p.is_file() && elf::read_header(p).ok().flatten().as_ref() == Some(header))
+    }
+
+    /// Gather `path` and everything it needs to run
+    fn add(&mut self, path: &Path) -> Result<()> {
+        if !self.files.insert(path.to_path_buf()) {
+            return Ok(());
+        }
+        let real = fs::canonicalize(path)
+            .with_context(|| format!("Failed to resolve {}", path.display()))?;
+
+        let dynamic = match elf::read_dynamic(&real)? {
+            Some(d) => d,
+            None => {
+                // Scripts need their interpreter
+                let file = File::open(&real)
+                    .with_context(|| format!("Failed to open {}", real.display()))?;
+                let mut line = String::new();
+                let _ = BufReader::new(file).take(256).read_line(&mut line);
+                if let Some(interp) = line
+                    .strip_prefix("#!")
+                    .and_then(|l| l.split_whitespace().next())
+                {
+                    self.add(Path::new(interp))?;
+                }
+                return Ok(());
+            }
+        };
+        let header = elf::read_header(&real)?.expect("ELF file has a header");
+
+        if let Some(interp) = &dynamic.interp {
+            self.add(Path::new(interp))?;
+        }
+        let origin = real.parent().unwrap_or(Path::new("/"));
+        for name in &dynamic.needed {
+            let lib = self
+                .find_library(name, &dynamic.runpath, origin, &header)
+                .ok_or_else(|| anyhow!("Failed to find {name}, which {} needs", path.display()))?;
+            self.add(&lib)?;
+        }
+
+        Ok(())
+    }
+
+    /// Cache key of the gathered files
+    ///
+    /// Changes whenever any of the files is replaced or modified, or the
+    /// accounts written into the rootfs change.
+    fn key(&self) -> Result<String> {
+        let mut key = CacheKey::default();
+        for file in &self.files {
+            key.add(file.as_os_str().as_bytes()).add_metadata(file)?;
+        }
+        key.add(HOST_ROOTFS_PASSWD).add(HOST_ROOTFS_GROUP);
+
+        Ok(key.finish())
+    }
+}
+
+/// Copy the host file at `path` into `root` at the same path
+///
+/// Symlinks along the way are recreated, so that the file is reachable
+/// through the same paths as on the host.
+fn mirror(path: &Path, root: &Path, depth: usize) -> Result<()> {
+    if depth > MAX_SYMLINKS {
+        bail!("Too many levels of symlinks in {}", path.display());
+    }
+
+    let mut host = PathBuf::from("/");
+    let mut components = path.components();
+    while let Some(component) = components.next() {
+        match component {
+            Component::Normal(name) => host.push(name),
+            Component::ParentDir => {
+                host.pop();
+                continue;
+            }
+            _ => continue,
+        }
+        let dest = root.join(host.strip_prefix("/").unwrap());
+        let metadata = fs::symlink_metadata(&host)
+            .with_context(|| format!("Failed to stat {}", host.display()))?;
+        let exists = fs::symlink_metadata(&dest).is_ok();
+
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&host)
+                .with_context(|| format!("Failed to read link {}", host.display()))?;
+            if !exists {
+                symlink(&target, &dest)
+                    .with_context(|| format!("Failed to create symlink {}", dest.display()))?;
+            }
+            // Continue from wherever the link points to
+            let rest: PathBuf = components.collect();
+            let resolved = host.parent().unwrap_or(Path::new("/")).join(target);
+            return mirror(&resolved.join(rest), root, depth + 1);
+        } else if exists {
+            continue;
+        } else if metadata.is_dir() {
+            fs::create_dir(&dest)
+                .with_context(|| format!("Failed to create {}", dest.display()))?;
+        } else {
+            fs::copy(&host, &dest).with_context(|| format!("Failed to copy {}", host.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Gather `executables` and everything init needs, plus their dependencies
+fn gather_host_files(executables: &[PathBuf]) -> Result<HostFiles> {
+    let mut files = HostFiles::new();
+    for name in INIT_EXECUTABLES {
+        let path = which(name).ok_or_else(|| {
+            anyhow!("Failed to find {name} in $PATH, which the guest needs to boot")
+        })?;
+        files.add(&path)?;
+    }
+    for path in OPTIONAL_EXECUTABLES.iter().filter_map(|name| which(name)) {
+        files.add(&path)?;
+    }
+    for path in INIT_PATHS.iter().map(Path::new).filter(|p| p.exists()) {
+        files.add(path)?;
+    }
+    for path in executables {
+        files.add(path)?;
+    }
+
+    Ok(files)
+}
+
+/// Build a rootfs from `executables` under `cache`
+fn build_host_rootfs(executables: &[PathBuf], cache: &Path) -> Result<PathBuf> {
+    let files = gather_host_files(executables)?;
+    let name = format!("host-{}", files.key()?);
+
+    unpack_cached(cache, &name, |dir| {
+        for file in &files.files {
+            mirror(file, dir, 0)?;
+        }
+        fs::create_dir_all(dir.join("etc")).context("Failed to create /etc")?;
+        fs::write(dir.join("etc/passwd"), HOST_ROOTFS_PASSWD)
+            .context("Failed to write /etc/passwd")?;
+        fs::write(dir.join("etc/group"), HOST_ROOTFS_GROUP)
+            .context("Failed to write /etc/group")?;
+        fs::create_dir_all(dir.join("var")).context("Failed to create /var")?;
+        symlink("../run", dir.join("var/run")).context("Failed to create /var/run")?;
+        Ok(())
+    })
+}
+
+/// Build a minimal rootfs in the cache from the host executables
+/// `executables`, their shared libraries and whatever init needs
+pub fn from_host(executables: &[PathBuf]) -> Result<PathBuf> {
+    build_host_rootfs(executables, &cache_dir("rootfs")?)
+}
+
+/// Turn the rootfs at `path` into a directory the guest can boot from
+///
+/// Tarballs and OCI image layouts are unpacked into the cache, picking the
+/// image for the OCI architecture `oci_arch` from the latter. Anything else,
+/// like a directory or a filesystem image, is returned as is.
+pub fn prepare(path: &Path, oci_arch: &str) -> Result<PathBuf> {
+    if is_tarball(path) {
+        unpack_tarball(path, &cache_dir("rootfs")?)
+    } else if is_oci_layout(path) {
+        unpack_oci(path, oci_arch, &cache_dir("rootfs")?)
+    } else {
+        Ok(path.to_path_buf())
+    }
+}
+
+/// Resolve the guest path `path` to a host path inside `rootfs`
+///
+/// Symlinks are followed as the guest would see them, so absolute link
+/// targets stay inside `rootfs`. Returns `None` if the path does not exist.
+pub fn resolve(rootfs: &Path, path: &Path) -> Option<PathBuf> {
+    let mut resolved = PathBuf::new();
+    let mut pending: Vec<PathBuf> = path
+        .components()
+        .rev()
+        .map(|c| PathBuf::from(c.as_os_str()))
+        .collect();
+    let mut links = 0;
+
+    while let Some(component) = pending.pop() {
+        match component.components().next()? {
+            Component::RootDir => resolved.clear(),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => {
+                let host = rootfs.join(&resolved).join(name);
+                let metadata = fs::symlink_metadata(&host).ok()?;
+                if metadata.file_type().is_symlink() {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return None;
+                    }
+                    let target = fs::read_link(&host).ok()?;
+                    pending.extend(
+                        target
+                            .components()
+                            .rev()
+                            .map(|c| PathBuf::from(c.as_os_str())),
+                    );
+                } else {
+                    resolved.push(name);
+                }
+            }
+            Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(rootfs.join(resolved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_resolve() {
+        let root = tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::write(root.join("usr/bin/bash"), "").unwrap();
+        symlink("usr/bin", root.join("bin")).unwrap();
+        symlink("/usr/bin/bash", root.join("usr/bin/sh")).unwrap();
+        symlink("../bin/bash", root.join("usr/lib/bash")).unwrap();
+        symlink("loop", root.join("loop")).unwrap();
+
+        let bash = root.join("usr/bin/bash");
+        assert_eq!(resolve(root, Path::new("/bin/bash")), Some(bash.clone()));
+        assert_eq!(resolve(root, Path::new("/bin/sh")), Some(bash.clone()));
+        assert_eq!(
+            resolve(root, Path::new("/usr/lib/bash")),
+            Some(bash.clone())
+        );
+        assert_eq!(resolve(root, Path::new("/../bin/./bash")), Some(bash));
+        assert_eq!(resolve(root, Path::new("/bin/zsh")), None);
+        assert_eq!(resolve(root, Path::new("/loop")), None);
+    }
+
+    #[test]
+    fn test_is_tarball() {
+        let dir = tempdir().unwrap();
+        for name in ["rootfs.tar", "rootfs.tar.zst", "rootfs.tgz"] {
+            let path = dir.path().join(name);
+            fs::write(&path, "").unwrap();
+            assert!(is_tarball(&path), "{name}");
+        }
+        let path = dir.path().join("rootfs.img");
+        fs::write(&path, "").unwrap();
+        assert!(!is_tarball(&path));
+        assert!(!is_tarball(dir.path()));
+    }
+
+    /// Create a tarball of the directory `src` at `tarball`
+    fn tar(src: &Path, tarball: &Path) {
+        let status = Command::new("tar")
+            .arg("-c")
+            .arg("-f")
+            .arg(tarball)
+            .arg("-C")
+            .arg(src)
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_unpack_tarball() {
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("usr/bin")).unwrap();
+        fs::write(src.join("usr/bin/hello"), "hi").unwrap();
+        symlink("usr/bin", src.join("bin")).unwrap();
+        let tarball = dir.path().join("rootfs.tar");
+        tar(&src, &tarball);
+
+        let cache = dir.path().join("cache");
+        fs::create_dir(&cache).unwrap();
+        let rootfs = unpack_tarball(&tarball, &cache).unwrap();
+        assert_eq!(fs::read_to_string(rootfs.join("bin/hello")).unwrap(), "hi");
+        for mount_point in MOUNT_POINTS {
+            assert!(rootfs.join(mount_point).is_dir(), "{mount_point}");
+        }
+        // Unpacking again reuses the directory
+        assert_eq!(unpack_tarball(&tarball, &cache).unwrap(), rootfs);
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_unpack_oci() {
+        let dir = tempdir().unwrap();
+        let layout = dir.path().join("layout");
+        let blobs = layout.join("blobs/sha256");
+        fs::create_dir_all(&blobs).unwrap();
+        fs::write(
+            layout.join(OCI_LAYOUT_FILE),
+            r#"{"imageLayoutVersion":"1.0.0"}"#,
+        )
+        .unwrap();
+
+        let lower = dir.path().join("lower");
+        fs::create_dir_all(lower.join("etc")).unwrap();
+        fs::create_dir_all(lower.join("opaque")).unwrap();
+        fs::write(lower.join("etc/kept"), "").unwrap();
+        fs::write(lower.join("etc/deleted"), "").unwrap();
+        fs::write(lower.join("opaque/hidden"), "").unwrap();
+        tar(&lower, &blobs.join("lower"));
+
+        let upper = dir.path().join("upper");
+        fs::create_dir_all(upper.join("etc")).unwrap();
+        fs::create_dir_all(upper.join("opaque")).unwrap();
+        fs::write(upper.join("etc/.wh.deleted"), "").unwrap();
+        fs::write(upper.join("opaque/.wh..wh..opq"), "").unwrap();
+        fs::write(upper.join("opaque/new"), "").unwrap();
+        tar(&upper, &blobs.join("upper"));
+
+        fs::write(
+            blobs.join("arm64"),
+            r#"{"layers":[{"mediaType":"application/vnd.oci.image.layer.v1.tar","digest":"sha256:lower"}]}"#,
+        )
+        .unwrap();
+        fs::write(
+            blobs.join("amd64"),
+            r#"{"layers":[
+                {"mediaType":"application/vnd.oci.image.layer.v1.tar","digest":"sha256:lower"},
+                {"mediaType":"application/vnd.oci.image.layer.v1.tar","digest":"sha256:upper"}
+            ]}"#,
+        )
+        .unwrap();
+        fs::write(
+            blobs.join("index"),
+            r#"{"manifests":[
+                {"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:arm64","platform":{"architecture":"arm64","os":"linux"}},
+                {"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:amd64","platform":{"architecture":"amd64","os":"linux"}}
+            ]}"#,
+        )
+        .unwrap();
+        fs::write(
+            layout.join("index.json"),
+            r#"{"manifests":[{"mediaType":"application/vnd.oci.image.index.v1+json","digest":"sha256:index"}]}"#,
+        )
+        .unwrap();
+        assert!(is_oci_layout(&layout));
+
+        let cache = dir.path().join("cache");
+        fs::create_dir(&cache).unwrap();
+        let rootfs = unpack_oci(&layout, "amd64", &cache).unwrap();
+        assert!(rootfs.ends_with("oci-sha256-amd64"));
+        assert!(rootfs.join("etc/kept").exists());
+        assert!(!rootfs.join("etc/deleted").exists());
+        assert!(!rootfs.join("etc/.wh.deleted").exists());
+        assert!(!rootfs.join("opaque/hidden").exists());
+        assert!(rootfs.join("opaque/new").exists());
+
+        let rootfs = unpack_oci(&layout, "arm64", &cache).unwrap();
+        assert!(rootfs.join("etc/deleted").exists());
+
+        let err = unpack_oci(&layout, "s390x", &cache).unwrap_err();
+        assert!(
+            err.to_string().contains("no manifest for linux/s390x"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_apply_whiteouts_symlinked_parent() {
+        let dir = tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("victim"), "").unwrap();
+
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::write(root.join("usr/lib/gone"), "").unwrap();
+        symlink("usr/lib", root.join("lib")).unwrap();
+        symlink(&outside, root.join("absolute")).unwrap();
+        symlink("../outside", root.join("relative")).unwrap();
+
+        let layer = dir.path().join("layer");
+        for parent in ["lib", "absolute", "relative"] {
+            fs::create_dir_all(layer.join(parent)).unwrap();
+        }
+        fs::write(layer.join("lib/.wh.gone"), "").unwrap();
+        fs::write(layer.join("absolute/.wh.victim"), "").unwrap();
+        fs::write(layer.join("relative/.wh..wh..opq"), "").unwrap();
+        let tarball = dir.path().join("layer.tar");
+        tar(&layer, &tarball);
+
+        apply_whiteouts(&tarball, &root).unwrap();
+        assert!(!root.join("usr/lib/gone").exists());
+        // Symlinks resolve inside the rootfs, so the host is left alone
+        assert!(outside.join("victim").exists());
+    }
+
+    #[test]
+    fn test_image_fstype() {
+        let dir = tempdir().unwrap();
+        let squashfs = dir.path().join("rootfs.squashfs");
+        fs::write(&squashfs, b"hsqs\0\0\0\0").unwrap();
+        assert_eq!(image_fstype(&squashfs), Some("squashfs"));
+
+        let mut data = vec![0; EROFS_MAGIC_OFFSET];
+        data.extend(EROFS_MAGIC);
+        data.extend([0; 128]);
+        let erofs = dir.path().join("rootfs.erofs");
+        fs::write(&erofs, data).unwrap();
+        assert_eq!(image_fstype(&erofs), Some("erofs"));
+
+        let other = dir.path().join("rootfs.img");
+        fs::write(&other, [0; 2048]).unwrap();
+        assert_eq!(image_fstype(&other), None);
+        assert_eq!(image_fstype(dir.path()), None);
+    }
+
+    #[test]
+    fn test_oci_blob() {
+        let layout = Path::new("/layout");
+        assert_eq!(
+            oci_blob(layout, "sha256:abc").unwrap(),
+            Path::new("/layout/blobs/sha256/abc")
+        );
+        assert!(oci_blob(layout, "sha256:../../etc/passwd").is_err());
+        assert!(oci_blob(layout, "abc").is_err());
+    }
+
+    #[test]
+    fn test_ld_so_conf_dirs() {
+        let dir = tempdir().unwrap();
+        let conf_d = dir.path().join("ld.so.conf.d");
+        fs::create_dir(&conf_d).unwrap();
+        let conf = dir.path().join("ld.so.conf");
+        fs::write(&conf, "# comment\n/opt/lib\ninclude ld.so.conf.d/*.conf\n").unwrap();
+        fs::write(conf_d.join("b.conf"), "/usr/lib/b # trailing\n").unwrap();
+        fs::write(conf_d.join("a.conf"), "/usr/lib/a\n").unwrap();
+        fs::write(conf_d.join("ignored"), "/usr/lib/ignored\n").unwrap();
+
+        assert_eq!(
+            ld_so_conf_dirs(&conf, 0),
+            ["/opt/lib", "/usr/lib/a", "/usr/lib/b"]
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>()
+        );
+        assert!(ld_so_conf_dirs(&dir.path().join("missing"), 0).is_empty());
+    }
+
+    #[test]
+    fn test_mirror() {
+        let host = tempdir().unwrap();
+        let host = host.path();
+        fs::create_dir_all(host.join("usr/bin")).unwrap();
+        fs::write(host.join("usr/bin/tool"), "tool").unwrap();
+        symlink("usr/bin", host.join("bin")).unwrap();
+        symlink("tool", host.join("usr/bin/alias")).unwrap();
+
+        let root = tempdir().unwrap();
+        let root = root.path();
+        mirror(&host.join("bin/alias"), root, 0).unwrap();
+        // Mirrored a second time through another path
+        mirror(&host.join("usr/bin/tool"), root, 0).unwrap();
+
+        let mirrored = root.join(host.strip_prefix("/").unwrap());
+        assert_eq!(
+            fs::read_link(mirrored.join("bin")).unwrap(),
+            Path::new("usr/bin")
+        );
+        assert_eq!(
+            fs::read_link(mirrored.join("usr/bin/alias")).unwrap(),
+            Path::new("tool")
+        );
+        assert_eq!(
+            fs::read_to_string(mirrored.join("bin/alias")).unwrap(),
+            "tool"
+        );
+    }
+
+    #[test]
+    fn test_gather_host_files() {
+        let bash = which("bash").unwrap();
+        let mut files = HostFiles::new();
+        files.add(&bash).unwrap();
+        assert!(files.files.contains(&bash));
+
+        let dynamic = elf::read_dynamic(&fs::canonicalize(&bash).unwrap())
+            .unwrap()
+            .unwrap();
+        if let Some(interp) = dynamic.interp {
+            assert!(files.files.contains(Path::new(&interp)));
+            // The interpreter plus at least libc
+            assert!(files.files.len() >= 3, "{:?}", files.files);
+        }
+
+        let key = files.key().unwrap();
+        files.add(&bash).unwrap();
+        assert_eq!(files.key().unwrap(), key);
+    }
+}
@@ -0,0 +1,359 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use tempfile::{Builder, TempDir};
+
+use crate::cache::cache_dir;
+
+/// Prefix of per-run directories
+const RUN_DIR_PREFIX: &str = "vmtest-run.";
+/// Prefix of init scripts written into a 9p rootfs
+pub(crate) const INIT_PREFIX: &str = "vmtest-init.";
+/// File in a run directory holding the [`owner_tag`] of the vmtest process
+/// owning it
+const PID_FILE: &str = "pid";
+/// How long a run directory may exist without a pid file before it is
+/// considered stale. Covers the window between creating the directory and
+/// writing the pid file.
+const PID_GRACE: Duration = Duration::from_secs(60);
+
+/// Returns the directory per-run directories are created in
+///
+/// Respects `$XDG_RUNTIME_DIR`. Otherwise falls back to a directory private
+/// to the user in `$TMPDIR` or `/tmp`, which is created if needed.
+pub fn base_dir() -> Result<PathBuf> {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() && Path::new(&dir).is_dir() => Ok(PathBuf::from(dir)),
+        _ => private_dir(&env::temp_dir()),
+    }
+}
+
+/// Returns the `vmtest-<uid>` directory in `parent`, creating it with mode
+/// 0700 if it does not exist
+///
+/// `parent` is usually shared, so anyone could have created the directory
+/// first. Fails unless it belongs to the user and no one else may access it.
+fn private_dir(parent: &Path) -> Result<PathBuf> {
+    // SAFETY: getuid() cannot fail
+    let uid = unsafe { libc::getuid() };
+    let dir = parent.join(format!("vmtest-{uid}"));
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to create {}", dir.display()));
+        }
+    }
+
+    let meta =
+        fs::symlink_metadata(&dir).with_context(|| format!("Failed to stat {}", dir.display()))?;
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        bail!(
+            "{} is not a directory private to the current user",
+            dir.display()
+        );
+    }
+
+    Ok(dir)
+}
+
+/// Returns the state and start time of the process with pid `pid`
+fn proc_stat(pid: u32) -> Option<(String, u64)> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The state follows the command name, which may contain spaces
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace();
+    let state = fields.next()?.to_string();
+    // The start time is the 19th field after the state
+    let start = fields.nth(18)?.parse().ok()?;
+
+    Some((state, start))
+}
+
+/// Whether or not the process with pid `pid` is still alive
+///
+/// Zombies that have not been reaped yet count as dead.
+pub(crate) fn pid_alive(pid: u32) -> bool {
+    proc_stat(pid).is_some_and(|(state, _)| state != "Z")
+}
+
+/// Identifies this process as the owner of files [`gc`] may remove
+///
+/// The tag is the pid along with the process' start time, so that another
+/// process reusing the pid is not mistaken for the owner.
+fn owner_tag() -> String {
+    let pid = process::id();
+    match proc_stat(pid) {
+        Some((_, start)) => format!("{pid}-{start}"),
+        None => pid.to_string(),
+    }
+}
+
+/// Whether or not the owner identified by `tag` is still alive
+///
+/// Tags without a start time only check the pid. Returns `None` if `tag` is
+/// malformed.
+fn owner_alive(tag: &str) -> Option<bool> {
+    let (pid, start) = match tag.split_once('-') {
+        Some((pid, start)) => (pid, Some(start.parse::<u64>().ok()?)),
+        None => (tag, None),
+    };
+    let pid = pid.parse().ok()?;
+
+    Some(pid_alive(pid) && start.map_or(true, |s| proc_stat(pid).map(|(_, t)| t) == Some(s)))
+}
+
+/// A private directory holding the sockets, scripts, disks and other state of
+/// a single run
+///
+/// The directory and everything inside it is removed on drop.
+pub(crate) struct RunDir {
+    dir: TempDir,
+}
+
+impl RunDir {
+    /// Create a new run directory in [`base_dir`]
+    pub fn new() -> Result<Self> {
+        Self::new_in(&base_dir()?)
+    }
+
+    fn new_in(base: &Path) -> Result<Self> {
+        // tempfile creates directories with mode 0700
+        let dir = Builder::new()
+            .prefix(RUN_DIR_PREFIX)
+            .tempdir_in(base)
+            .with_context(|| format!("Failed to create run directory in {}", base.display()))?;
+        fs::write(dir.path().join(PID_FILE), owner_tag())
+            .context("Failed to write run directory pid file")?;
+
+        Ok(Self { dir })
+    }
+
+    /// Path to the run directory
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Path to the socket called `name` inside the run directory
+    pub fn sock(&self, name: &str) -> PathBuf {
+        self.path().join(format!("{name}.sock"))
+    }
+}
+
+/// Prefix for init scripts owned by this process
+///
+/// The [`owner_tag`] lets [`gc`] tell whether the owner is still around.
+pub(crate) fn init_prefix() -> String {
+    format!("{INIT_PREFIX}{}.", owner_tag())
+}
+
+/// Whether or not the run directory `dir` belongs to a run that is gone
+fn run_dir_stale(dir: &Path) -> bool {
+    match fs::read_to_string(dir.join(PID_FILE)) {
+        Ok(tag) => owner_alive(tag.trim()) != Some(true),
+        Err(_) => fs::metadata(dir)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|mtime| SystemTime::now().duration_since(mtime).ok())
+            .map_or(true, |age| age > PID_GRACE),
+    }
+}
+
+/// Whether or not `name` is an init script of a process that is gone
+fn init_stale(name: &OsStr) -> bool {
+    let Some(rest) = name.to_str().and_then(|n| n.strip_prefix(INIT_PREFIX)) else {
+        return false;
+    };
+    rest.split('.')
+        .next()
+        .and_then(owner_alive)
+        .is_some_and(|alive| !alive)
+}
+
+/// Remove stale run directories in `base` and stale init scripts in
+/// `tmp_dirs`, returning what was removed
+fn gc_in(base: &Path, tmp_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut removed = Vec::new();
+
+    if let Ok(entries) = fs::read_dir(base) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_run_dir = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|n| n.starts_with(RUN_DIR_PREFIX));
+            if !is_run_dir || !path.is_dir() || !run_dir_stale(&path) {
+                continue;
+            }
+            match fs::remove_dir_all(&path) {
+                Ok(()) => removed.push(path),
+                Err(e) => debug!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    for dir in tmp_dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if !init_stale(&entry.file_name()) {
+                continue;
+            }
+            let path = entry.path();
+            match fs::remove_file(&path) {
+                Ok(()) => removed.push(path),
+                Err(e) => debug!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    removed
+}
+
+/// Remove files left behind by vmtest runs that did not exit cleanly
+///
+/// This covers per-run directories in [`base_dir`] as well as init scripts
+/// in the host's temp dir and in the temp dirs of cached rootfs. Files of
+/// runs that are still alive are left alone. Returns the removed paths.
+pub fn gc() -> Result<Vec<PathBuf>> {
+    let tmp = env::temp_dir();
+    let mut tmp_dirs = vec![tmp.clone()];
+    let rootfs_cache = cache_dir("rootfs")?;
+    if let Ok(entries) = fs::read_dir(&rootfs_cache) {
+        // Init scripts live at the same path inside the rootfs as on the host
+        let rel = tmp.strip_prefix("/").unwrap_or(&tmp);
+        tmp_dirs.extend(entries.flatten().map(|e| e.path().join(rel)));
+    }
+
+    Ok(gc_in(&base_dir()?, &tmp_dirs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pid that is not in use
+    fn dead_pid() -> u32 {
+        // Exceeds the kernel's PID_MAX_LIMIT
+        u32::MAX
+    }
+
+    #[test]
+    fn test_pid_alive() {
+        assert!(pid_alive(process::id()));
+        assert!(!pid_alive(dead_pid()));
+
+        let mut child = process::Command::new("true").spawn().unwrap();
+        // Wait for the child to exit without reaping it
+        while pid_alive(child.id()) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(Path::new(&format!("/proc/{}", child.id())).exists());
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_owner_alive() {
+        let tag = owner_tag();
+        assert!(tag.starts_with(&format!("{}-", process::id())));
+        assert_eq!(owner_alive(&tag), Some(true));
+        // Tags of older vmtest versions only hold the pid
+        assert_eq!(owner_alive(&process::id().to_string()), Some(true));
+        // The pid was reused by another process
+        assert_eq!(owner_alive(&format!("{}-1", process::id())), Some(false));
+        assert_eq!(owner_alive(&dead_pid().to_string()), Some(false));
+        assert_eq!(owner_alive("abc"), None);
+        assert_eq!(owner_alive(&format!("{}-abc", process::id())), None);
+    }
+
+    #[test]
+    fn test_run_dir() {
+        let base = TempDir::new().unwrap();
+        let run_dir = RunDir::new_in(base.path()).unwrap();
+        let path = run_dir.path().to_path_buf();
+
+        assert!(path.starts_with(base.path()));
+        assert_eq!(run_dir.sock("qmp"), path.join("qmp.sock"));
+        assert_eq!(
+            fs::read_to_string(path.join(PID_FILE)).unwrap(),
+            owner_tag()
+        );
+
+        drop(run_dir);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_private_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let parent = TempDir::new().unwrap();
+        let dir = private_dir(parent.path()).unwrap();
+        assert_eq!(dir.parent().unwrap(), parent.path());
+        assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+        // Reused by later runs
+        assert_eq!(private_dir(parent.path()).unwrap(), dir);
+
+        // Others may access the directory
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(private_dir(parent.path()).is_err());
+        // Not a directory at all
+        fs::remove_dir(&dir).unwrap();
+        std::os::unix::fs::symlink(parent.path(), &dir).unwrap();
+        assert!(private_dir(parent.path()).is_err());
+    }
+
+    #[test]
+    fn test_gc() {
+        let base = TempDir::new().unwrap();
+        let tmp = TempDir::new().unwrap();
+
+        let live = RunDir::new_in(base.path()).unwrap();
+        let stale = base.path().join(format!("{RUN_DIR_PREFIX}stale"));
+        fs::create_dir(&stale).unwrap();
+        fs::write(stale.join(PID_FILE), dead_pid().to_string()).unwrap();
+        fs::write(stale.join("qmp.sock"), "").unwrap();
+        // Run directory whose pid file has not been written yet
+        let fresh = base.path().join(format!("{RUN_DIR_PREFIX}fresh"));
+        fs::create_dir(&fresh).unwrap();
+        // Run directory of a process whose pid was reused
+        let reused = base.path().join(format!("{RUN_DIR_PREFIX}reused"));
+        fs::create_dir(&reused).unwrap();
+        fs::write(reused.join(PID_FILE), format!("{}-1", process::id())).unwrap();
+        let unrelated = base.path().join("unrelated");
+        fs::create_dir(&unrelated).unwrap();
+        fs::write(unrelated.join(PID_FILE), dead_pid().to_string()).unwrap();
+
+        let live_init = tmp.path().join(format!("{}abcde.sh", init_prefix()));
+        fs::write(&live_init, "").unwrap();
+        let stale_init = tmp
+            .path()
+            .join(format!("{INIT_PREFIX}{}.abcde.sh", dead_pid()));
+        fs::write(&stale_init, "").unwrap();
+        let reused_init = tmp
+            .path()
+            .join(format!("{INIT_PREFIX}{}-1.abcde.sh", process::id()));
+        fs::write(&reused_init, "").unwrap();
+        let old_init = tmp.path().join("vmtest-initabcde.sh");
+        fs::write(&old_init, "").unwrap();
+
+        let mut removed = gc_in(base.path(), &[tmp.path().to_path_buf()]);
+        removed.sort();
+        let mut expected = vec![stale, reused, stale_init, reused_init];
+        expected.sort();
+        assert_eq!(removed, expected);
+        assert!(live.path().exists());
+        assert!(fresh.exists());
+        assert!(unrelated.exists());
+        assert!(live_init.exists());
+        assert!(old_init.exists());
+    }
+}
@@ -0,0 +1,304 @@
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, warn};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::output::Output;
+use crate::run_dir::base_dir;
+use crate::vmtest::Session;
+
+/// Requests a client sends over the control socket
+///
+/// Each request is a single line of JSON.
+#[derive(Serialize, Deserialize, Debug)]
+enum Request {
+    /// Run a command in the VM
+    Exec { command: String },
+    /// Shut down the VM and stop serving
+    Stop,
+}
+
+/// How often the server checks whether the session was cancelled while it
+/// waits for clients
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Responses the server sends over the control socket
+///
+/// Each response is a single line of JSON.
+#[derive(Serialize, Deserialize, Debug)]
+enum Response {
+    /// A line of command output
+    Output(String),
+    /// Command exited with the provided exit code
+    Exit(i64),
+    /// Command failed to run
+    Error(String),
+    /// VM has been shut down
+    Stopped,
+}
+
+/// Returns the path of the file with `suffix` of the session named `name`
+///
+/// Fails if `name` is not a valid session name. Session names may only
+/// contain ASCII letters, digits, `_` and `-`, so that they cannot escape
+/// [`base_dir`], which is private to the user.
+fn session_path(name: &str, suffix: &str) -> Result<PathBuf> {
+    let name_re = Regex::new(r"^[A-Za-z0-9_-]+$").unwrap();
+    if !name_re.is_match(name) {
+        bail!("Invalid session name '{name}': must only contain letters, digits, '_' and '-'");
+    }
+
+    Ok(base_dir()?.join(format!("vmtest-session-{name}.{suffix}")))
+}
+
+/// Returns the path to the control socket of the session named `name`
+pub fn socket_path(name: &str) -> Result<PathBuf> {
+    session_path(name, "sock")
+}
+
+/// Returns the path to the log of the server of the session named `name`
+pub fn log_path(name: &str) -> Result<PathBuf> {
+    session_path(name, "log")
+}
+
+/// Write a single message to the stream
+fn send<T: serde::Serialize>(stream: &mut UnixStream, msg: &T) -> Result<()> {
+    let mut line = serde_json::to_string(msg).context("Failed to serialize message")?;
+    line.push('\n');
+    stream
+        .write_all(line.as_bytes())
+        .context("Failed to write to control socket")
+}
+
+/// Read a single message from the stream
+///
+/// Returns `None` if the peer hung up.
+fn recv<T: serde::de::DeserializeOwned>(reader: &mut impl BufRead) -> Result<Option<T>> {
+    let mut line = String::new();
+    match reader
+        .read_line(&mut line)
+        .context("Failed to read from control socket")?
+    {
+        0 => Ok(None),
+        _ => Ok(Some(
+            serde_json::from_str(&line).context("Failed to deserialize message")?,
+        )),
+    }
+}
+
+/// Run a command in the session and stream the results back to the client
+fn handle_exec(session: &mut Session, stream: &UnixStream, command: &str) -> Result<()> {
+    let (sender, receiver) = channel::<Output>();
+    let mut writer = stream
+        .try_clone()
+        .context("Failed to clone control socket")?;
+
+    // `Session::exec()` blocks, so forward updates on their own thread
+    let forwarder = thread::spawn(move || -> Result<()> {
+        for msg in receiver {
+            let resp = match msg {
+                Output::Command(line) => Response::Output(line),
+                Output::CommandEnd(Ok(rc)) => Response::Exit(rc),
+                // NB: use debug formatting to get full trace
+                Output::CommandEnd(Err(e)) => Response::Error(format!("{:?}", e)),
+                _ => continue,
+            };
+            send(&mut writer, &resp)?;
+        }
+
+        Ok(())
+    });
+
+    session.exec(command, sender);
+
+    forwarder
+        .join()
+        .map_err(|_| anyhow!("Output forwarding thread panicked"))?
+}
+
+/// Serve `session` on a control socket at `path`
+///
+/// Clients are served one at a time. Returns once a client requests the
+/// session be stopped or the session is cancelled, at which point the VM has
+/// been shut down.
+pub fn serve(mut session: Session, path: &Path) -> Result<()> {
+    // Clean up after a previous session that did not exit cleanly
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("Session socket {} is already in use", path.display());
+        }
+        fs::remove_file(path).context("Failed to remove stale session socket")?;
+    }
+
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Failed to bind {}", path.display()))?;
+    scopeguard::defer! {
+        let _ = fs::remove_file(path);
+    }
+    // Don't block in accept() so cancellation is noticed
+    listener
+        .set_nonblocking(true)
+        .context("Failed to make session socket non-blocking")?;
+    let canceller = session.canceller();
+
+    loop {
+        if canceller.is_cancelled() {
+            session.stop();
+            return Ok(());
+        }
+        let mut stream = match listener.accept() {
+            Ok((s, _)) => s,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(CANCEL_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                warn!("Failed to accept session client: {e}");
+                continue;
+            }
+        };
+        stream
+            .set_nonblocking(false)
+            .context("Failed to make session client blocking")?;
+
+        let mut reader = BufReader::new(
+            stream
+                .try_clone()
+                .context("Failed to clone control socket")?,
+        );
+        let req: Request = match recv(&mut reader) {
+            Ok(Some(r)) => r,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Failed to read session request: {:?}", e);
+                continue;
+            }
+        };
+        debug!("Session request: {:?}", req);
+
+        match req {
+            Request::Exec { command } => {
+                // A client going away mid-command is not fatal to the session
+                if let Err(e) = handle_exec(&mut session, &stream, &command) {
+                    warn!("Failed to serve exec request: {:?}", e);
+                }
+            }
+            Request::Stop => {
+                session.stop();
+                let _ = send(&mut stream, &Response::Stopped);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A client for a session's control socket
+pub struct Client {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl Client {
+    /// Connect to the control socket at `path`
+    pub fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("Failed to connect to session at {}", path.display()))?;
+        let reader = BufReader::new(
+            stream
+                .try_clone()
+                .context("Failed to clone control socket")?,
+        );
+
+        Ok(Self { stream, reader })
+    }
+
+    /// Run `command` in the session's VM
+    ///
+    /// `output` is called for each line of command output as it arrives.
+    ///
+    /// Returns the exit code of the command.
+    pub fn exec<F>(mut self, command: &str, mut output: F) -> Result<i64>
+    where
+        F: FnMut(String),
+    {
+        send(
+            &mut self.stream,
+            &Request::Exec {
+                command: command.to_string(),
+            },
+        )?;
+
+        loop {
+            match recv(&mut self.reader)? {
+                Some(Response::Output(line)) => output(line),
+                Some(Response::Exit(rc)) => return Ok(rc),
+                Some(Response::Error(e)) => bail!(e),
+                Some(r) => bail!("Unexpected session response: {:?}", r),
+                None => bail!("Session hung up before command finished"),
+            }
+        }
+    }
+
+    /// Shut down the session's VM
+    pub fn stop(mut self) -> Result<()> {
+        send(&mut self.stream, &Request::Stop)?;
+
+        match recv(&mut self.reader)? {
+            Some(Response::Stopped) => Ok(()),
+            Some(r) => bail!("Unexpected session response: {:?}", r),
+            None => bail!("Session hung up before VM was stopped"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_roundtrip() {
+        let (mut a, b) = UnixStream::pair().unwrap();
+        send(
+            &mut a,
+            &Request::Exec {
+                command: "echo 'multi\nline'".into(),
+            },
+        )
+        .unwrap();
+        send(&mut a, &Request::Stop).unwrap();
+        drop(a);
+
+        let mut reader = BufReader::new(b);
+        match recv(&mut reader).unwrap() {
+            Some(Request::Exec { command }) => assert_eq!(command, "echo 'multi\nline'"),
+            r => panic!("Unexpected request: {:?}", r),
+        }
+        assert!(matches!(recv(&mut reader).unwrap(), Some(Request::Stop)));
+        assert!(recv::<Request>(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_session_path() {
+        let path = socket_path("my-session_1").unwrap();
+        assert_eq!(
+            path.file_name().unwrap(),
+            "vmtest-session-my-session_1.sock"
+        );
+        assert_eq!(
+            log_path("default").unwrap().file_name().unwrap(),
+            "vmtest-session-default.log"
+        );
+
+        for name in ["", "../x", "a/b", "a b", "."] {
+            assert!(socket_path(name).is_err(), "{name:?} was accepted");
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, warn};
+use tempfile::{Builder, TempDir};
+
+/// How long to wait for swtpm to create its control socket
+const SWTPM_STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A software TPM 2.0 emulator process
+///
+/// The process is killed and its state is deleted when this is dropped.
+pub struct Swtpm {
+    child: Child,
+    /// Holds the TPM state and the control socket
+    dir: TempDir,
+    /// Forwards swtpm's stderr to the log so it never fills up the pipe
+    stderr: Option<JoinHandle<()>>,
+}
+
+impl Swtpm {
+    /// Start swtpm with fresh TPM state kept in a new directory inside `dir`
+    ///
+    /// Returns once swtpm is ready for QEMU to connect.
+    pub fn spawn(dir: &Path) -> Result<Self> {
+        let dir = Builder::new()
+            .prefix("vmtest-swtpm")
+            .tempdir_in(dir)
+            .context("Failed to create swtpm state directory")?;
+        let sock = dir.path().join("swtpm.sock");
+
+        let mut tpmstate = "dir=".to_string();
+        tpmstate.push_str(&dir.path().to_string_lossy());
+        let mut ctrl = "type=unixio,path=".to_string();
+        ctrl.push_str(&sock.to_string_lossy());
+
+        // `--terminate` makes swtpm exit once QEMU disconnects
+        let child = Command::new("swtpm")
+            .args(["socket", "--tpm2", "--terminate"])
+            .arg("--tpmstate")
+            .arg(tpmstate)
+            .arg("--ctrl")
+            .arg(ctrl)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            // Terminal signals are for us, we shut swtpm down on our own
+            .process_group(0)
+            .spawn()
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => anyhow!("swtpm not found. Is it installed?"),
+                _ => anyhow!(e).context("Failed to spawn swtpm"),
+            })?;
+        let mut swtpm = Self {
+            child,
+            dir,
+            stderr: None,
+        };
+        swtpm.wait_ready(&sock)?;
+        debug!("swtpm ready at {}", sock.display());
+
+        // The pipe is closed once swtpm exits, which ends the thread
+        if let Some(pipe) = swtpm.child.stderr.take() {
+            swtpm.stderr = Some(thread::spawn(move || {
+                for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+                    debug!("swtpm: {line}");
+                }
+            }));
+        }
+
+        Ok(swtpm)
+    }
+
+    /// Wait for swtpm to create its control socket
+    fn wait_ready(&mut self, sock: &Path) -> Result<()> {
+        let start = Instant::now();
+        while !sock.exists() {
+            if let Some(status) = self.child.try_wait().context("Failed to wait on swtpm")? {
+                let mut stderr = String::new();
+                if let Some(mut pipe) = self.child.stderr.take() {
+                    let _ = pipe.read_to_string(&mut stderr);
+                }
+                bail!("swtpm exited with {}: {}", status, stderr.trim());
+            }
+            if start.elapsed() > SWTPM_STARTUP_TIMEOUT {
+                bail!("Timed out waiting for swtpm to start");
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Ok(())
+    }
+
+    /// Path to the control socket QEMU connects to
+    pub fn sock(&self) -> PathBuf {
+        self.dir.path().join("swtpm.sock")
+    }
+}
+
+impl Drop for Swtpm {
+    fn drop(&mut self) {
+        // swtpm normally exits on its own once QEMU goes away
+        match self.child.try_wait() {
+            Ok(Some(status)) if !status.success() => {
+                warn!("swtpm exited with {status}");
+            }
+            Ok(Some(_)) => (),
+            _ => {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+            }
+        }
+        if let Some(stderr) = self.stderr.take() {
+            let _ = stderr.join();
+        }
+    }
+}
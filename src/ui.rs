@@ -1,17 +1,19 @@
 use std::cmp::min;
 use std::env;
-use std::sync::mpsc::{channel, Receiver};
-use std::thread;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 
 use anyhow::{anyhow, Error};
 use console::{strip_ansi_codes, style, truncate_str, Style, Term};
 
-use crate::output::Output;
+use crate::output::{Output, OutputSink, Sink, Tee};
+use crate::result::{Recorder, Stats, Timings};
 use crate::vmtest::Vmtest;
 
 const WINDOW_LENGTH: usize = 10;
-// sysexits.h catchall exit code for when we failed to run the vm for miscellaneous reasons.
-const EX_UNAVAILABLE: i32 = 69;
+/// sysexits.h catchall exit code for when we failed to run the vm for miscellaneous reasons.
+pub const EX_UNAVAILABLE: i32 = 69;
 
 /// Console UI
 ///
@@ -19,6 +21,25 @@ const EX_UNAVAILABLE: i32 = 69;
 /// formatting and reporting any errors.
 pub struct Ui {
     vmtest: Vmtest,
+    /// Additional sinks every run is recorded to
+    sinks: Vec<Sink>,
+}
+
+/// Renders the updates of a single target to the console
+///
+/// Rendering happens on a background thread. Its handle is returned
+/// alongside the sink and finishes once the run is over or the sink is
+/// flushed, returning `None` if the VM failed to run the command or the
+/// exit code of the command otherwise.
+pub struct ConsoleSink {
+    sender: Mutex<Sender<Message>>,
+}
+
+/// What the console UI thread receives
+enum Message {
+    Output(Output),
+    /// Finish rendering and acknowledge through the sender
+    Flush(Sender<()>),
 }
 
 struct Stage {
@@ -175,26 +196,111 @@ fn error_out_stage(stage: &mut Stage, err: &Error) {
     stage.expand(true);
 }
 
+impl ConsoleSink {
+    /// Construct a sink rendering the run of the target named `target`
+    ///
+    /// `show_cmd` specifies if the command output should always be shown.
+    pub fn new(target: &str, show_cmd: bool) -> (Self, JoinHandle<Option<i32>>) {
+        let (sender, receiver) = channel();
+        let target = target.to_string();
+        let ui = thread::spawn(move || Ui::target_ui(receiver, target, show_cmd));
+
+        (
+            Self {
+                sender: Mutex::new(sender),
+            },
+            ui,
+        )
+    }
+}
+
+impl OutputSink for ConsoleSink {
+    fn send(&self, output: Output) {
+        // The UI is gone once it rendered the run
+        let _ = self.sender.lock().unwrap().send(Message::Output(output));
+    }
+
+    fn flush(&self) {
+        let (ack, acked) = channel();
+        if self
+            .sender
+            .lock()
+            .unwrap()
+            .send(Message::Flush(ack))
+            .is_ok()
+        {
+            let _ = acked.recv();
+        }
+    }
+}
+
 impl Ui {
     /// Construct a new UI
     pub fn new(vmtest: Vmtest) -> Self {
-        Self { vmtest }
+        Self {
+            vmtest,
+            sinks: Vec::new(),
+        }
+    }
+
+    /// Also send the updates of every run to `sink`
+    ///
+    /// Useful for recording runs while they are rendered to the console.
+    pub fn add_sink(&mut self, sink: Sink) {
+        self.sinks.push(sink);
+    }
+
+    /// Set up the sink for a run of the target named `target`
+    ///
+    /// Returns the sink and the handle of the console UI thread.
+    fn run_sink(&self, target: &str, show_cmd: bool) -> (Sink, JoinHandle<Option<i32>>) {
+        let (console, ui) = ConsoleSink::new(target, show_cmd);
+        // The console goes last so it gets the original errors, not copies
+        let mut sinks = self.sinks.clone();
+        sinks.push(Arc::new(console));
+        let sink = Arc::new(Tee::new(sinks));
+        sink.target(target);
+
+        (sink, ui)
+    }
+
+    /// Report `target` as interrupted if its run was cancelled
+    ///
+    /// Returns whether the run was cancelled.
+    fn interrupted(&self, target: &str) -> bool {
+        if !self.vmtest.canceller().is_cancelled() {
+            return false;
+        }
+        let msg = style(format!("Interrupted target '{target}'"))
+            .red()
+            .bright();
+        let _ = Term::stderr().write_line(&msg.to_string());
+
+        true
     }
 
     /// UI for a single target. Must be run on its own thread.
     ///
     /// Returns None if the vm failed to run the command.
     /// Otherwise, return the return code of the command.
-    fn target_ui(updates: Receiver<Output>, target: String, show_cmd: bool) -> Option<i32> {
+    fn target_ui(updates: Receiver<Message>, target: String, show_cmd: bool) -> Option<i32> {
         let term = Term::stdout();
         let mut stage = Stage::new(term.clone(), &heading(&target, 1), None);
         let mut stages = 0;
         let mut rc = Some(0);
+        let mut timings = Timings::default();
+        let mut flushed = None;
 
         // Main state machine loop
         loop {
             let msg = match updates.recv() {
-                Ok(l) => l,
+                Ok(Message::Output(o)) => o,
+                // The VM may outlive the command, for example to host a
+                // debug shell. The run flushes before taking the terminal.
+                Ok(Message::Flush(ack)) => {
+                    flushed = Some(ack);
+                    break;
+                }
                 // Qemu hangs up when done
                 Err(_) => break,
             };
@@ -248,26 +354,120 @@ impl Ui {
                         }
                     };
                 }
+                Output::Timings(t) => timings = *t,
             }
         }
 
         // Force stage cleanup so we can do final fixup if we want
         drop(stage);
 
+        let summary = |verdict: &str| match timings.is_empty() {
+            true => verdict.to_string(),
+            false => format!("{verdict} ({timings})"),
+        };
         match rc {
             Some(0) if !show_cmd => {
                 clear_last_lines(&term, stages);
-                term.write_line("PASS").expect("Failed to write terminal");
+                term.write_line(&summary("PASS"))
+                    .expect("Failed to write terminal");
             }
             Some(_) if !show_cmd => {
-                term.write_line("FAILED").expect("Failed to write terminal");
+                term.write_line(&summary("FAILED"))
+                    .expect("Failed to write terminal");
+            }
+            // The command's output stays up, so report timings below it
+            Some(_) if !timings.is_empty() => {
+                term.write_line(&format!("Timings: {timings}"))
+                    .expect("Failed to write terminal");
             }
             _ => (),
         }
+        if let Some(ack) = flushed {
+            let _ = ack.send(());
+        }
+
+        rc
+    }
+
+    /// Attach the terminal to a login shell in the first target of the provided `vmtest`
+    ///
+    /// Returns the exit code of the shell, or EX_UNAVAILABLE if there is an issue that
+    /// prevents running the shell.
+    pub fn shell(self) -> i32 {
+        let name = match self.vmtest.targets().first() {
+            Some(t) => t.name.clone(),
+            None => return EX_UNAVAILABLE,
+        };
+        // The UI renders on its own thread b/c `Vmtest::shell()` will block
+        let (sink, ui) = self.run_sink(&name, true);
+
+        self.vmtest.shell(0, sink);
+
+        let rc = ui
+            .join()
+            .expect("Failed to join UI thread")
+            .unwrap_or(EX_UNAVAILABLE);
+        self.interrupted(&self.vmtest.targets()[0].name);
 
         rc
     }
 
+    /// Boot each target in the provided `vmtest` `runs` times and report
+    /// how long booting took
+    ///
+    /// Targets are shut down right after setup; their commands are not run.
+    /// Boot time is measured from spawning QEMU until the guest is ready.
+    ///
+    /// Returns 0, or EX_UNAVAILABLE if a target failed to boot.
+    pub fn bench(self, runs: usize) -> i32 {
+        let term = Term::stdout();
+        for (idx, target) in self.vmtest.targets().iter().enumerate() {
+            term.write_line(&heading(&target.name, 1))
+                .expect("Failed to write terminal");
+
+            let mut spawn = Vec::new();
+            let mut boot = Vec::new();
+            let mut total = Vec::new();
+            for run in 1..=runs {
+                let recorder = Arc::new(Recorder::default());
+                let mut sinks = self.sinks.clone();
+                sinks.push(recorder.clone());
+                let sink = Tee::new(sinks);
+                sink.target(&target.name);
+
+                if let Some(session) = self.vmtest.session(idx, sink) {
+                    session.stop();
+                }
+                let result = recorder.finish(None);
+                if self.interrupted(&target.name) {
+                    return EX_UNAVAILABLE;
+                }
+                if let Some(failure) = result.failure {
+                    let msg = style(format!("{:?}", failure.error)).red().bright();
+                    term.write_line(&msg.to_string())
+                        .expect("Failed to write terminal");
+                    return EX_UNAVAILABLE;
+                }
+
+                let t = result.timings;
+                term.write_line(&format!("Run {run}/{runs}: {t}"))
+                    .expect("Failed to write terminal");
+                spawn.extend(t.spawn);
+                boot.extend(t.boot);
+                total.extend(t.boot_total());
+            }
+
+            for (name, samples) in [("spawn", spawn), ("boot", boot), ("total", total)] {
+                if let Some(stats) = Stats::new(&samples) {
+                    term.write_line(&format!("{name}: {stats}"))
+                        .expect("Failed to write terminal");
+                }
+            }
+        }
+
+        0
+    }
+
     /// Run all the targets in the provided `vmtest`
     ///
     /// `filter` specifies the regex to filter targets by.
@@ -280,26 +480,31 @@ impl Ui {
     /// is an issue that prevents running the command.
     ///
     /// When multiple targets are ran, it returns how many targets failed.
+    /// Targets after one that was interrupted through [`Vmtest::canceller`]
+    /// are skipped.
     pub fn run(self, show_cmd: bool) -> i32 {
         let mut failed = 0;
         let targets = self.vmtest.targets();
         let single_cmd = targets.len() == 1;
 
         for (idx, target) in targets.iter().enumerate() {
-            let (sender, receiver) = channel::<Output>();
-
-            // Start UI on its own thread b/c `Vmtest::run_one()` will block
-            let name = target.name.clone();
-            let ui = thread::spawn(move || Self::target_ui(receiver, name, show_cmd));
+            // The UI renders on its own thread b/c `Vmtest::run_one()` will block
+            let (sink, ui) = self.run_sink(&target.name, show_cmd);
 
             // Run a target
-            self.vmtest.run_one(idx, sender);
+            let result = self.vmtest.run_one(idx, sink);
+            ui.join().expect("Failed to join UI thread");
 
-            let rc = ui
-                .join()
-                .expect("Failed to join UI thread")
+            let rc = match result.failure {
                 // Transform VM error into a pre-baked error code that represent the failure
-                .unwrap_or(EX_UNAVAILABLE);
+                Some(_) => EX_UNAVAILABLE,
+                // Interactive targets do not run a command
+                None => result.exit_code.unwrap_or(0) as i32,
+            };
+
+            if self.interrupted(&target.name) {
+                return if single_cmd { rc } else { failed + 1 };
+            }
 
             if single_cmd {
                 return rc;
@@ -1,26 +1,325 @@
+use std::collections::{HashMap, HashSet};
 use std::convert::AsRef;
+use std::env;
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::thread::{self, JoinHandle};
 
 use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
 
-use crate::config::{Config, Target};
-use crate::output::Output;
-use crate::qemu::Qemu;
+use crate::config::{
+    Config, ControllerKind, Disk, DiskBus, DiskMedia, MemoryBackendKind, Rootfs, Target, TpmModel,
+};
+use crate::output::{IntoSink, Output, OutputSink, Sink, Tee};
+pub use crate::qemu::Canceller;
+use crate::qemu::{
+    arch_supported, arch_supports_uefi, oci_arch, parse_cpu_list, parse_memory, parse_size,
+    supported_arches, Qemu, VmHandle,
+};
+use crate::result::Recorder;
+pub use crate::result::RunResult;
+use crate::rootfs;
+
+/// Name of the config file looked for in a directory
+pub const CONFIG_FILE: &str = "vmtest.toml";
 
 /// Central vmtest data structure
+#[derive(Clone)]
 pub struct Vmtest {
     base: PathBuf,
     config: Config,
+    shell_on_failure: bool,
+    canceller: Canceller,
+}
+
+/// A VM that stays alive across multiple commands
+///
+/// Booting a VM usually dominates the runtime of short commands. A session
+/// boots the target once and then runs any number of commands against the
+/// live VM.
+///
+/// The QEMU process is torn down when the session is stopped or dropped.
+pub struct Session {
+    qemu: Qemu,
+    vm: VmHandle,
+}
+
+/// A target run in progress on a background thread
+///
+/// Created by [`Vmtest::spawn`]. Real time updates are available through
+/// [`RunHandle::events`] and friends. Dropping the handle lets the run
+/// continue in the background; cancel it first to shut the VM down.
+pub struct RunHandle {
+    events: Receiver<Output>,
+    /// Waker of the last [`RunHandle::poll_event`] that came up empty
+    waker: Arc<Mutex<Option<Waker>>>,
+    canceller: Canceller,
+    thread: JoinHandle<RunResult>,
+}
+
+/// Sink feeding a [`RunHandle`]
+struct HandleSink {
+    sender: Mutex<Sender<Output>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl HandleSink {
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+impl OutputSink for HandleSink {
+    fn send(&self, output: Output) {
+        let _ = self.sender.lock().unwrap().send(output);
+        self.wake();
+    }
+}
+
+impl Drop for HandleSink {
+    fn drop(&mut self) {
+        // The handle sees a hang up once the sender is gone. Boot output may
+        // outlive the run, so this is the only reliable place to wake it.
+        drop(std::mem::replace(
+            self.sender.get_mut().unwrap(),
+            channel().0,
+        ));
+        self.wake();
+    }
+}
+
+/// Validate the device options of `disk`
+///
+/// `controllers` maps the names of the target's controllers to their kinds.
+fn validate_disk_device(
+    target: &Target,
+    disk: &Disk,
+    controllers: &HashMap<&str, ControllerKind>,
+) -> Result<()> {
+    if let Some(name) = &disk.controller {
+        let expected = match disk.bus() {
+            DiskBus::VirtioBlk => bail!(
+                "Target '{}' disk '{}' cannot use a controller with bus 'virtio-blk'",
+                target.name,
+                disk.name
+            ),
+            DiskBus::VirtioScsi => ControllerKind::VirtioScsi,
+            DiskBus::Nvme => ControllerKind::Nvme,
+        };
+        match controllers.get(name.as_str()) {
+            None => bail!(
+                "Target '{}' disk '{}' references unknown controller '{}'",
+                target.name,
+                disk.name,
+                name
+            ),
+            Some(kind) if *kind != expected => bail!(
+                "Target '{}' disk '{}' must use the same bus as controller '{}'",
+                target.name,
+                disk.name,
+                name
+            ),
+            _ => (),
+        }
+    }
+
+    if let Some(queues) = disk.queues {
+        // Queues of shared controllers are configured on the controller
+        let per_disk = match disk.bus() {
+            DiskBus::VirtioBlk => true,
+            DiskBus::VirtioScsi => false,
+            DiskBus::Nvme => disk.controller.is_none(),
+        };
+        if !per_disk {
+            bail!(
+                "Target '{}' disk '{}' must set 'queues' on its controller instead",
+                target.name,
+                disk.name
+            );
+        }
+        if queues == 0 {
+            bail!(
+                "Target '{}' disk '{}' must have at least 1 queue",
+                target.name,
+                disk.name
+            );
+        }
+    }
+
+    // QEMU accepts power of 2 block sizes between 512 bytes and 2 MiB
+    for size in [disk.logical_block_size, disk.physical_block_size]
+        .into_iter()
+        .flatten()
+    {
+        if !size.is_power_of_two() || !(512..=2 << 20).contains(&size) {
+            bail!(
+                "Target '{}' disk '{}' has invalid block size {}. Expected a power of 2 between 512 and 2097152",
+                target.name,
+                disk.name,
+                size
+            );
+        }
+    }
+    if let (Some(logical), Some(physical)) = (disk.logical_block_size, disk.physical_block_size) {
+        if physical < logical {
+            bail!(
+                "Target '{}' disk '{}' physical block size must not be smaller than logical block size",
+                target.name,
+                disk.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Validate the CPU and memory layout of `target`
+fn validate_cpu_memory(target: &Target) -> Result<()> {
+    let vm = &target.vm;
+    if vm.num_cpus == 0 {
+        bail!("Target '{}' must have at least 1 CPU", target.name);
+    }
+
+    // Hotpluggable CPUs count towards the topology and NUMA nodes
+    let max_cpus = vm.max_cpus.unwrap_or(vm.num_cpus);
+    if vm.max_cpus.is_some() {
+        if max_cpus < vm.num_cpus {
+            bail!(
+                "Target '{}' max_cpus must be at least num_cpus",
+                target.name
+            );
+        }
+        if target.arch != "x86_64" {
+            bail!(
+                "Target '{}' CPU hotplug is only supported on x86_64",
+                target.name
+            );
+        }
+    }
+
+    if let Some(t) = &vm.topology {
+        if t.sockets == 0 || t.cores == 0 || t.threads == 0 {
+            bail!(
+                "Target '{}' topology must have at least 1 socket, core and thread",
+                target.name
+            );
+        }
+        if t.cpus() != u32::from(max_cpus) {
+            bail!(
+                "Target '{}' topology has {} CPUs but {} is {}",
+                target.name,
+                t.cpus(),
+                if vm.max_cpus.is_some() {
+                    "max_cpus"
+                } else {
+                    "num_cpus"
+                },
+                max_cpus
+            );
+        }
+    }
+
+    if let Some(backend) = &vm.memory_backend {
+        if backend.path.is_some() && backend.kind != MemoryBackendKind::Hugetlbfs {
+            bail!(
+                "Target '{}' memory_backend path requires kind hugetlbfs",
+                target.name
+            );
+        }
+        parse_memory(&vm.memory)
+            .with_context(|| format!("Target '{}' has invalid memory", target.name))?;
+    }
+
+    if vm.numa.is_empty() {
+        return Ok(());
+    }
+
+    let memory = parse_memory(&vm.memory)
+        .with_context(|| format!("Target '{}' has invalid memory", target.name))?;
+    let mut node_memory = 0;
+    let mut assigned = vec![false; usize::from(max_cpus)];
+    for (id, node) in vm.numa.iter().enumerate() {
+        node_memory += parse_memory(&node.memory).with_context(|| {
+            format!(
+                "Target '{}' NUMA node {} has invalid memory",
+                target.name, id
+            )
+        })?;
+
+        let ranges = match &node.cpus {
+            Some(cpus) => parse_cpu_list(cpus).with_context(|| {
+                format!("Target '{}' NUMA node {} has invalid cpus", target.name, id)
+            })?,
+            None => Vec::new(),
+        };
+        for cpu in ranges.into_iter().flat_map(|(start, end)| start..=end) {
+            match assigned.get_mut(usize::from(cpu)) {
+                None => bail!(
+                    "Target '{}' NUMA node {} has CPU {} but the VM only has {} CPUs",
+                    target.name,
+                    id,
+                    cpu,
+                    max_cpus
+                ),
+                Some(true) => bail!(
+                    "Target '{}' CPU {} is in more than one NUMA node",
+                    target.name,
+                    cpu
+                ),
+                Some(a) => *a = true,
+            }
+        }
+    }
+
+    if let Some(cpu) = assigned.iter().position(|a| !a) {
+        bail!(
+            "Target '{}' CPU {} is not in any NUMA node",
+            target.name,
+            cpu
+        );
+    }
+    if node_memory != memory {
+        bail!(
+            "Target '{}' NUMA nodes have {} bytes of memory but the VM has {}",
+            target.name,
+            node_memory,
+            memory
+        );
+    }
+
+    Ok(())
 }
 
 /// Validate the statically known config parameters
-fn validate_config(config: &Config) -> Result<()> {
+pub(crate) fn validate_config(config: &Config) -> Result<()> {
+    // Unwrap should never fail b/c the regex is static
+    let user_re = Regex::new(r"^([A-Za-z_][A-Za-z0-9._-]*\$?|[0-9]+:[0-9]+)$").unwrap();
+    // Disk and controller names double as device serial numbers, which are
+    // limited to 20 bytes
+    let disk_name_re = Regex::new(r"^[A-Za-z0-9_]{1,20}$").unwrap();
+    // Disk formats are passed to QEMU inside a `-drive` option string
+    let disk_format_re = Regex::new(r"^[a-z0-9]+$").unwrap();
+
     for (idx, target) in config.target.iter().enumerate() {
         if target.name.is_empty() {
             bail!("Target index={} name empty", idx);
         }
 
+        if !arch_supported(&target.arch) {
+            bail!(
+                "Target '{}' has unsupported arch '{}'. Supported arches: {}",
+                target.name,
+                target.arch,
+                supported_arches().collect::<Vec<_>>().join(", ")
+            );
+        }
+
         // Must choose image XOR kernel. We do not allow combining image and kernel
         // b/c images typically make use of initramfs to locate the root disk,
         // handle encrypted partitions, LVM, etc., and we cannot accurately guess
@@ -41,6 +340,96 @@ fn validate_config(config: &Config) -> Result<()> {
         if target.uefi && target.image.is_none() {
             bail!("Target '{}' must specify 'image' with 'uefi'", target.name);
         }
+        if target.uefi && !arch_supports_uefi(&target.arch) {
+            bail!(
+                "Target '{}' cannot use 'uefi' on arch '{}'",
+                target.name,
+                target.arch
+            );
+        }
+
+        if target.secure_boot {
+            if !target.uefi {
+                bail!(
+                    "Target '{}' must set 'uefi' with 'secure_boot'",
+                    target.name
+                );
+            }
+            if target.arch != "x86_64" {
+                bail!(
+                    "Target '{}' can only use 'secure_boot' on x86_64",
+                    target.name
+                );
+            }
+            // Secure Boot is a property of the firmware build we pick
+            if target.vm.bios.is_some() {
+                bail!(
+                    "Target '{}' cannot specify a bios with 'secure_boot'",
+                    target.name
+                );
+            }
+        }
+
+        // Kernel targets mount their rootfs over 9p during boot and QEMU
+        // refuses to migrate a guest with mounted 9p exports.
+        if target.snapshot && target.image.is_none() {
+            bail!(
+                "Target '{}' must specify 'image' with 'snapshot'",
+                target.name
+            );
+        }
+        // A restored guest would still refer to the disks attached to the run
+        // that saved the snapshot rather than the ones attached to this run.
+        if target.snapshot && !target.vm.disks.is_empty() {
+            bail!(
+                "Target '{}' cannot use 'disks' with 'snapshot'",
+                target.name
+            );
+        }
+
+        if let Some(cloud_init) = &target.cloud_init {
+            if target.image.is_none() {
+                bail!(
+                    "Target '{}' must specify 'image' with 'cloud_init'",
+                    target.name
+                );
+            }
+            if cloud_init.user_data.is_some()
+                && (!cloud_init.packages.is_empty() || cloud_init.services.is_some())
+            {
+                bail!(
+                    "Target '{}' cloud_init cannot combine 'user_data' with 'packages' or 'services'",
+                    target.name
+                );
+            }
+        }
+
+        validate_cpu_memory(target)?;
+
+        if target.vm.tpm {
+            if !["x86_64", "aarch64"].contains(&target.arch.as_str()) {
+                bail!(
+                    "Target '{}' cannot use 'tpm' on arch '{}'",
+                    target.name,
+                    target.arch
+                );
+            }
+            // A restored VM would expect TPM state that a fresh swtpm lacks
+            if target.snapshot {
+                bail!("Target '{}' cannot use 'tpm' with 'snapshot'", target.name);
+            }
+        }
+
+        match target.vm.tpm_model {
+            Some(_) if !target.vm.tpm => {
+                bail!("Target '{}' must set 'tpm' with 'tpm_model'", target.name)
+            }
+            Some(TpmModel::Crb) if target.arch != "x86_64" => bail!(
+                "Target '{}' can only use tpm_model 'crb' on x86_64",
+                target.name
+            ),
+            _ => (),
+        }
 
         if !target.uefi && target.vm.bios.is_some() {
             bail!(
@@ -56,6 +445,141 @@ fn validate_config(config: &Config) -> Result<()> {
             );
         }
 
+        if let Rootfs::FromHost { .. } = target.rootfs {
+            if target.kernel.is_none() {
+                bail!(
+                    "Target '{}' must specify 'kernel' with 'rootfs.from_host'",
+                    target.name
+                );
+            }
+        }
+
+        if let Some(overlay) = &target.rootfs_overlay {
+            if target.kernel.is_none() {
+                bail!(
+                    "Target '{}' must specify 'kernel' with 'rootfs_overlay'",
+                    target.name
+                );
+            }
+            if let Some(size) = &overlay.size {
+                parse_size(size).with_context(|| {
+                    format!("Target '{}' has invalid rootfs_overlay size", target.name)
+                })?;
+            }
+        }
+
+        let mut controllers = HashMap::new();
+        for controller in &target.vm.controllers {
+            if !disk_name_re.is_match(&controller.name) {
+                bail!(
+                    "Target '{}' has invalid controller name '{}'. Expected up to 20 letters, digits or underscores",
+                    target.name,
+                    controller.name
+                );
+            }
+            if controllers
+                .insert(controller.name.as_str(), controller.kind)
+                .is_some()
+            {
+                bail!(
+                    "Target '{}' has duplicate controller name '{}'",
+                    target.name,
+                    controller.name
+                );
+            }
+            if controller.queues == Some(0) {
+                bail!(
+                    "Target '{}' controller '{}' must have at least 1 queue",
+                    target.name,
+                    controller.name
+                );
+            }
+        }
+
+        // NVMe disks without a controller get one serialed after the disk,
+        // which the guest must be able to tell apart from named controllers
+        let mut nvme_serials = target
+            .vm
+            .controllers
+            .iter()
+            .filter(|c| c.kind == ControllerKind::Nvme)
+            .map(|c| c.name.as_str())
+            .collect::<HashSet<_>>();
+        let mut disk_vars = HashSet::new();
+        for disk in &target.vm.disks {
+            if !disk_name_re.is_match(&disk.name) {
+                bail!(
+                    "Target '{}' has invalid disk name '{}'. Expected up to 20 letters, digits or underscores",
+                    target.name,
+                    disk.name
+                );
+            }
+            if !disk_vars.insert(disk.env_var()) {
+                bail!(
+                    "Target '{}' has duplicate disk name '{}'",
+                    target.name,
+                    disk.name
+                );
+            }
+            if disk.bus() == DiskBus::Nvme
+                && disk.controller.is_none()
+                && !nvme_serials.insert(disk.name.as_str())
+            {
+                bail!(
+                    "Target '{}' NVMe disk '{}' must not share its name with an NVMe controller",
+                    target.name,
+                    disk.name
+                );
+            }
+
+            if let Some(format) = &disk.format {
+                if !disk_format_re.is_match(format) {
+                    bail!(
+                        "Target '{}' disk '{}' has invalid format '{}'. Expected lowercase letters or digits, like 'raw' or 'qcow2'",
+                        target.name,
+                        disk.name,
+                        format
+                    );
+                }
+            }
+
+            match (&disk.path, &disk.size) {
+                (Some(_), None) => (),
+                (None, Some(size)) => {
+                    parse_size(size).with_context(|| {
+                        format!(
+                            "Target '{}' disk '{}' has invalid size",
+                            target.name, disk.name
+                        )
+                    })?;
+                }
+                _ => bail!(
+                    "Target '{}' disk '{}' must specify exactly one of 'path' or 'size'",
+                    target.name,
+                    disk.name
+                ),
+            }
+
+            if disk.media == DiskMedia::Cdrom {
+                if disk.path.is_none() {
+                    bail!(
+                        "Target '{}' disk '{}' must specify 'path' with media 'cdrom'",
+                        target.name,
+                        disk.name
+                    );
+                }
+                if disk.bus() != DiskBus::VirtioScsi {
+                    bail!(
+                        "Target '{}' disk '{}' must use bus 'virtio-scsi' with media 'cdrom'",
+                        target.name,
+                        disk.name
+                    );
+                }
+            }
+
+            validate_disk_device(target, disk, &controllers)?;
+        }
+
         if let Some(image) = &target.image {
             if image.as_os_str().is_empty() {
                 bail!("Target '{}' has empty image path", target.name);
@@ -71,6 +595,16 @@ fn validate_config(config: &Config) -> Result<()> {
         if target.command.is_empty() {
             bail!("Target '{}' has empty command", target.name);
         }
+
+        if let Some(user) = &target.user {
+            if !user_re.is_match(user) {
+                bail!(
+                    "Target '{}' has invalid user '{}'. Expected a user name or uid:gid",
+                    target.name,
+                    user
+                );
+            }
+        }
     }
 
     Ok(())
@@ -87,9 +621,51 @@ impl Vmtest {
         Ok(Self {
             base: path.as_ref().to_owned(),
             config,
+            shell_on_failure: false,
+            canceller: Canceller::default(),
         })
     }
 
+    /// Construct a new instance from the config file at `path`
+    ///
+    /// If `path` is a directory, the `vmtest.toml` inside it is used.
+    /// Relative paths in the config are based off of the directory the config
+    /// file is in. Only the targets `filter` returns true for are kept and
+    /// validated, which is how the CLI selects targets.
+    pub fn from_path<P: AsRef<Path>>(path: P, filter: impl Fn(&Target) -> bool) -> Result<Self> {
+        let mut path = path.as_ref().to_owned();
+        if path.is_dir() {
+            path.push(CONFIG_FILE);
+        }
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut config: Config = toml::from_str(&contents).context("Failed to parse config")?;
+        config.target.retain(filter);
+
+        // Unwrap is safe b/c we just pushed a file name if nothing else
+        let base = path.parent().unwrap();
+        Self::new(base, config)
+    }
+
+    /// Attach an interactive shell to the VM when a target's command fails
+    ///
+    /// The shell runs in the same guest as the failed command, with the same
+    /// mounts and working directory. The VM is only torn down once the shell
+    /// exits.
+    ///
+    /// Default: false
+    pub fn set_shell_on_failure(&mut self, shell_on_failure: bool) {
+        self.shell_on_failure = shell_on_failure;
+    }
+
+    /// Returns a handle to cancel runs from another thread
+    ///
+    /// Cancelling shuts down the VM of the target that is currently running
+    /// and makes any later runs fail before booting.
+    pub fn canceller(&self) -> Canceller {
+        self.canceller.clone()
+    }
+
     /// Resolve an input path relative to the base path
     fn resolve_path(&self, input: &Path) -> PathBuf {
         if input.is_relative() {
@@ -107,8 +683,26 @@ impl Vmtest {
         &self.config.target
     }
 
+    /// Resolve an executable for a rootfs built from host executables
+    ///
+    /// Bare names are looked up in `$PATH` and paths are relative to the
+    /// config file.
+    fn resolve_executable(&self, exe: &str) -> Result<PathBuf> {
+        if exe.contains('/') {
+            // The rootfs mirrors host paths, which must be absolute
+            let path = self.resolve_path(Path::new(exe));
+            Ok(env::current_dir()
+                .context("Failed to get current directory")?
+                .join(path))
+        } else {
+            rootfs::which(exe).ok_or_else(|| anyhow!("Failed to find {exe} in $PATH"))
+        }
+    }
+
     /// Setups up a `Qemu` instance for a run
-    fn setup_qemu(&self, idx: usize, updates: Sender<Output>) -> Result<Qemu> {
+    ///
+    /// `shell` is whether a shell may be attached to the VM.
+    fn setup_qemu(&self, idx: usize, updates: Sink, shell: bool) -> Result<Qemu> {
         let mut target: Target = self
             .config
             .target
@@ -117,27 +711,799 @@ impl Vmtest {
             .clone();
         target.image = target.image.map(|s| self.resolve_path(s.as_path()));
         target.kernel = target.kernel.map(|s| self.resolve_path(s.as_path()));
-        target.rootfs = self.resolve_path(target.rootfs.as_path());
+        target.rootfs = match &target.rootfs {
+            Rootfs::Path(path) => {
+                // Validation ensures the arch is supported
+                let arch = oci_arch(&target.arch).unwrap_or_default();
+                rootfs::prepare(&self.resolve_path(path), arch)
+            }
+            Rootfs::FromHost { from_host } => from_host
+                .iter()
+                .map(|exe| self.resolve_executable(exe))
+                .collect::<Result<Vec<_>>>()
+                .and_then(|exes| rootfs::from_host(&exes)),
+        }
+        .map(Rootfs::Path)
+        .with_context(|| format!("Failed to prepare rootfs for '{}'", target.name))?;
         target.vm.bios = target.vm.bios.map(|s| self.resolve_path(s.as_path()));
+        if let Some(backend) = target.vm.memory_backend.as_mut() {
+            backend.path = backend.path.as_deref().map(|p| self.resolve_path(p));
+        }
         target.vm.mounts.iter_mut().for_each(|(_, m)| {
             m.host_path = self.resolve_path(m.host_path.as_path());
         });
+        target.vm.disks.iter_mut().for_each(|d| {
+            d.path = d.path.as_deref().map(|p| self.resolve_path(p));
+        });
+
+        let mut qemu =
+            Qemu::new(updates, target, &self.base, shell).context("Failed to setup QEMU")?;
+        qemu.set_canceller(self.canceller.clone());
 
-        Qemu::new(updates, target, &self.base).context("Failed to setup QEMU")
+        Ok(qemu)
     }
 
     /// Run a single target
     ///
     /// `idx` is the position of the target in the target list (0-indexed).
     ///
-    /// `updates` is the channel real time updates should be sent to. See
-    /// [`Output`] docs for more details.
-    pub fn run_one(&self, idx: usize, updates: Sender<Output>) {
-        match self.setup_qemu(idx, updates.clone()) {
+    /// `updates` is where real time updates should be sent to, for example
+    /// a channel. See [`Output`] docs for more details.
+    ///
+    /// Returns the outcome of the run once it is over.
+    pub fn run_one(&self, idx: usize, updates: impl IntoSink) -> RunResult {
+        let recorder = Arc::new(Recorder::default());
+        // `updates` goes last so it gets the original errors, not copies
+        let updates: Sink = Arc::new(Tee::new(vec![recorder.clone(), updates.into_sink()]));
+        let status = match self.setup_qemu(idx, updates.clone(), self.shell_on_failure) {
             Ok(q) => q.run(),
             Err(e) => {
-                let _ = updates.send(Output::BootEnd(Err(e)));
+                updates.send(Output::BootEnd(Err(e)));
+                None
+            }
+        };
+
+        recorder.finish(status)
+    }
+
+    /// Run a single target on a background thread
+    ///
+    /// `idx` is the position of the target in the target list (0-indexed).
+    ///
+    /// Unlike [`Vmtest::run_one`], this returns right away. The run has its
+    /// own [`Canceller`], independent of [`Vmtest::canceller`].
+    pub fn spawn(&self, idx: usize) -> RunHandle {
+        let mut vmtest = self.clone();
+        vmtest.canceller = Canceller::default();
+        let canceller = vmtest.canceller();
+
+        let (sender, events) = channel();
+        let waker: Arc<Mutex<Option<Waker>>> = Arc::default();
+        let updates = HandleSink {
+            sender: Mutex::new(sender),
+            waker: waker.clone(),
+        };
+        let thread = thread::spawn(move || vmtest.run_one(idx, updates));
+
+        RunHandle {
+            events,
+            waker,
+            canceller,
+            thread,
+        }
+    }
+
+    /// Boot a single target and attach the terminal to a login shell in it
+    ///
+    /// `idx` is the position of the target in the target list (0-indexed).
+    /// The target's command is not run.
+    ///
+    /// `updates` is where real time updates should be sent to. See
+    /// [`Output`] docs for more details.
+    pub fn shell(&self, idx: usize, updates: impl IntoSink) {
+        let updates = updates.into_sink();
+        match self.setup_qemu(idx, updates.clone(), true) {
+            Ok(q) => q.shell(),
+            Err(e) => updates.send(Output::BootEnd(Err(e))),
+        };
+    }
+
+    /// Boot a single target and keep it running
+    ///
+    /// `idx` is the position of the target in the target list (0-indexed).
+    ///
+    /// `updates` is where boot and setup updates should be sent to. See
+    /// [`Output`] docs for more details. Boot output continues to be sent
+    /// for as long as the VM is alive.
+    ///
+    /// Returns `None` if the VM failed to come up. The error is reported
+    /// through `updates`.
+    pub fn session(&self, idx: usize, updates: impl IntoSink) -> Option<Session> {
+        let updates = updates.into_sink();
+        let mut qemu = match self.setup_qemu(idx, updates.clone(), false) {
+            Ok(q) => q,
+            Err(e) => {
+                updates.send(Output::BootEnd(Err(e)));
+                return None;
+            }
+        };
+        let vm = qemu.start()?;
+
+        Some(Session { qemu, vm })
+    }
+}
+
+impl RunHandle {
+    /// Cancel the run
+    ///
+    /// Returns right away. The VM is shut down on a separate thread, see
+    /// [`Canceller::cancel_in_background`]. The run then finishes with an
+    /// error, which [`RunHandle::wait`] or the events report.
+    pub fn cancel(&self) {
+        self.canceller.cancel_in_background();
+    }
+
+    /// Returns a handle to cancel the run from elsewhere
+    pub fn canceller(&self) -> Canceller {
+        self.canceller.clone()
+    }
+
+    /// Whether or not the run has finished
+    ///
+    /// There may still be unread events.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Returns a blocking iterator over the run's events
+    ///
+    /// The iterator ends once the run has finished. See [`Output`] docs for
+    /// more details.
+    pub fn events(&self) -> impl Iterator<Item = Output> + '_ {
+        self.events.iter()
+    }
+
+    /// Returns the next event if there is one, without blocking
+    pub fn try_event(&self) -> Option<Output> {
+        self.events.try_recv().ok()
+    }
+
+    /// Poll for the next event
+    ///
+    /// Returns `Poll::Ready(None)` once the run has finished and all events
+    /// were read. Useful for driving a run from an async runtime, for
+    /// example with `std::future::poll_fn(|cx| handle.poll_event(cx)).await`.
+    pub fn poll_event(&self, cx: &mut TaskContext<'_>) -> Poll<Option<Output>> {
+        // Register before checking so an event sent in between still wakes us
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        match self.events.try_recv() {
+            Ok(msg) => Poll::Ready(Some(msg)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+        }
+    }
+
+    /// Wait for the run to finish
+    ///
+    /// Unread events are discarded. Returns the outcome of the run, or an
+    /// error if the run panicked.
+    pub fn wait(self) -> Result<RunResult> {
+        self.thread.join().map_err(|_| anyhow!("Run panicked"))
+    }
+}
+
+impl Session {
+    /// Run a command inside the session's VM
+    ///
+    /// The target's configured command is ignored; `command` is run in its
+    /// place with the same semantics.
+    ///
+    /// `updates` is where real time updates should be sent to. It receives
+    /// the `Command*` variants of [`Output`] and is dropped once the command
+    /// finishes, which hangs up channels.
+    pub fn exec(&mut self, command: &str, updates: impl IntoSink) {
+        self.qemu.set_updates(updates.into_sink());
+        self.qemu.exec(&mut self.vm, command);
+        // Hang up so the receiver knows the command is done
+        self.qemu.set_updates(channel().0.into_sink());
+    }
+
+    /// Returns a handle to cancel the session from elsewhere
+    ///
+    /// Cancelling shuts the VM down. Commands running at the time fail.
+    pub fn canceller(&self) -> Canceller {
+        self.qemu.canceller()
+    }
+
+    /// Sync guest filesystems and shut down the VM
+    pub fn stop(mut self) {
+        self.qemu.stop(self.vm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        CloudInit, Controller, MemoryBackend, MemoryBackendKind, NumaNode, Topology, VMConfig,
+    };
+    use crate::result::Stage;
+    use crate::run_dir::pid_alive;
+    use rstest::rstest;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Wake;
+    use std::time::{Duration, Instant};
+
+    #[rstest]
+    #[case("nobody", true)]
+    #[case("build-user_1", true)]
+    #[case("machine$", true)]
+    #[case("1000:1000", true)]
+    #[case("", false)]
+    #[case("1000", false)]
+    #[case("1000:", false)]
+    #[case("user; rm -rf /", false)]
+    #[case("user\"", false)]
+    fn test_validate_user(#[case] user: &str, #[case] valid: bool) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                kernel: Some("bzImage".into()),
+                command: "true".into(),
+                user: Some(user.into()),
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    fn scratch(name: &str) -> Disk {
+        Disk {
+            name: name.into(),
+            size: Some("1G".into()),
+            ..Default::default()
+        }
+    }
+
+    #[rstest]
+    #[case(vec![scratch("scratch"), scratch("other")], true)]
+    #[case(vec![scratch("a_name_that_is_way_too_long")], false)]
+    #[case(vec![scratch("with-dash")], false)]
+    #[case(vec![scratch("dup"), scratch("DUP")], false)]
+    #[case(vec![Disk { size: Some("lots".into()), ..scratch("bad_size") }], false)]
+    #[case(vec![Disk { path: Some("disk.img".into()), ..scratch("both") }], false)]
+    #[case(vec![Disk { size: None, ..scratch("neither") }], false)]
+    #[case(vec![Disk { format: Some("qcow2".into()), ..scratch("qcow2") }], true)]
+    #[case(vec![Disk { format: Some("raw,file=/etc/shadow".into()), ..scratch("inject") }], false)]
+    #[case(vec![Disk { format: Some("".into()), ..scratch("empty_format") }], false)]
+    #[case(vec![Disk { path: Some("a.iso".into()), size: None, media: DiskMedia::Cdrom, ..scratch("cd") }], true)]
+    #[case(vec![Disk { media: DiskMedia::Cdrom, ..scratch("scratch_cd") }], false)]
+    #[case(vec![Disk { path: Some("a.iso".into()), size: None, media: DiskMedia::Cdrom, bus: Some(DiskBus::Nvme), ..scratch("nvme_cd") }], false)]
+    fn test_validate_disks(#[case] disks: Vec<Disk>, #[case] valid: bool) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                kernel: Some("bzImage".into()),
+                command: "true".into(),
+                vm: VMConfig {
+                    disks,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case(Some("image.qcow2"), vec![], true)]
+    #[case(None, vec![], false)]
+    #[case(Some("image.qcow2"), vec![scratch("scratch")], false)]
+    fn test_validate_snapshot(
+        #[case] image: Option<&str>,
+        #[case] disks: Vec<Disk>,
+        #[case] valid: bool,
+    ) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                image: image.map(Into::into),
+                kernel: image.is_none().then(|| "bzImage".into()),
+                snapshot: true,
+                command: "true".into(),
+                vm: VMConfig {
+                    disks,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    fn controller(name: &str, kind: ControllerKind) -> Controller {
+        Controller {
+            name: name.into(),
+            kind,
+            queues: None,
+        }
+    }
+
+    #[rstest]
+    #[case(vec![], vec![Disk { queues: Some(4), ..scratch("blk") }], true)]
+    #[case(vec![], vec![Disk { queues: Some(0), ..scratch("blk") }], false)]
+    #[case(vec![], vec![Disk { bus: Some(DiskBus::Nvme), queues: Some(4), ..scratch("nvme") }], true)]
+    #[case(vec![], vec![Disk { bus: Some(DiskBus::VirtioScsi), queues: Some(4), ..scratch("scsi") }], false)]
+    #[case(
+        vec![controller("nvme0", ControllerKind::Nvme)],
+        vec![
+            Disk { bus: Some(DiskBus::Nvme), controller: Some("nvme0".into()), ..scratch("ns1") },
+            Disk { bus: Some(DiskBus::Nvme), controller: Some("nvme0".into()), ..scratch("ns2") },
+        ],
+        true
+    )]
+    #[case(
+        vec![controller("nvme0", ControllerKind::Nvme)],
+        vec![Disk { bus: Some(DiskBus::Nvme), controller: Some("nvme0".into()), queues: Some(2), ..scratch("ns") }],
+        false
+    )]
+    #[case(vec![], vec![Disk { bus: Some(DiskBus::Nvme), controller: Some("nvme0".into()), ..scratch("ns") }], false)]
+    #[case(
+        vec![controller("scsi", ControllerKind::VirtioScsi)],
+        vec![Disk { bus: Some(DiskBus::Nvme), controller: Some("scsi".into()), ..scratch("ns") }],
+        false
+    )]
+    #[case(
+        vec![controller("scsi", ControllerKind::VirtioScsi)],
+        vec![Disk { controller: Some("scsi".into()), ..scratch("blk") }],
+        false
+    )]
+    #[case(
+        vec![controller("dup", ControllerKind::Nvme), controller("dup", ControllerKind::VirtioScsi)],
+        vec![],
+        false
+    )]
+    #[case(vec![Controller { queues: Some(0), ..controller("nvme0", ControllerKind::Nvme) }], vec![], false)]
+    // The disk's own controller would share the serial of the named one
+    #[case(
+        vec![controller("nvme0", ControllerKind::Nvme)],
+        vec![
+            Disk { bus: Some(DiskBus::Nvme), controller: Some("nvme0".into()), ..scratch("ns1") },
+            Disk { bus: Some(DiskBus::Nvme), ..scratch("nvme0") },
+        ],
+        false
+    )]
+    #[case(
+        vec![controller("scsi0", ControllerKind::VirtioScsi)],
+        vec![Disk { bus: Some(DiskBus::Nvme), ..scratch("scsi0") }],
+        true
+    )]
+    #[case(vec![], vec![Disk { logical_block_size: Some(4096), physical_block_size: Some(4096), ..scratch("blk") }], true)]
+    #[case(vec![], vec![Disk { logical_block_size: Some(512), physical_block_size: Some(4096), ..scratch("blk") }], true)]
+    #[case(vec![], vec![Disk { logical_block_size: Some(4096), physical_block_size: Some(512), ..scratch("blk") }], false)]
+    #[case(vec![], vec![Disk { logical_block_size: Some(1000), ..scratch("blk") }], false)]
+    #[case(vec![], vec![Disk { physical_block_size: Some(256), ..scratch("blk") }], false)]
+    fn test_validate_storage(
+        #[case] controllers: Vec<Controller>,
+        #[case] disks: Vec<Disk>,
+        #[case] valid: bool,
+    ) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                kernel: Some("bzImage".into()),
+                command: "true".into(),
+                vm: VMConfig {
+                    disks,
+                    controllers,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case(true, CloudInit::default(), true)]
+    #[case(false, CloudInit::default(), false)]
+    #[case(true, CloudInit { user_data: Some("#cloud-config".into()), ..Default::default() }, true)]
+    #[case(true, CloudInit { user_data: Some("#cloud-config".into()), packages: vec!["fio".into()], ..Default::default() }, false)]
+    #[case(true, CloudInit { user_data: Some("#cloud-config".into()), services: Some(vec![]), ..Default::default() }, false)]
+    fn test_validate_cloud_init(
+        #[case] image: bool,
+        #[case] cloud_init: CloudInit,
+        #[case] valid: bool,
+    ) {
+        let (image, kernel) = if image {
+            (Some("image.qcow2".into()), None)
+        } else {
+            (None, Some("bzImage".into()))
+        };
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                image,
+                kernel,
+                command: "true".into(),
+                cloud_init: Some(cloud_init),
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case("x86_64", true, None, false, true)]
+    #[case("x86_64", true, Some(TpmModel::Crb), false, true)]
+    #[case("aarch64", true, Some(TpmModel::Tis), false, true)]
+    #[case("aarch64", true, Some(TpmModel::Crb), false, false)]
+    #[case("riscv64", true, None, false, false)]
+    #[case("x86_64", false, Some(TpmModel::Tis), false, false)]
+    #[case("x86_64", true, None, true, false)]
+    fn test_validate_tpm(
+        #[case] arch: &str,
+        #[case] tpm: bool,
+        #[case] tpm_model: Option<TpmModel>,
+        #[case] snapshot: bool,
+        #[case] valid: bool,
+    ) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                image: Some("image.qcow2".into()),
+                arch: arch.into(),
+                snapshot,
+                command: "true".into(),
+                vm: VMConfig {
+                    tpm,
+                    tpm_model,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case("x86_64", true, None, true)]
+    #[case("x86_64", false, None, false)]
+    #[case("aarch64", true, None, false)]
+    #[case("x86_64", true, Some("OVMF_CODE.fd"), false)]
+    fn test_validate_secure_boot(
+        #[case] arch: &str,
+        #[case] uefi: bool,
+        #[case] bios: Option<&str>,
+        #[case] valid: bool,
+    ) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                image: Some("image.qcow2".into()),
+                arch: arch.into(),
+                uefi,
+                secure_boot: true,
+                command: "true".into(),
+                vm: VMConfig {
+                    bios: bios.map(Into::into),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    fn node(cpus: Option<&str>, memory: &str) -> NumaNode {
+        NumaNode {
+            cpus: cpus.map(Into::into),
+            memory: memory.into(),
+        }
+    }
+
+    #[rstest]
+    #[case(4, None, vec![], true)]
+    #[case(0, None, vec![], false)]
+    #[case(8, Some(Topology { sockets: 2, cores: 2, threads: 2 }), vec![], true)]
+    #[case(4, Some(Topology { sockets: 2, cores: 2, threads: 2 }), vec![], false)]
+    #[case(4, Some(Topology { sockets: 0, ..Default::default() }), vec![], false)]
+    #[case(4, None, vec![node(Some("0-1"), "3G"), node(Some("2,3"), "1024")], true)]
+    #[case(4, None, vec![node(Some("0-3"), "2G"), node(None, "2G")], true)]
+    #[case(4, None, vec![node(Some("0-1"), "2G"), node(Some("2"), "2G")], false)]
+    #[case(4, None, vec![node(Some("0-2"), "2G"), node(Some("2-3"), "2G")], false)]
+    #[case(4, None, vec![node(Some("0-4"), "4G")], false)]
+    #[case(4, None, vec![node(Some("0-1"), "2G"), node(Some("2-3"), "1G")], false)]
+    #[case(4, None, vec![node(Some("3-0"), "4G")], false)]
+    #[case(4, None, vec![node(Some("0-3"), "lots")], false)]
+    fn test_validate_cpu_memory(
+        #[case] num_cpus: u16,
+        #[case] topology: Option<Topology>,
+        #[case] numa: Vec<NumaNode>,
+        #[case] valid: bool,
+    ) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                kernel: Some("bzImage".into()),
+                command: "true".into(),
+                vm: VMConfig {
+                    num_cpus,
+                    topology,
+                    memory: "4G".into(),
+                    numa,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case(2, Some(4), "x86_64", None, vec![], true)]
+    #[case(4, Some(4), "x86_64", None, vec![], true)]
+    #[case(4, Some(2), "x86_64", None, vec![], false)]
+    #[case(2, Some(4), "aarch64", None, vec![], false)]
+    #[case(2, Some(4), "x86_64", Some(Topology { sockets: 4, ..Default::default() }), vec![], true)]
+    #[case(2, Some(4), "x86_64", Some(Topology { sockets: 2, ..Default::default() }), vec![], false)]
+    #[case(2, Some(4), "x86_64", None, vec![node(Some("0-1"), "2G"), node(Some("2-3"), "2G")], true)]
+    #[case(2, Some(4), "x86_64", None, vec![node(Some("0"), "2G"), node(Some("1"), "2G")], false)]
+    fn test_validate_max_cpus(
+        #[case] num_cpus: u16,
+        #[case] max_cpus: Option<u16>,
+        #[case] arch: &str,
+        #[case] topology: Option<Topology>,
+        #[case] numa: Vec<NumaNode>,
+        #[case] valid: bool,
+    ) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                kernel: Some("bzImage".into()),
+                command: "true".into(),
+                arch: arch.into(),
+                vm: VMConfig {
+                    num_cpus,
+                    max_cpus,
+                    topology,
+                    memory: "4G".into(),
+                    numa,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case(MemoryBackendKind::Hugetlbfs, Some("/mnt/huge"), "4G", true)]
+    #[case(MemoryBackendKind::Hugetlbfs, None, "4096", true)]
+    #[case(MemoryBackendKind::Memfd, None, "4G", true)]
+    #[case(MemoryBackendKind::Memfd, Some("/mnt/huge"), "4G", false)]
+    #[case(MemoryBackendKind::Anonymous, None, "4GiB", false)]
+    fn test_validate_memory_backend(
+        #[case] kind: MemoryBackendKind,
+        #[case] path: Option<&str>,
+        #[case] memory: &str,
+        #[case] valid: bool,
+    ) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                kernel: Some("bzImage".into()),
+                command: "true".into(),
+                vm: VMConfig {
+                    memory: memory.into(),
+                    memory_backend: Some(MemoryBackend {
+                        kind,
+                        path: path.map(Into::into),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case("ppc64le", false, true)]
+    #[case("s390x", false, true)]
+    #[case("loongarch64", true, true)]
+    #[case("armv7", true, true)]
+    #[case("i386", true, true)]
+    #[case("s390x", true, false)]
+    #[case("mips64", false, false)]
+    fn test_validate_arch(#[case] arch: &str, #[case] uefi: bool, #[case] valid: bool) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                image: Some("image.qcow2".into()),
+                uefi,
+                command: "true".into(),
+                arch: arch.into(),
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case(Some("bzImage"), None, true)]
+    #[case(None, Some("image.qcow2"), false)]
+    fn test_validate_rootfs_from_host(
+        #[case] kernel: Option<&str>,
+        #[case] image: Option<&str>,
+        #[case] valid: bool,
+    ) {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                kernel: kernel.map(Into::into),
+                image: image.map(Into::into),
+                rootfs: Rootfs::FromHost {
+                    from_host: vec!["bash".into()],
+                },
+                command: "true".into(),
+                ..Default::default()
+            }],
+        };
+        assert_eq!(validate_config(&config).is_ok(), valid);
+    }
+
+    /// A vmtest whose only target fails to boot
+    fn unbootable() -> Vmtest {
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                kernel: Some("/nonexistent/kernel".into()),
+                qemu_command: Some("/nonexistent/qemu".into()),
+                command: "true".into(),
+                ..Default::default()
+            }],
+        };
+        Vmtest::new("/", config).unwrap()
+    }
+
+    #[test]
+    fn test_spawn() {
+        let handle = unbootable().spawn(0);
+        let events = handle.events().collect::<Vec<_>>();
+        assert!(matches!(events.last(), Some(Output::BootEnd(Err(_)))));
+        assert!(handle.try_event().is_none());
+        let result = handle.wait().unwrap();
+        assert!(!result.success());
+        assert_eq!(result.failure.unwrap().stage, Stage::Boot);
+        assert!(result.qemu_status.is_none());
+    }
+
+    #[test]
+    fn test_spawn_cancel() {
+        let vmtest = unbootable();
+        let handle = vmtest.spawn(0);
+        handle.cancel();
+        assert!(handle.canceller().is_cancelled());
+        // Runs are cancelled independently
+        assert!(!vmtest.canceller().is_cancelled());
+        assert!(handle.wait().unwrap().failure.is_some());
+    }
+
+    /// A vmtest whose only target runs `sleep` in place of QEMU
+    ///
+    /// The stub writes its pid to `pid` in `dir` once it is running.
+    fn hanging(dir: &Path) -> Vmtest {
+        let qemu = dir.join("qemu");
+        let script = format!(
+            "#!/bin/sh\n[ \"$1\" = --help ] && exit 0\necho $$ > '{}'\nexec sleep 600\n",
+            dir.join("pid").display()
+        );
+        fs::write(&qemu, script).unwrap();
+        fs::set_permissions(&qemu, fs::Permissions::from_mode(0o755)).unwrap();
+        let image = dir.join("image.raw");
+        fs::write(&image, "").unwrap();
+
+        let config = Config {
+            target: vec![Target {
+                name: "test".into(),
+                image: Some(image),
+                qemu_command: Some(qemu.to_string_lossy().into_owned()),
+                command: "true".into(),
+                ..Default::default()
+            }],
+        };
+        Vmtest::new("/", config).unwrap()
+    }
+
+    #[test]
+    fn test_spawn_cancel_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let handle = hanging(dir.path()).spawn(0);
+
+        let pid_file = dir.path().join("pid");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let pid = loop {
+            let pid = fs::read_to_string(&pid_file).ok();
+            if let Some(pid) = pid.and_then(|p| p.trim().parse::<u32>().ok()) {
+                break pid;
             }
+            assert!(Instant::now() < deadline, "QEMU stub did not start");
+            thread::sleep(Duration::from_millis(10));
         };
+        assert!(pid_alive(pid));
+
+        // Shutting QEMU down takes a while, which cancel() must not wait for
+        let cancelled = Instant::now();
+        handle.cancel();
+        assert!(cancelled.elapsed() < Duration::from_millis(500));
+
+        let failure = handle.wait().unwrap().failure.unwrap();
+        assert_eq!(failure.stage, Stage::Boot);
+        assert_eq!(failure.error.to_string(), "Run was cancelled");
+        assert!(!pid_alive(pid));
+    }
+
+    #[test]
+    fn test_spawn_poll_event() {
+        struct Flag(AtomicBool);
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = TaskContext::from_waker(&waker);
+        let handle = unbootable().spawn(0);
+        let mut events = 0;
+        loop {
+            match handle.poll_event(&mut cx) {
+                Poll::Ready(Some(_)) => events += 1,
+                Poll::Ready(None) => break,
+                Poll::Pending => {
+                    while !flag.0.swap(false, Ordering::SeqCst) {
+                        thread::yield_now();
+                    }
+                }
+            }
+        }
+        assert!(events > 0);
+    }
+
+    #[test]
+    fn test_from_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join(CONFIG_FILE),
+            r#"
+            [[target]]
+            name = "kernel"
+            kernel = "bzImage"
+            command = "true"
+            [[target]]
+            name = "broken"
+            command = "true"
+            "#,
+        )
+        .unwrap();
+
+        // Invalid targets are fine as long as they are filtered out
+        assert!(Vmtest::from_path(dir.path(), |_| true).is_err());
+        let vmtest = Vmtest::from_path(dir.path(), |t| t.name == "kernel").unwrap();
+        assert_eq!(vmtest.targets().len(), 1);
+        assert_eq!(
+            vmtest.resolve_path(Path::new("bzImage")),
+            dir.path().join("bzImage")
+        );
+
+        let vmtest =
+            Vmtest::from_path(dir.path().join(CONFIG_FILE), |t| t.name == "kernel").unwrap();
+        assert_eq!(vmtest.targets().len(), 1);
+        assert!(Vmtest::from_path(dir.path().join("nonexistent.toml"), |_| true).is_err());
     }
 }
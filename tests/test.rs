@@ -1,8 +1,9 @@
-use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::path::Path;
+use std::process::Command;
 use std::sync::mpsc::channel;
+use std::thread;
 
 use tempfile::{tempdir, tempdir_in};
 use test_log::test;
@@ -10,7 +11,10 @@ use test_log::test;
 use vmtest::output::Output;
 use vmtest::ui::Ui;
 use vmtest::Mount;
-use vmtest::{Config, Target, VMConfig};
+use vmtest::{
+    Config, Controller, ControllerKind, Disk, DiskBus, MemoryBackend, MemoryBackendKind, NumaNode,
+    OverlayUpper, Rootfs, RootfsOverlay, Target, Topology, VMConfig,
+};
 
 mod helpers;
 use helpers::*;
@@ -18,24 +22,24 @@ use helpers::*;
 // Expect that we can run the entire matrix successfully
 #[test]
 fn test_run() {
-    let config = Config {
-        target: vec![
-            Target {
-                name: "uefi image boots with uefi flag".to_string(),
-                image: Some(asset("image-uefi.raw-efi")),
-                uefi: true,
-                command: "/mnt/vmtest/main.sh nixos".to_string(),
-                ..Default::default()
-            },
-            Target {
-                name: "not uefi image boots without uefi flag".to_string(),
-                image: Some(asset("image-not-uefi.raw")),
-                uefi: false,
-                command: "/mnt/vmtest/main.sh nixos".to_string(),
-                ..Default::default()
-            },
-        ],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("uefi image boots with uefi flag")
+                .image(asset("image-uefi.raw-efi"))
+                .uefi(true)
+                .command("/mnt/vmtest/main.sh nixos")
+                .build()
+                .unwrap(),
+        )
+        .target(
+            Target::builder("not uefi image boots without uefi flag")
+                .image(asset("image-not-uefi.raw"))
+                .command("/mnt/vmtest/main.sh nixos")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &["main.sh"]);
     let ui = Ui::new(vmtest);
     let failed = ui.run(false);
@@ -45,31 +49,32 @@ fn test_run() {
 // Expect that when we run multiple targets, we get the correct number of failures.
 #[test]
 fn test_run_multiple_return_number_failures() {
-    let config = Config {
-        target: vec![
-            Target {
-                name: "uefi image boots with uefi flag".to_string(),
-                image: Some(asset("image-uefi.raw-efi")),
-                uefi: true,
-                command: "exit 1".to_string(),
-                ..Default::default()
-            },
-            Target {
-                name: "uefi image boots with uefi flag 2".to_string(),
-                image: Some(asset("image-uefi.raw-efi")),
-                uefi: true,
-                command: "exit 1".to_string(),
-                ..Default::default()
-            },
-            Target {
-                name: "not uefi image boots without uefi flag".to_string(),
-                image: Some(asset("image-not-uefi.raw")),
-                uefi: false,
-                command: "/mnt/vmtest/main.sh nixos".to_string(),
-                ..Default::default()
-            },
-        ],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("uefi image boots with uefi flag")
+                .image(asset("image-uefi.raw-efi"))
+                .uefi(true)
+                .command("exit 1")
+                .build()
+                .unwrap(),
+        )
+        .target(
+            Target::builder("uefi image boots with uefi flag 2")
+                .image(asset("image-uefi.raw-efi"))
+                .uefi(true)
+                .command("exit 1")
+                .build()
+                .unwrap(),
+        )
+        .target(
+            Target::builder("not uefi image boots without uefi flag")
+                .image(asset("image-not-uefi.raw"))
+                .command("/mnt/vmtest/main.sh nixos")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &["main.sh"]);
     let ui = Ui::new(vmtest);
     let failed = ui.run(false);
@@ -79,15 +84,16 @@ fn test_run_multiple_return_number_failures() {
 // Expect that when we run a single target, we return the return code of the command.
 #[test]
 fn test_run_single_return_number_return_code() {
-    let config = Config {
-        target: vec![Target {
-            name: "not uefi image boots without uefi flag".to_string(),
-            image: Some(asset("image-not-uefi.raw")),
-            uefi: false,
-            command: "exit 12".to_string(),
-            ..Default::default()
-        }],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("not uefi image boots without uefi flag")
+                .image(asset("image-not-uefi.raw"))
+                .command("exit 12")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &["main.sh"]);
     let ui = Ui::new(vmtest);
     let failed = ui.run(false);
@@ -97,15 +103,16 @@ fn test_run_single_return_number_return_code() {
 // Expect that when we fail to start the vm, we return 69 (EX_UNAVAILABLE).
 #[test]
 fn test_vmtest_infra_error() {
-    let config = Config {
-        target: vec![Target {
-            name: "not an actual image, should return EX_UNAVAILABLE".to_string(),
-            image: Some(asset("not_an_actual_image")),
-            uefi: false,
-            command: "exit 12".to_string(),
-            ..Default::default()
-        }],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("not an actual image, should return EX_UNAVAILABLE")
+                .image(asset("not_an_actual_image"))
+                .command("exit 12")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &["main.sh"]);
     let ui = Ui::new(vmtest);
     let failed = ui.run(false);
@@ -118,24 +125,24 @@ fn test_run_one() {
     let uefi_image = create_new_image(asset("image-uefi.raw-efi"));
     let non_uefi_image = create_new_image(asset("image-not-uefi.raw"));
 
-    let config = Config {
-        target: vec![
-            Target {
-                name: "uefi image boots with uefi flag".to_string(),
-                image: Some(uefi_image.as_pathbuf()),
-                uefi: true,
-                command: "/mnt/vmtest/main.sh nixos".to_string(),
-                ..Default::default()
-            },
-            Target {
-                name: "not uefi image boots without uefi flag".to_string(),
-                image: Some(non_uefi_image.as_pathbuf()),
-                uefi: false,
-                command: "/mnt/vmtest/main.sh nixos".to_string(),
-                ..Default::default()
-            },
-        ],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("uefi image boots with uefi flag")
+                .image(uefi_image.as_pathbuf())
+                .uefi(true)
+                .command("/mnt/vmtest/main.sh nixos")
+                .build()
+                .unwrap(),
+        )
+        .target(
+            Target::builder("not uefi image boots without uefi flag")
+                .image(non_uefi_image.as_pathbuf())
+                .command("/mnt/vmtest/main.sh nixos")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &["main.sh"]);
     for i in 0..2 {
         let (send, recv) = channel();
@@ -150,24 +157,24 @@ fn test_run_out_of_bounds() {
     let uefi_image = create_new_image(asset("image-uefi.raw-efi"));
     let non_uefi_image = create_new_image(asset("image-not-uefi.raw"));
 
-    let config = Config {
-        target: vec![
-            Target {
-                name: "uefi image boots with uefi flag".to_string(),
-                image: Some(uefi_image.as_pathbuf()),
-                uefi: true,
-                command: "/mnt/vmtest/main.sh nixos".to_string(),
-                ..Default::default()
-            },
-            Target {
-                name: "not uefi image boots without uefi flag".to_string(),
-                image: Some(non_uefi_image.as_pathbuf()),
-                uefi: false,
-                command: "/mnt/vmtest/main.sh nixos".to_string(),
-                ..Default::default()
-            },
-        ],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("uefi image boots with uefi flag")
+                .image(uefi_image.as_pathbuf())
+                .uefi(true)
+                .command("/mnt/vmtest/main.sh nixos")
+                .build()
+                .unwrap(),
+        )
+        .target(
+            Target::builder("not uefi image boots without uefi flag")
+                .image(non_uefi_image.as_pathbuf())
+                .command("/mnt/vmtest/main.sh nixos")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &["main.sh"]);
     let (send, recv) = channel();
     vmtest.run_one(2, send);
@@ -179,15 +186,16 @@ fn test_run_out_of_bounds() {
 fn test_not_uefi() {
     let uefi_image = create_new_image(asset("image-uefi.raw-efi"));
 
-    let config = Config {
-        target: vec![Target {
-            name: "uefi image does not boot without uefi flag".to_string(),
-            image: Some(uefi_image.as_pathbuf()),
-            uefi: false,
-            command: "echo unreachable".to_string(),
-            ..Default::default()
-        }],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("uefi image does not boot without uefi flag")
+                .image(uefi_image.as_pathbuf())
+                .command("echo unreachable")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &["main.sh"]);
     let (send, recv) = channel();
     vmtest.run_one(0, send);
@@ -196,16 +204,18 @@ fn test_not_uefi() {
 
 #[test]
 fn test_command_runs_in_shell() {
-    let config = Config {
-        target: vec![Target {
-            name: "command is run in shell".to_string(),
-            kernel: Some(asset("bzImage-v5.15-default")),
-            // `$0` is a portable way of getting the name of the shell without relying
-            // on env vars which may be propagated from the host into the guest.
-            command: "if true; then echo -n $0 > /mnt/vmtest/result; fi".to_string(),
-            ..Default::default()
-        }],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("command is run in shell")
+                .kernel(asset("bzImage-v5.15-default"))
+                // `$0` is a portable way of getting the name of the shell without relying
+                // on env vars which may be propagated from the host into the guest.
+                .command("if true; then echo -n $0 > /mnt/vmtest/result; fi")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, dir) = setup(config, &[]);
     let (send, recv) = channel();
     vmtest.run_one(0, send);
@@ -252,18 +262,119 @@ fn test_interactive_shell() {
     assert_eq!(result, "hello world\n");
 }
 
+// Validate that `vmtest shell` gives an interactive shell in image targets,
+// with the shared directory already mounted.
+#[test]
+fn test_shell_subcommand_image() {
+    let image = create_new_image(asset("image-uefi.raw-efi"));
+    let dir = tempdir().expect("Failed to create tempdir");
+    let config_path = dir.path().join("vmtest.toml");
+    fs::write(
+        &config_path,
+        format!(
+            r#"
+            [[target]]
+            name = "image"
+            image = "{}"
+            uefi = true
+            command = "false"
+            "#,
+            image.as_pathbuf().display()
+        ),
+    )
+    .expect("Failed to write config");
+
+    let vmtest_bin_path = Path::new(env!("CARGO_BIN_EXE_vmtest"));
+    let command = format!(
+        "{} -c {} shell image",
+        vmtest_bin_path
+            .to_str()
+            .expect("Failed to convert vmtest path to str"),
+        config_path.display(),
+    );
+    let mut p = rexpect::spawn(&command, Some(60000)).expect("Failed to spawn vmtest");
+    p.exp_regex(".*root@.*#.*")
+        .expect("Did not get shell prompt");
+    p.send_line("echo \"hello world\" > /mnt/vmtest/result")
+        .expect("Failed to send echo command");
+    p.exp_regex(".*root@.*#.*")
+        .expect("Did not get shell prompt after sending command.");
+    p.send_line("exit").expect("Failed to send exit command.");
+    p.exp_eof().expect("vmtest did not return EOF");
+
+    let result_path = dir.path().join("result");
+    let result = fs::read_to_string(result_path).expect("Failed to read result");
+    assert_eq!(result, "hello world\n");
+}
+
+// Validate that a failing command drops into a shell in the same guest when
+// `--shell-on-failure` is passed.
+#[test]
+fn test_shell_on_failure() {
+    let dir = tempdir().expect("Failed to create tempdir");
+    assert!(env::set_current_dir(dir.path()).is_ok());
+    let vmtest_bin_path = Path::new(env!("CARGO_BIN_EXE_vmtest"));
+    let command = format!(
+        "{} --shell-on-failure --kernel {} 'touch /tmp/marker && false'",
+        vmtest_bin_path
+            .to_str()
+            .expect("Failed to convert vmtest path to str"),
+        asset("bzImage-v5.15-default").to_str().unwrap(),
+    );
+    let mut p = rexpect::spawn(&command, Some(30000)).expect("Failed to spawn vmtest");
+    p.exp_regex(".*root@.*#.*")
+        .expect("Did not get shell prompt");
+    // State left behind by the failed command should be visible
+    p.send_line("[[ -e /tmp/marker ]] && pwd > /mnt/vmtest/result")
+        .expect("Failed to send command");
+    p.exp_regex(".*root@.*#.*")
+        .expect("Did not get shell prompt after sending command.");
+    p.send_line("exit").expect("Failed to send exit command.");
+    p.exp_eof().expect("vmtest did not return EOF");
+
+    // Shell runs in the same working directory as the command
+    let result_path = dir.path().join("result");
+    let result = fs::read_to_string(result_path).expect("Failed to read result");
+    assert_eq!(result.trim(), dir.path().to_str().unwrap());
+}
+
+// Validate that `--bench` boots the target once per run and summarizes how
+// long booting took.
+#[test]
+fn test_bench() {
+    let output = Command::new(env!("CARGO_BIN_EXE_vmtest"))
+        .arg("--kernel")
+        .arg(asset("bzImage-v5.15-default"))
+        .arg("--bench")
+        .arg("2")
+        .output()
+        .expect("Failed to run vmtest");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "vmtest failed: {stdout}");
+
+    for run in ["Run 1/2: spawn", "Run 2/2: spawn"] {
+        assert!(stdout.contains(run), "Missing {run:?}: {stdout}");
+    }
+    for stage in ["spawn", "boot", "total"] {
+        let stats = format!("{stage}: min ");
+        assert!(stdout.contains(&stats), "Missing {stats:?}: {stdout}");
+    }
+}
+
 // Tests that for kernel targets, environment variables from the host are propagated
 // into the guest.
 #[test]
 fn test_kernel_target_env_var_propagation() {
-    let config = Config {
-        target: vec![Target {
-            name: "host env vars are propagated into guest".to_string(),
-            kernel: Some(asset("bzImage-v5.15-default")),
-            command: "echo -n $TEST_ENV_VAR > /mnt/vmtest/result".to_string(),
-            ..Default::default()
-        }],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("host env vars are propagated into guest")
+                .kernel(asset("bzImage-v5.15-default"))
+                .command("echo -n $TEST_ENV_VAR > /mnt/vmtest/result")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
 
     // Set test env var
     env::set_var("TEST_ENV_VAR", "test value");
@@ -282,14 +393,16 @@ fn test_kernel_target_env_var_propagation() {
 // Tests that for kernel targets, current working directory is preserved in the guest
 #[test]
 fn test_kernel_target_cwd_preserved() {
-    let config = Config {
-        target: vec![Target {
-            name: "host cwd preserved in guest".to_string(),
-            kernel: Some(asset("bzImage-v5.15-default")),
-            command: "cat text_file.txt".to_string(),
-            ..Default::default()
-        }],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("host cwd preserved in guest")
+                .kernel(asset("bzImage-v5.15-default"))
+                .command("cat text_file.txt")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
 
     // Calculate source fixture directory and pass it in as the base path
     // to `Vmtest`. The base path is what controls the working directory.
@@ -308,16 +421,18 @@ fn test_kernel_target_cwd_preserved() {
 
 #[test]
 fn test_command_process_substitution() {
-    let config = Config {
-        target: vec![Target {
-            name: "command can run process substitution".to_string(),
-            kernel: Some(asset("bzImage-v5.15-default")),
-            // `$0` is a portable way of getting the name of the shell without relying
-            // on env vars which may be propagated from the host into the guest.
-            command: "cat <(echo -n $0) > /mnt/vmtest/result".to_string(),
-            ..Default::default()
-        }],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("command can run process substitution")
+                .kernel(asset("bzImage-v5.15-default"))
+                // `$0` is a portable way of getting the name of the shell without relying
+                // on env vars which may be propagated from the host into the guest.
+                .command("cat <(echo -n $0) > /mnt/vmtest/result")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, dir) = setup(config, &[]);
     let (send, recv) = channel();
     vmtest.run_one(0, send);
@@ -331,14 +446,16 @@ fn test_command_process_substitution() {
 
 #[test]
 fn test_qemu_error_shown() {
-    let config = Config {
-        target: vec![Target {
-            name: "invalid kernel path".to_string(),
-            kernel: Some(asset("doesn't exist")),
-            command: "true".to_string(),
-            ..Default::default()
-        }],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("invalid kernel path")
+                .kernel(asset("doesn't exist"))
+                .command("true")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &[]);
     let (send, recv) = channel();
     vmtest.run_one(0, send);
@@ -348,6 +465,134 @@ fn test_qemu_error_shown() {
     assert!(msg.contains("qemu: could not open kernel file"));
 }
 
+// Test that a rootfs tarball built for another arch is rejected before boot
+#[test]
+fn test_foreign_rootfs_rejected() {
+    let dir = tempdir().expect("Failed to create tempdir");
+    let src = dir.path().join("rootfs");
+    fs::create_dir_all(src.join("usr/bin")).unwrap();
+    std::os::unix::fs::symlink("usr/bin", src.join("bin")).unwrap();
+    fs::copy("/proc/self/exe", src.join("usr/bin/bash")).unwrap();
+    fs::copy("/proc/self/exe", src.join("usr/bin/qemu-ga")).unwrap();
+    let tarball = dir.path().join("rootfs.tar");
+    let status = std::process::Command::new("tar")
+        .arg("-cf")
+        .arg(&tarball)
+        .arg("-C")
+        .arg(&src)
+        .arg(".")
+        .status()
+        .expect("Failed to run tar");
+    assert!(status.success());
+
+    let arch = if env::consts::ARCH == "aarch64" {
+        "x86_64"
+    } else {
+        "aarch64"
+    };
+    let config = Config::builder()
+        .target(
+            Target::builder("foreign rootfs")
+                .kernel(asset("bzImage-v5.15-default"))
+                .rootfs(tarball)
+                .arch(arch)
+                .command("true")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+    let (send, recv) = channel();
+    vmtest.run_one(0, send);
+
+    let err = assert_get_err!(recv, Output::BootEnd);
+    let msg = format!("{:?}", err);
+    assert!(msg.contains(&format!("but the target arch is {arch}")));
+}
+
+// Test that a rootfs built from host executables boots and only has those
+#[test]
+fn test_kernel_rootfs_from_host() {
+    let config = Config::builder()
+        .target(
+            Target::builder("rootfs from host")
+                .kernel(asset("bzImage-v5.15-default"))
+                .rootfs(Rootfs::FromHost {
+                    from_host: vec!["uname".into()],
+                })
+                .command("bash -xc \"uname -r && [[ ! -e /etc/os-release ]]\"")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+    let (send, recv) = channel();
+    vmtest.run_one(0, send);
+    assert_no_err!(recv);
+}
+
+// Test that a cancelled run shuts the VM down instead of waiting on the command
+#[test]
+fn test_kernel_cancel() {
+    let config = Config::builder()
+        .target(
+            Target::builder("cancel kernel")
+                .kernel(asset("bzImage-v5.15-default"))
+                .command("sleep 600")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+    let canceller = vmtest.canceller();
+    let (send, recv) = channel();
+    let run = thread::spawn(move || vmtest.run_one(0, send));
+
+    loop {
+        match recv.recv().expect("Run hung up before the command started") {
+            Output::CommandStart => break,
+            Output::BootEnd(Err(e)) | Output::SetupEnd(Err(e)) => panic!("{:?}", e),
+            _ => (),
+        }
+    }
+    canceller.cancel();
+
+    let err = get_error(recv, None).expect("Cancelled run did not fail");
+    assert!(err.to_string().contains("cancelled"), "{:?}", err);
+    run.join().expect("Failed to join run");
+    assert!(canceller.is_cancelled());
+}
+
+// Test that a spawned run reports the command's exit code
+#[test]
+fn test_kernel_spawn() {
+    let config = Config::builder()
+        .target(
+            Target::builder("spawn kernel")
+                .kernel(asset("bzImage-v5.15-default"))
+                .command("echo hello; exit 3")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+    let handle = vmtest.spawn(0);
+    let result = handle.wait().expect("Run panicked");
+    assert!(result.failure.is_none(), "{:?}", result.failure);
+    assert_eq!(result.exit_code, Some(3));
+    assert!(result.output.iter().any(|l| l == "hello"));
+    assert!(!result.console.is_empty());
+    assert!(result.timings.spawn.is_some());
+    assert!(result.timings.boot.is_some());
+    assert!(result.timings.setup.is_some());
+    assert!(result.timings.command.is_some());
+    assert!(result.qemu_status.is_some());
+}
+
 // Test that host FS cannot be written to if `ro` flag is passed to guest kernel args
 #[test]
 fn test_kernel_ro_flag() {
@@ -355,54 +600,267 @@ fn test_kernel_ro_flag() {
     let root = Path::new(env!("CARGO_MANIFEST_DIR"));
     let touch_dir = tempdir_in(root).expect("Failed to create tempdir");
 
-    let config = Config {
-        target: vec![Target {
-            name: "cannot touch host rootfs with ro".to_string(),
-            kernel: Some(asset("bzImage-v5.15-default")),
-            kernel_args: Some("ro".to_string()),
-            command: format!("touch {}/file", touch_dir.path().display()),
-            ..Default::default()
-        }],
-    };
+    let config = Config::builder()
+        .target(
+            Target::builder("cannot touch host rootfs with ro")
+                .kernel(asset("bzImage-v5.15-default"))
+                .kernel_args("ro")
+                .command(format!("touch {}/file", touch_dir.path().display()))
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &[]);
     let (send, recv) = channel();
     vmtest.run_one(0, send);
     assert_err!(recv, Output::CommandEnd, i64);
 }
 
+#[test]
+fn test_run_as_user() {
+    let image = create_new_image(asset("image-uefi.raw-efi"));
+    let command =
+        r#"bash -xc "[[ $(id -un) == nobody && $HOME == $(getent passwd nobody | cut -d: -f6) ]]""#;
+    let config = Config::builder()
+        .target(
+            Target::builder("run as user name")
+                .image(image.as_pathbuf())
+                .uefi(true)
+                .command(command)
+                .user("nobody")
+                .build()
+                .unwrap(),
+        )
+        .target(
+            Target::builder("run as uid:gid")
+                .kernel(asset("bzImage-v5.15-default"))
+                .command(r#"bash -xc "[[ $(id -u) == 1234 && $(id -g) == 5678 ]]""#)
+                .user("1234:5678")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+
+    for idx in 0..2 {
+        let (send, recv) = channel();
+        vmtest.run_one(idx, send);
+        assert_no_err!(recv);
+    }
+}
+
+#[test]
+fn test_kernel_rootfs_overlay() {
+    // Cannot place this dir in tmpfs b/c vmtest will mount over host /tmp with a new tmpfs
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let touch_dir = tempdir_in(root).expect("Failed to create tempdir");
+
+    let config = Config::builder()
+        .target(
+            Target::builder("tmpfs overlay is writable")
+                .kernel(asset("bzImage-v5.15-default"))
+                .rootfs_overlay(RootfsOverlay::default())
+                .command(format!("touch {}/file", touch_dir.path().display()))
+                .build()
+                .unwrap(),
+        )
+        .target(
+            Target::builder("disk overlay is writable")
+                .kernel(asset("bzImage-v5.15-default"))
+                .rootfs_overlay(RootfsOverlay {
+                    upper: OverlayUpper::Disk,
+                    size: Some("1G".into()),
+                })
+                .command(format!("touch {}/file", touch_dir.path().display()))
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+
+    for idx in 0..2 {
+        let (send, recv) = channel();
+        vmtest.run_one(idx, send);
+        assert_no_err!(recv);
+    }
+
+    // Guest writes must not leak onto the host
+    assert!(!touch_dir.path().join("file").exists());
+}
+
+#[test]
+fn test_run_extra_disks() {
+    let image = create_new_image(asset("image-uefi.raw-efi"));
+    let vm = VMConfig::builder()
+        .disk(Disk {
+            name: "blk".into(),
+            size: Some("64M".into()),
+            ..Default::default()
+        })
+        .disk(Disk {
+            name: "scsi".into(),
+            size: Some("32M".into()),
+            format: Some("qcow2".into()),
+            bus: Some(DiskBus::VirtioScsi),
+            ..Default::default()
+        })
+        .disk(Disk {
+            name: "nvme".into(),
+            size: Some("16M".into()),
+            readonly: true,
+            bus: Some(DiskBus::Nvme),
+            ..Default::default()
+        })
+        .build();
+    let command = r#"bash -xc "
+        [[ $(blockdev --getsize64 $VMTEST_DISK_BLK) == $((64 << 20)) ]] &&
+        [[ $(blockdev --getsize64 $VMTEST_DISK_SCSI) == $((32 << 20)) ]] &&
+        [[ $(blockdev --getsize64 $VMTEST_DISK_NVME) == $((16 << 20)) ]] &&
+        [[ $(blockdev --getro $VMTEST_DISK_NVME) == 1 ]]
+    ""#;
+
+    let config = Config::builder()
+        .target(
+            Target::builder("extra disks in image")
+                .image(image.as_pathbuf())
+                .uefi(true)
+                .command(command)
+                .vm(vm.clone())
+                .build()
+                .unwrap(),
+        )
+        .target(
+            Target::builder("extra disks in kernel")
+                .kernel(asset("bzImage-v5.15-default"))
+                .command(command)
+                .vm(vm)
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+
+    for idx in 0..2 {
+        let (send, recv) = channel();
+        vmtest.run_one(idx, send);
+        assert_no_err!(recv);
+    }
+}
+
+#[test]
+fn test_run_storage_controllers() {
+    let config = Config::builder()
+        .target(
+            Target::builder("nvme namespaces and scsi controller")
+                .kernel(asset("bzImage-v5.15-default"))
+                .command(
+                    r#"bash -xc "
+                [[ $(blockdev --getss $VMTEST_DISK_NS1) == 4096 ]] &&
+                [[ $(blockdev --getss $VMTEST_DISK_NS2) == 512 ]] &&
+                [[ $(blockdev --getpbsz $VMTEST_DISK_NS2) == 4096 ]] &&
+                [[ $(cat /sys/block/$(basename $VMTEST_DISK_NS2)/nsid) == 2 ]] &&
+                [[ -b $VMTEST_DISK_SCSI ]]
+            ""#,
+                )
+                .vm(VMConfig::builder()
+                    .controller(Controller {
+                        name: "nvme0".into(),
+                        kind: ControllerKind::Nvme,
+                        queues: Some(2),
+                    })
+                    .controller(Controller {
+                        name: "scsi0".into(),
+                        kind: ControllerKind::VirtioScsi,
+                        queues: Some(2),
+                    })
+                    .disk(Disk {
+                        name: "ns1".into(),
+                        size: Some("16M".into()),
+                        bus: Some(DiskBus::Nvme),
+                        controller: Some("nvme0".into()),
+                        logical_block_size: Some(4096),
+                        physical_block_size: Some(4096),
+                        ..Default::default()
+                    })
+                    .disk(Disk {
+                        name: "ns2".into(),
+                        size: Some("16M".into()),
+                        bus: Some(DiskBus::Nvme),
+                        controller: Some("nvme0".into()),
+                        physical_block_size: Some(4096),
+                        ..Default::default()
+                    })
+                    .disk(Disk {
+                        name: "scsi".into(),
+                        size: Some("16M".into()),
+                        bus: Some(DiskBus::VirtioScsi),
+                        controller: Some("scsi0".into()),
+                        ..Default::default()
+                    })
+                    .build())
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+    let (send, recv) = channel();
+    vmtest.run_one(0, send);
+    assert_no_err!(recv);
+}
+
+#[test]
+fn test_run_tpm() {
+    let image = create_new_image(asset("image-uefi.raw-efi"));
+    let config = Config::builder()
+        .target(
+            Target::builder("tpm with uefi")
+                .image(image.as_pathbuf())
+                .uefi(true)
+                .command("[[ -c /dev/tpm0 && $(cat /sys/class/tpm/tpm0/tpm_version_major) == 2 ]]")
+                .vm(VMConfig::builder().tpm(true).build())
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+    let (send, recv) = channel();
+    vmtest.run_one(0, send);
+    assert_no_err!(recv);
+}
+
 #[test]
 fn test_run_custom_resources() {
     let uefi_image_t1 = create_new_image(asset("image-uefi.raw-efi"));
     let uefi_image_t2 = create_new_image(asset("image-uefi.raw-efi"));
 
-    let config = Config {
-        target: vec![
-            Target {
-                name: "Custom number of CPUs".to_string(),
-                image: Some(uefi_image_t1.as_pathbuf()),
-                uefi: true,
-                command: r#"bash -xc "[[ "$(nproc)" == "1" ]]""#.into(),
-                vm: VMConfig {
-                    num_cpus: 1,
-                    ..Default::default()
-                },
-                ..Default::default()
-            },
-            Target {
-                name: "Custom amount of RAM".to_string(),
-                image: Some(uefi_image_t2.as_pathbuf()),
-                uefi: true,
+    let config = Config::builder()
+        .target(
+            Target::builder("Custom number of CPUs")
+                .image(uefi_image_t1.as_pathbuf())
+                .uefi(true)
+                .command(r#"bash -xc "[[ "$(nproc)" == "1" ]]""#)
+                .vm(VMConfig::builder().num_cpus(1).build())
+                .build()
+                .unwrap(),
+        )
+        .target(
+            Target::builder("Custom amount of RAM")
+                .image(uefi_image_t2.as_pathbuf())
+                .uefi(true)
                 // Should be in the 200 thousands, but it's variable.
-                command: r#"bash -xc "cat /proc/meminfo | grep 'MemTotal:         2..... kB'""#
-                    .into(),
-                vm: VMConfig {
-                    memory: "256M".into(),
-                    ..Default::default()
-                },
-                ..Default::default()
-            },
-        ],
-    };
+                .command(r#"bash -xc "cat /proc/meminfo | grep 'MemTotal:         2..... kB'""#)
+                .vm(VMConfig::builder().memory("256M").build())
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &["main.sh"]);
     for i in 0..2 {
         let (send, recv) = channel();
@@ -411,48 +869,140 @@ fn test_run_custom_resources() {
     }
 }
 
+#[test]
+fn test_run_cpu_topology_and_numa() {
+    let image = create_new_image(asset("image-uefi.raw-efi"));
+    let command = r#"bash -xc "[[ $(nproc) == 4 && -d /sys/devices/system/node/node1 && $(cat /sys/devices/system/node/node1/cpulist) == 2-3 ]]""#;
+    let config = Config::builder()
+        .target(
+            Target::builder("topology and numa")
+                .image(image.as_pathbuf())
+                .uefi(true)
+                .command(command)
+                .vm(VMConfig::builder()
+                    .num_cpus(4)
+                    .topology(Topology {
+                        sockets: 2,
+                        cores: 2,
+                        threads: 1,
+                    })
+                    .memory("1G")
+                    .numa_node(NumaNode {
+                        cpus: Some("0-1".into()),
+                        memory: "512M".into(),
+                    })
+                    .numa_node(NumaNode {
+                        cpus: Some("2-3".into()),
+                        memory: "512M".into(),
+                    })
+                    .build())
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+    let (send, recv) = channel();
+    vmtest.run_one(0, send);
+    assert_no_err!(recv);
+}
+
+#[test]
+fn test_run_memfd_memory() {
+    let image = create_new_image(asset("image-uefi.raw-efi"));
+    let config = Config::builder()
+        .target(
+            Target::builder("memfd memory")
+                .image(image.as_pathbuf())
+                .uefi(true)
+                .command("true")
+                .vm(VMConfig::builder()
+                    .memory("1G")
+                    .memory_backend(MemoryBackend {
+                        kind: MemoryBackendKind::Memfd,
+                        prealloc: true,
+                        ..Default::default()
+                    })
+                    .build())
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+    let (send, recv) = channel();
+    vmtest.run_one(0, send);
+    assert_no_err!(recv);
+}
+
+#[test]
+fn test_run_vmtest_ctl() {
+    let image = create_new_image(asset("image-uefi.raw-efi"));
+    let command = r#"bash -xc "vmtest-ctl cpu-add && [[ $(nproc) == 2 ]] && vmtest-ctl balloon 512M && vmtest-ctl cpu-del""#;
+    let config = Config::builder()
+        .target(
+            Target::builder("balloon and cpu hotplug")
+                .image(image.as_pathbuf())
+                .uefi(true)
+                .command(command)
+                .vm(VMConfig::builder()
+                    .num_cpus(1)
+                    .max_cpus(2)
+                    .memory("1G")
+                    .balloon(true)
+                    .build())
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &[]);
+    let (send, recv) = channel();
+    vmtest.run_one(0, send);
+    assert_no_err!(recv);
+}
+
 #[test]
 fn test_run_custom_mounts() {
     let uefi_image = create_new_image(asset("image-uefi.raw-efi"));
 
-    let config = Config {
-        target: vec![
-            Target {
-                name: "mount".to_string(),
-                image: Some(uefi_image.as_pathbuf()),
-                uefi: true,
-                command: r#"bash -xc "[[ -e /tmp/mount/README.md ]]""#.into(),
-                vm: VMConfig {
-                    mounts: HashMap::from([(
-                        "/tmp/mount".into(),
+    let config = Config::builder()
+        .target(
+            Target::builder("mount")
+                .image(uefi_image.as_pathbuf())
+                .uefi(true)
+                .command(r#"bash -xc "[[ -e /tmp/mount/README.md ]]""#)
+                .vm(VMConfig::builder()
+                    .mount(
+                        "/tmp/mount",
                         Mount {
                             host_path: Path::new(env!("CARGO_MANIFEST_DIR")).into(),
                             writable: true,
                         },
-                    )]),
-                    ..Default::default()
-                },
-                ..Default::default()
-            },
-            Target {
-                name: "RO mount".to_string(),
-                image: Some(uefi_image.as_pathbuf()),
-                uefi: true,
-                command: r#"bash -xc "(touch /tmp/ro/hi && exit -1) || true""#.into(),
-                vm: VMConfig {
-                    mounts: HashMap::from([(
-                        "/tmp/ro".into(),
+                    )
+                    .build())
+                .build()
+                .unwrap(),
+        )
+        .target(
+            Target::builder("RO mount")
+                .image(uefi_image.as_pathbuf())
+                .uefi(true)
+                .command(r#"bash -xc "(touch /tmp/ro/hi && exit -1) || true""#)
+                .vm(VMConfig::builder()
+                    .mount(
+                        "/tmp/ro",
                         Mount {
                             host_path: Path::new(env!("CARGO_MANIFEST_DIR")).into(),
                             writable: false,
                         },
-                    )]),
-                    ..Default::default()
-                },
-                ..Default::default()
-            },
-        ],
-    };
+                    )
+                    .build())
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
     let (vmtest, _dir) = setup(config, &["main.sh"]);
     for i in 0..2 {
         let (send, recv) = channel();
@@ -460,3 +1010,75 @@ fn test_run_custom_mounts() {
         assert_no_err!(recv);
     }
 }
+
+// Expect that a session can run multiple commands against the same VM
+#[test]
+fn test_session_multiple_commands() {
+    let config = Config::builder()
+        .target(
+            Target::builder("session")
+                .kernel(asset("bzImage-v5.15-default"))
+                .command("true")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, dir) = setup(config, &[]);
+    let (send, recv) = channel();
+    let mut session = vmtest.session(0, send).expect("Failed to start session");
+    drop(recv);
+
+    // State written by the first command must be visible to the second,
+    // which would not be the case if the VM was rebooted in between.
+    let (send, recv) = channel();
+    session.exec("echo -n hello > /tmp/session", send);
+    assert_no_err!(recv);
+    let (send, recv) = channel();
+    session.exec("cat /tmp/session > /mnt/vmtest/result", send);
+    assert_no_err!(recv);
+    session.stop();
+
+    let result_path = dir.path().join("result");
+    let result = fs::read_to_string(result_path).expect("Failed to read result");
+    assert_eq!(result, "hello");
+}
+
+// Expect that a target can be resumed from the snapshot saved by a previous run
+#[test]
+fn test_run_snapshot() {
+    let image = create_new_image(asset("image-not-uefi.raw"));
+    let cache = tempdir().expect("Failed to create tempdir");
+    env::set_var("XDG_CACHE_HOME", cache.path());
+
+    let config = Config::builder()
+        .target(
+            Target::builder("snapshot")
+                .image(image.as_pathbuf())
+                .snapshot(true)
+                .command("/mnt/vmtest/main.sh nixos")
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+    let (vmtest, _dir) = setup(config, &["main.sh"]);
+
+    // First run saves the snapshot, second run resumes from it
+    for _ in 0..2 {
+        let (send, recv) = channel();
+        vmtest.run_one(0, send);
+        assert_no_err!(recv);
+    }
+
+    // A single snapshot of state and disk, without any temporary files
+    let mut files = fs::read_dir(cache.path().join("vmtest/snapshots"))
+        .expect("Failed to read snapshot cache")
+        .map(|e| {
+            let path = e.expect("Failed to read snapshot cache entry").path();
+            path.extension().unwrap_or_default().to_owned()
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(files, ["qcow2", "state"]);
+}